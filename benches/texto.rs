@@ -0,0 +1,89 @@
+//! Timings for `Texto` on a 1M-line buffer. Run with `cargo bench`.
+
+use editor_texto::texto::Texto;
+use std::time::{Duration, Instant};
+
+const LINES: usize = 1_000_000;
+const OPS: usize = 10_000;
+
+/// Small LCG so every run touches the same positions.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % max
+    }
+}
+
+fn report(name: &str, ops: usize, elapsed: Duration) {
+    println!(
+        "{:<28} {:>8} ops  {:>10.2?} total  {:>10.2?}/op",
+        name,
+        ops,
+        elapsed,
+        elapsed / ops as u32
+    );
+}
+
+/// Line `i` of the buffer, the same for `Texto` and the baseline.
+fn line(i: usize) -> String {
+    format!("{:>8} the quick brown fox jumps over the lazy dog", i)
+}
+
+fn bench(name: &str, ops: usize, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ops {
+        f();
+    }
+    report(name, ops, start.elapsed());
+}
+
+fn main() {
+    let start = Instant::now();
+    let mut texto = Texto::from_lines((0..LINES).map(line));
+    report("load 1M lines", 1, start.elapsed());
+
+    let mut rng = Lcg(42);
+    let text: Vec<char> = "hello".chars().collect();
+
+    bench("insert chars", OPS, || {
+        let y = rng.next(texto.len_lines());
+        let x = rng.next(texto.line_len(y) + 1);
+        texto.insert(y, x, &text);
+    });
+    bench("remove chars", OPS, || {
+        let y = rng.next(texto.len_lines());
+        let len = texto.line_len(y);
+        if len > 0 {
+            texto.remove(y, rng.next(len), 1);
+        }
+    });
+    bench("insert line", OPS, || {
+        let y = rng.next(texto.len_lines());
+        texto.insert_line(y, &text);
+    });
+    bench("remove line", OPS, || {
+        let y = rng.next(texto.len_lines());
+        texto.remove_line(y);
+    });
+    bench("read line", OPS, || {
+        let y = rng.next(texto.len_lines());
+        std::hint::black_box(texto.line_string(y));
+    });
+    bench("pos <-> char index", OPS, || {
+        let y = rng.next(texto.len_lines());
+        let idx = texto.pos_to_char(y, 0);
+        std::hint::black_box(texto.char_to_pos(idx));
+    });
+
+    // The previous storage, for comparison.
+    let mut lines: Vec<Vec<char>> = (0..LINES).map(|i| line(i).chars().collect()).collect();
+    bench("Vec<Vec<char>> insert line", OPS / 10, || {
+        let y = rng.next(lines.len());
+        lines.insert(y, text.clone());
+    });
+}
@@ -0,0 +1,202 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// How the `"+` and `"*` registers reach the system clipboard, set with
+/// `:set clipboard=...`. Whatever the provider, the text is also kept in
+/// the register itself, so putting it back works without a clipboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// The first local tool that is installed, or OSC 52 over SSH and when
+    /// there is none.
+    Auto,
+    /// Escape sequence asking the terminal to set its clipboard. Works over
+    /// SSH, but the clipboard can't be read back.
+    Osc52,
+    WlCopy,
+    Xclip,
+    Pbcopy,
+    /// Only the registers.
+    Internal,
+}
+
+impl Provider {
+    pub fn from_name(name: &str) -> Option<Provider> {
+        Some(match name {
+            "auto" => Provider::Auto,
+            "osc52" => Provider::Osc52,
+            "wl-copy" => Provider::WlCopy,
+            "xclip" => Provider::Xclip,
+            "pbcopy" => Provider::Pbcopy,
+            "internal" => Provider::Internal,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Auto => "auto",
+            Provider::Osc52 => "osc52",
+            Provider::WlCopy => "wl-copy",
+            Provider::Xclip => "xclip",
+            Provider::Pbcopy => "pbcopy",
+            Provider::Internal => "internal",
+        }
+    }
+
+    /// The commands that copy to and paste from the clipboard, or the
+    /// primary selection with `primary`.
+    fn commands(&self, primary: bool) -> Option<(Vec<&'static str>, Vec<&'static str>)> {
+        let selection = if primary { "primary" } else { "clipboard" };
+        Some(match self {
+            Provider::WlCopy if primary => (
+                vec!["wl-copy", "--primary"],
+                vec!["wl-paste", "--no-newline", "--primary"],
+            ),
+            Provider::WlCopy => (vec!["wl-copy"], vec!["wl-paste", "--no-newline"]),
+            Provider::Xclip => (
+                vec!["xclip", "-selection", selection],
+                vec!["xclip", "-o", "-selection", selection],
+            ),
+            Provider::Pbcopy => (vec!["pbcopy"], vec!["pbpaste"]),
+            _ => return None,
+        })
+    }
+
+    /// What `Auto` stands for right now. Local tools need their display
+    /// server, and are skipped over SSH where they would copy on the wrong
+    /// machine.
+    fn resolve(self) -> Provider {
+        if self != Provider::Auto {
+            return self;
+        }
+        let remote = env::var_os("SSH_TTY").is_some() || env::var_os("SSH_CONNECTION").is_some();
+        let found = [
+            (Provider::WlCopy, "WAYLAND_DISPLAY"),
+            (Provider::Xclip, "DISPLAY"),
+            (Provider::Pbcopy, ""),
+        ]
+        .into_iter()
+        .find(|(provider, display)| {
+            (display.is_empty() || env::var_os(display).is_some())
+                && provider
+                    .commands(false)
+                    .is_some_and(|(copy, paste)| in_path(copy[0]) && in_path(paste[0]))
+        });
+        match found {
+            Some((provider, _)) if !remote => provider,
+            _ => Provider::Osc52,
+        }
+    }
+}
+
+/// Sends `text` to the clipboard, or to the primary selection with
+/// `primary`. Returns the provider that took it, or why none could.
+pub fn copy(provider: Provider, text: &str, primary: bool) -> Result<Provider, String> {
+    let provider = provider.resolve();
+    match provider {
+        Provider::Internal => Ok(provider),
+        Provider::Osc52 => osc52(text, primary).map(|_| provider),
+        _ => {
+            let (command, _) = provider.commands(primary).unwrap();
+            run_copy(&command, text)
+                .map(|_| provider)
+                .map_err(|err| format!("{}: {}", command[0], err))
+        }
+    }
+}
+
+/// Reads the clipboard, or the primary selection with `primary`. `None`
+/// when the provider can't read it; the register is used instead then.
+pub fn paste(provider: Provider, primary: bool) -> Option<String> {
+    let (_, command) = provider.resolve().commands(primary)?;
+    let output = Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+fn run_copy(command: &[&str], text: &str) -> io::Result<()> {
+    // The tools may stay around to serve the selection, so they must not
+    // keep the terminal open.
+    let mut child = Command::new(command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exited with {}", status)));
+    }
+    Ok(())
+}
+
+/// Writes the OSC 52 sequence straight to the terminal, around ncurses.
+/// Inside tmux it is sent again wrapped for passthrough, as tmux only
+/// forwards the plain one with `set-clipboard on`.
+fn osc52(text: &str, primary: bool) -> Result<(), String> {
+    if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 0 {
+        return Err("OSC 52 needs a terminal".to_string());
+    }
+    let target = if primary { "p" } else { "c" };
+    let sequence = format!("\x1b]52;{};{}\x07", target, base64(text.as_bytes()));
+    let mut out = io::stdout().lock();
+    let mut write = |bytes: &[u8]| out.write_all(bytes).map_err(|err| err.to_string());
+    write(sequence.as_bytes())?;
+    if env::var_os("TMUX").is_some() {
+        let escaped = sequence.replace('\x1b', "\x1b\x1b");
+        write(format!("\x1bPtmux;{}\x1b\\", escaped).as_bytes())?;
+    }
+    out.flush().map_err(|err| err.to_string())
+}
+
+fn in_path(command: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| {
+        env::split_paths(&path).any(|dir| Path::new(&dir).join(command).is_file())
+    })
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_short_chunks() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe]), "//4=");
+    }
+}
@@ -0,0 +1,206 @@
+use crate::file;
+use ncurses::*;
+use std::fs;
+use std::path::PathBuf;
+
+/// How many entries each history keeps.
+const HISTORY_LEN: usize = 200;
+
+/// Lines entered on the command line, oldest first, kept across sessions
+/// in the rim state directory.
+#[derive(Debug, Clone, Default)]
+pub struct CmdHistory {
+    pub entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl CmdHistory {
+    /// Loads the history called `name`, `cmd` for `:` lines.
+    pub fn load(name: &str) -> CmdHistory {
+        let path = file::state_dir("history").map(|dir| dir.join(name));
+        let entries = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|data| data.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        CmdHistory { entries, path }
+    }
+
+    /// Adds `line` as the newest entry, dropping an older copy of it.
+    pub fn add(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        self.entries.retain(|entry| entry != line);
+        self.entries.push(line.to_string());
+        if self.entries.len() > HISTORY_LEN {
+            self.entries.drain(..self.entries.len() - HISTORY_LEN);
+        }
+        if let Some(path) = &self.path {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            let _ = file::write_private(path, (self.entries.join("\n") + "\n").as_bytes());
+        }
+    }
+}
+
+pub enum CmdLineEvent {
+    Continue,
+    Cancel,
+    Done(String),
+}
+
+/// Text being typed on the command line, with the state of history
+/// browsing and completion.
+#[derive(Debug, Default)]
+pub struct CmdLine {
+    pub text: Vec<char>,
+    pub cursor: usize,
+    /// Index of the history entry shown, while browsing with up/down.
+    history_pos: Option<usize>,
+    /// What was typed before browsing started; only entries starting with
+    /// it are shown.
+    history_prefix: String,
+    /// Where the completed word starts, the candidates and which one is
+    /// shown, while cycling with Tab.
+    pub completion: Option<(usize, Vec<String>, usize)>,
+}
+
+impl CmdLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_string(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    /// Applies one key. `complete` gives the start of the word to complete
+    /// and the candidates for it.
+    pub fn handle_key(
+        &mut self,
+        ch: i32,
+        history: &CmdHistory,
+        complete: impl Fn(&str) -> (usize, Vec<String>),
+    ) -> CmdLineEvent {
+        if ch != 9 && ch != KEY_BTAB {
+            self.completion = None;
+        }
+        if ch != KEY_UP && ch != KEY_DOWN {
+            self.history_pos = None;
+        }
+        match ch {
+            27 => return CmdLineEvent::Cancel,
+            KEY_ENTER | 10 | 13 => return CmdLineEvent::Done(self.as_string()),
+            KEY_BACKSPACE | 127 | 8 => {
+                if self.text.is_empty() {
+                    return CmdLineEvent::Cancel;
+                }
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.text.remove(self.cursor);
+                }
+            }
+            KEY_DC if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            }
+            KEY_LEFT => self.cursor = self.cursor.saturating_sub(1),
+            KEY_RIGHT => self.cursor = (self.cursor + 1).min(self.text.len()),
+            // Ctrl-B
+            KEY_HOME | 2 => self.cursor = 0,
+            // Ctrl-E
+            KEY_END | 5 => self.cursor = self.text.len(),
+            // Ctrl-U
+            21 => {
+                self.text.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            // Ctrl-W
+            23 => {
+                let mut start = self.cursor;
+                while start > 0 && self.text[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.text[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.text.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            KEY_UP | KEY_DOWN => self.browse_history(ch == KEY_UP, history),
+            9 | KEY_BTAB => self.complete(ch == 9, complete),
+            c if (32..127).contains(&c) => {
+                self.text.insert(self.cursor, c as u8 as char);
+                self.cursor += 1;
+            }
+            _ => (),
+        }
+        CmdLineEvent::Continue
+    }
+
+    fn browse_history(&mut self, older: bool, history: &CmdHistory) {
+        let start = match self.history_pos {
+            Some(pos) => pos,
+            None => {
+                self.history_prefix = self.as_string();
+                history.entries.len()
+            }
+        };
+        let matches = |i: &usize| history.entries[*i].starts_with(&self.history_prefix);
+        let found = if older {
+            (0..start).rev().find(matches)
+        } else {
+            (start + 1..history.entries.len()).find(matches)
+        };
+        match found {
+            Some(pos) => {
+                self.history_pos = Some(pos);
+                let entry = history.entries[pos].clone();
+                self.set_text(&entry);
+            }
+            // Going down past the newest entry gives back what was typed.
+            None if !older => {
+                self.history_pos = None;
+                let prefix = self.history_prefix.clone();
+                self.set_text(&prefix);
+            }
+            None => self.history_pos = Some(start),
+        }
+    }
+
+    fn complete(&mut self, forward: bool, complete: impl Fn(&str) -> (usize, Vec<String>)) {
+        let (start, candidates, shown) = match self.completion.take() {
+            Some((start, candidates, shown)) => {
+                let n = candidates.len();
+                let next = if forward {
+                    (shown + 1) % n
+                } else {
+                    (shown + n - 1) % n
+                };
+                (start, candidates, next)
+            }
+            None => {
+                let before: String = self.text[..self.cursor].iter().collect();
+                let (start, candidates) = complete(&before);
+                if candidates.is_empty() {
+                    return;
+                }
+                let shown = if forward { 0 } else { candidates.len() - 1 };
+                (start, candidates, shown)
+            }
+        };
+        let word: Vec<char> = candidates[shown].chars().collect();
+        let end = self.cursor;
+        self.text.splice(start..end, word.iter().copied());
+        self.cursor = start + word.len();
+        if candidates.len() > 1 {
+            self.completion = Some((start, candidates, shown));
+        }
+    }
+}
@@ -0,0 +1,662 @@
+use crate::file::Archivo;
+use crate::search;
+use regex::Regex;
+use std::fs;
+
+/// Where a line address starts from, before any `+n`/`-n` offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    /// `.`, the cursor line. Also what a bare offset like `+3` counts from.
+    Current,
+    /// `$`
+    Last,
+    /// A line number as typed, so 1 is the first line.
+    Line(usize),
+    /// `'a`, the line of a mark.
+    Mark(char),
+    /// `/pattern/`, the next line that matches.
+    Search(String),
+    /// `?pattern?`, the previous line that matches.
+    SearchBack(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Addr {
+    pub base: Address,
+    pub offset: isize,
+}
+
+/// A parsed command line: `[range]name[!] [args]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// No addresses, one, or two for `a,b`. `%` is stored as `1,$`.
+    pub range: Vec<Addr>,
+    pub name: String,
+    pub bang: bool,
+    pub args: String,
+}
+
+/// An ex command the editor knows about.
+#[derive(Debug)]
+pub struct ExCommand {
+    pub name: &'static str,
+    /// How many letters of the name have to be typed, `w` for `write`.
+    pub abbrev: usize,
+    /// Whether it takes a range.
+    pub range: bool,
+    /// Whether the argument is a file name, for completion.
+    pub file: bool,
+}
+
+pub const COMMANDS: &[ExCommand] = &[
+    ExCommand {
+        name: "copy",
+        abbrev: 2,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "delete",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "display",
+        abbrev: 2,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "edit",
+        abbrev: 1,
+        range: false,
+        file: true,
+    },
+    ExCommand {
+        name: "global",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "join",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "move",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "nohlsearch",
+        abbrev: 3,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "normal",
+        abbrev: 4,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "quit",
+        abbrev: 1,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "registers",
+        abbrev: 3,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "set",
+        abbrev: 2,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "substitute",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "t",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "undolist",
+        abbrev: 5,
+        range: false,
+        file: false,
+    },
+    ExCommand {
+        name: "vglobal",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "write",
+        abbrev: 1,
+        range: false,
+        file: true,
+    },
+    ExCommand {
+        name: "wq",
+        abbrev: 2,
+        range: false,
+        file: true,
+    },
+    ExCommand {
+        name: "xit",
+        abbrev: 1,
+        range: false,
+        file: true,
+    },
+    ExCommand {
+        name: "exit",
+        abbrev: 3,
+        range: false,
+        file: true,
+    },
+    ExCommand {
+        name: "yank",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: ">",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+    ExCommand {
+        name: "<",
+        abbrev: 1,
+        range: true,
+        file: false,
+    },
+];
+
+/// Splits `/pattern/rest` at the closing delimiter, which is whatever the
+/// first character is. An escaped delimiter in the pattern stands for itself.
+/// The closing delimiter may be left out when nothing follows the pattern.
+pub fn split_pattern(args: &str) -> Result<(String, &str), String> {
+    let mut chars = args.char_indices();
+    let delim = match chars.next() {
+        Some((_, c)) if c.is_alphanumeric() || c == '\\' || c == '"' || c == '|' || c == ' ' => {
+            return Err("Regular expressions can't be delimited by letters".to_string());
+        }
+        Some((_, c)) => c,
+        None => return Ok((String::new(), "")),
+    };
+    let mut pattern = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, c)) if c == delim => pattern.push(c),
+                Some((_, c)) => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                None => pattern.push('\\'),
+            },
+            c if c == delim => return Ok((pattern, &args[i + c.len_utf8()..])),
+            c => pattern.push(c),
+        }
+    }
+    Ok((pattern, ""))
+}
+
+/// Finds the command `name` is an abbreviation of.
+pub fn lookup(name: &str) -> Option<&'static ExCommand> {
+    // `>>>` shifts three times, it is still the `>` command.
+    let name = match name.chars().next() {
+        Some('<' | '>') => &name[..1],
+        _ => name,
+    };
+    COMMANDS
+        .iter()
+        .find(|cmd| name.len() >= cmd.abbrev && cmd.name.starts_with(name))
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    skip_blanks(&chars, &mut i);
+    while chars.get(i) == Some(&':') {
+        i += 1;
+        skip_blanks(&chars, &mut i);
+    }
+
+    let range = parse_range(&chars, &mut i)?;
+    skip_blanks(&chars, &mut i);
+
+    let start = i;
+    match chars.get(i) {
+        Some(c) if c.is_ascii_alphabetic() => {
+            while chars.get(i).is_some_and(|c| c.is_ascii_alphabetic()) {
+                i += 1;
+            }
+        }
+        Some('<' | '>') => {
+            let c = chars[i];
+            while chars.get(i) == Some(&c) {
+                i += 1;
+            }
+        }
+        Some('&' | '=' | '!' | '#' | '~' | '@') => i += 1,
+        Some(c) => return Err(format!("Not an editor command: {}", c)),
+        None => (),
+    }
+    let name: String = chars[start..i].iter().collect();
+    let bang = name != "!" && chars.get(i) == Some(&'!');
+    if bang {
+        i += 1;
+    }
+    let args: String = chars[i..].iter().collect();
+
+    Ok(Command {
+        range,
+        name,
+        bang,
+        args: args.trim_start().to_string(),
+    })
+}
+
+fn parse_range(chars: &[char], i: &mut usize) -> Result<Vec<Addr>, String> {
+    let mut range = Vec::new();
+    if chars.get(*i) == Some(&'%') {
+        *i += 1;
+        range.push(Addr {
+            base: Address::Line(1),
+            offset: 0,
+        });
+        range.push(Addr {
+            base: Address::Last,
+            offset: 0,
+        });
+    } else {
+        let first = parse_addr(chars, i)?;
+        skip_blanks(chars, i);
+        if matches!(chars.get(*i), Some(',' | ';')) {
+            *i += 1;
+            skip_blanks(chars, i);
+            let second = parse_addr(chars, i)?;
+            range.push(first.unwrap_or(CURRENT));
+            range.push(second.unwrap_or(CURRENT));
+        } else if let Some(first) = first {
+            range.push(first);
+        }
+    }
+    Ok(range)
+}
+
+const CURRENT: Addr = Addr {
+    base: Address::Current,
+    offset: 0,
+};
+
+fn skip_blanks(chars: &[char], i: &mut usize) {
+    while chars.get(*i).is_some_and(|c| *c == ' ' || *c == '\t') {
+        *i += 1;
+    }
+}
+
+fn parse_number(chars: &[char], i: &mut usize) -> Option<usize> {
+    let start = *i;
+    while chars.get(*i).is_some_and(char::is_ascii_digit) {
+        *i += 1;
+    }
+    let digits: String = chars[start..*i].iter().collect();
+    digits.parse().ok()
+}
+
+fn parse_addr(chars: &[char], i: &mut usize) -> Result<Option<Addr>, String> {
+    let base = match chars.get(*i) {
+        Some('.') => {
+            *i += 1;
+            Address::Current
+        }
+        Some('$') => {
+            *i += 1;
+            Address::Last
+        }
+        Some(c) if c.is_ascii_digit() => {
+            Address::Line(parse_number(chars, i).ok_or("Invalid range")?)
+        }
+        Some('\'') => {
+            let mark = *chars.get(*i + 1).ok_or("Invalid range")?;
+            *i += 2;
+            Address::Mark(mark)
+        }
+        Some(&delim @ ('/' | '?')) => {
+            *i += 1;
+            let mut pattern = String::new();
+            while let Some(&c) = chars.get(*i) {
+                *i += 1;
+                if c == delim {
+                    break;
+                }
+                if c == '\\' && chars.get(*i) == Some(&delim) {
+                    pattern.push(delim);
+                    *i += 1;
+                } else {
+                    pattern.push(c);
+                }
+            }
+            if delim == '/' {
+                Address::Search(pattern)
+            } else {
+                Address::SearchBack(pattern)
+            }
+        }
+        Some('+' | '-') => Address::Current,
+        _ => return Ok(None),
+    };
+    let mut offset = 0isize;
+    loop {
+        skip_blanks(chars, i);
+        let sign = match chars.get(*i) {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => break,
+        };
+        *i += 1;
+        offset += sign * parse_number(chars, i).unwrap_or(1) as isize;
+    }
+    Ok(Some(Addr { base, offset }))
+}
+
+/// Parses an address given as an argument, like the destination of `:m`.
+pub fn parse_address(text: &str) -> Result<Addr, String> {
+    let chars: Vec<char> = text.trim().chars().collect();
+    let mut i = 0;
+    match parse_addr(&chars, &mut i)? {
+        Some(addr) if i == chars.len() => Ok(addr),
+        _ => Err("Invalid address".to_string()),
+    }
+}
+
+/// Turns a search pattern into a regex, applying the case options and
+/// the last pattern for an empty one.
+pub type Compile<'a> = &'a dyn Fn(&str) -> Result<Regex, String>;
+
+/// Turns an address into a line number, where 1 is the first line and 0
+/// means "before the first line". `current` is the 0-based cursor line.
+pub fn resolve_addr(
+    addr: &Addr,
+    archivo: &Archivo,
+    current: usize,
+    compile: Compile,
+) -> Result<usize, String> {
+    let buffer = &archivo.buffer;
+    let last = buffer.len_lines();
+    let base = match &addr.base {
+        Address::Current => current + 1,
+        Address::Last => last,
+        Address::Line(n) => *n,
+        Address::Mark(mark) => match archivo.marks.get(mark) {
+            Some((y, _)) => y + 1,
+            None => return Err("Mark not set".to_string()),
+        },
+        Address::Search(pattern) | Address::SearchBack(pattern) => {
+            let re = compile(pattern)?;
+            let forward = matches!(addr.base, Address::Search(_));
+            match search::find_line(buffer, &re, current, forward) {
+                Some(y) => y + 1,
+                None => return Err(format!("Pattern not found: {}", pattern)),
+            }
+        }
+    };
+    let line = base as isize + addr.offset;
+    if line < 0 || line as usize > last {
+        return Err("Invalid range".to_string());
+    }
+    Ok(line as usize)
+}
+
+/// The lines a command applies to, as a 0-based inclusive pair. Without a
+/// range that is the cursor line.
+pub fn resolve_range(
+    range: &[Addr],
+    archivo: &Archivo,
+    current: usize,
+    compile: Compile,
+) -> Result<(usize, usize), String> {
+    let mut lines = Vec::new();
+    for addr in range {
+        // Line 0 is accepted for a range and means the first line.
+        lines.push(resolve_addr(addr, archivo, current, compile)?.max(1) - 1);
+    }
+    Ok(match lines[..] {
+        [] => (current, current),
+        [a] => (a, a),
+        [a, b, ..] => (a.min(b), a.max(b)),
+    })
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => path.to_string(),
+    }
+}
+
+/// Completions for a command line being typed: command names while the
+/// name is typed, file names in the argument of commands that take one.
+/// Returns the char index where the completed word starts and the words
+/// that can replace it.
+pub fn complete(line: &str) -> (usize, Vec<String>) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while chars.get(i).is_some_and(|c| *c == ':' || *c == ' ') {
+        i += 1;
+    }
+    if parse_range(&chars, &mut i).is_err() {
+        return (chars.len(), Vec::new());
+    }
+    skip_blanks(&chars, &mut i);
+    let start = i;
+    while chars.get(i).is_some_and(|c| c.is_ascii_alphabetic()) {
+        i += 1;
+    }
+    let name: String = chars[start..i].iter().collect();
+    if i == chars.len() {
+        let names = COMMANDS
+            .iter()
+            .filter(|cmd| cmd.name.starts_with(&name))
+            .map(|cmd| cmd.name.to_string())
+            .collect();
+        return (start, names);
+    }
+    match lookup(&name) {
+        Some(cmd) if cmd.file => match chars.iter().rposition(|c| *c == ' ') {
+            Some(space) if space >= i => {
+                let word: String = chars[space + 1..].iter().collect();
+                (space + 1, complete_path(&word))
+            }
+            _ => (chars.len(), Vec::new()),
+        },
+        _ => (chars.len(), Vec::new()),
+    }
+}
+
+/// File names starting with `word`, directories with a trailing `/`.
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(p) => (&word[..=p], &word[p + 1..]),
+        None => ("", word),
+    };
+    let listed = if dir.is_empty() {
+        ".".to_string()
+    } else {
+        expand_home(dir)
+    };
+    let Ok(entries) = fs::read_dir(listed) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(base: Address, offset: isize) -> Addr {
+        Addr { base, offset }
+    }
+
+    #[test]
+    fn parse_splits_name_bang_and_args() {
+        let cmd = parse("  :w! out.txt").unwrap();
+        assert_eq!(
+            (cmd.range, cmd.name.as_str(), cmd.bang, cmd.args.as_str()),
+            (vec![], "w", true, "out.txt")
+        );
+        let cmd = parse("s/a/b  ").unwrap();
+        assert_eq!((cmd.name.as_str(), cmd.args.as_str()), ("s", "/a/b  "));
+        let cmd = parse("normal   ix ").unwrap();
+        assert_eq!((cmd.name.as_str(), cmd.args.as_str()), ("normal", "ix "));
+        let cmd = parse(">>>").unwrap();
+        assert_eq!((cmd.name.as_str(), cmd.args.as_str()), (">>>", ""));
+        let cmd = parse("!ls").unwrap();
+        assert_eq!(
+            (cmd.name.as_str(), cmd.bang, cmd.args.as_str()),
+            ("!", false, "ls")
+        );
+        assert!(parse("*").is_err());
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(
+            parse("%d").unwrap().range,
+            [addr(Address::Line(1), 0), addr(Address::Last, 0)]
+        );
+        assert_eq!(
+            parse("3,$-2y").unwrap().range,
+            [addr(Address::Line(3), 0), addr(Address::Last, -2)]
+        );
+        assert_eq!(
+            parse(".+3 , -").unwrap().range,
+            [addr(Address::Current, 3), addr(Address::Current, -1)]
+        );
+        assert_eq!(
+            parse(",5").unwrap().range,
+            [addr(Address::Current, 0), addr(Address::Line(5), 0)]
+        );
+        assert_eq!(parse("12").unwrap().name, "");
+    }
+
+    #[test]
+    fn parse_marks_and_patterns() {
+        assert_eq!(
+            parse("'a,'b+1d").unwrap().range,
+            [addr(Address::Mark('a'), 0), addr(Address::Mark('b'), 1)]
+        );
+        assert_eq!(
+            parse(r"/a\/b/-1,?c?s/x/y/").unwrap().range,
+            [
+                addr(Address::Search("a/b".to_string()), -1),
+                addr(Address::SearchBack("c".to_string()), 0)
+            ]
+        );
+        assert_eq!(parse_address(" 'x+2 "), Ok(addr(Address::Mark('x'), 2)));
+        assert!(parse_address("3 4").is_err());
+    }
+
+    /// Five lines, `one` to `five`, with mark `a` on `two` and a selection
+    /// from `two` to `four`.
+    fn archivo() -> Archivo {
+        let mut archivo = Archivo::empty("test");
+        archivo.buffer = crate::texto::Texto::from_text("one\ntwo\nthree\nfour\nfive");
+        archivo.marks.insert('a', (1, 0));
+        archivo.marks.insert('<', (1, 2));
+        archivo.marks.insert('>', (3, 0));
+        archivo
+    }
+
+    fn resolve(line: &str, current: usize) -> Result<(usize, usize), String> {
+        let compile: Compile = &|p| Regex::new(p).map_err(|e| e.to_string());
+        resolve_range(&parse(line)?.range, &archivo(), current, compile)
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        assert_eq!(resolve("d", 2), Ok((2, 2)));
+        assert_eq!(resolve("%d", 2), Ok((0, 4)));
+        assert_eq!(resolve("4,2d", 0), Ok((1, 3)));
+        assert_eq!(resolve(".,+2d", 1), Ok((1, 3)));
+        assert_eq!(resolve("$-1d", 0), Ok((3, 3)));
+        assert_eq!(resolve("0d", 3), Ok((0, 0)));
+        assert_eq!(resolve("'a,'a+2d", 4), Ok((1, 3)));
+        assert_eq!(resolve("'<,'>d", 4), Ok((1, 3)));
+        assert_eq!(resolve("'b", 0), Err("Mark not set".to_string()));
+        assert_eq!(resolve("$+1d", 0), Err("Invalid range".to_string()));
+        assert_eq!(resolve("-3d", 1), Err("Invalid range".to_string()));
+    }
+
+    #[test]
+    fn resolve_patterns() {
+        // Searches start after the cursor line and wrap around.
+        assert_eq!(resolve("/t/d", 1), Ok((2, 2)));
+        assert_eq!(resolve("/o/d", 3), Ok((0, 0)));
+        assert_eq!(resolve("?f?d", 3), Ok((4, 4)));
+        assert_eq!(resolve("/two/+2d", 4), Ok((3, 3)));
+        assert_eq!(resolve("/one/,/four/d", 0), Ok((0, 3)));
+        assert_eq!(
+            resolve("/six/d", 0),
+            Err("Pattern not found: six".to_string())
+        );
+    }
+
+    #[test]
+    fn lookup_takes_abbreviations() {
+        assert_eq!(lookup("w").map(|c| c.name), Some("write"));
+        assert_eq!(lookup("subst").map(|c| c.name), Some("substitute"));
+        assert_eq!(lookup(">>").map(|c| c.name), Some(">"));
+        assert_eq!(lookup("writex").map(|c| c.name), None);
+    }
+
+    #[test]
+    fn split_pattern_at_the_delimiter() {
+        assert_eq!(split_pattern("/a/b/g"), Ok(("a".to_string(), "b/g")));
+        assert_eq!(split_pattern(r"#a\#b#c"), Ok(("a#b".to_string(), "c")));
+        assert_eq!(split_pattern(r"/a\.b"), Ok((r"a\.b".to_string(), "")));
+        assert_eq!(split_pattern(""), Ok((String::new(), "")));
+        assert!(split_pattern("xax").is_err());
+    }
+
+    #[test]
+    fn complete_names_and_files() {
+        let (start, names) = complete("se");
+        assert_eq!(start, 0);
+        assert_eq!(names, ["set"]);
+        assert_eq!(complete("set fo").1, Vec::<String>::new());
+    }
+}
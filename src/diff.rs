@@ -0,0 +1,205 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Stop looking for the shortest edit script after this many differences;
+/// past that point the files are just reported as too different.
+const MAX_EDITS: usize = 2000;
+
+/// Line diff between `a` and `b` using Myers' algorithm. Returns `None` when
+/// the two differ in more than `MAX_EDITS` lines.
+pub fn diff_lines(a: &[String], b: &[String]) -> Option<Vec<DiffLine>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut out: Vec<DiffLine> = a[..prefix].iter().cloned().map(DiffLine::Same).collect();
+    out.extend(myers(ma, mb)?);
+    out.extend(a[a.len() - suffix..].iter().cloned().map(DiffLine::Same));
+    Some(out)
+}
+
+fn myers(a: &[String], b: &[String]) -> Option<Vec<DiffLine>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize;
+    let mut v = vec![0isize; 2 * max + 2];
+    // trace[d] holds v[-d..=d] as it was before step d.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    let mut end = None;
+    'search: for d in 0..=max.min(MAX_EDITS) as isize {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let down =
+                k == -d || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize]);
+            let mut x = if down {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                end = Some(d);
+                break 'search;
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..=end?).rev() {
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            let prev = &trace[d as usize];
+            let at = |k: isize| prev[(k + d) as usize];
+            let k = x - y;
+            let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                k + 1
+            } else {
+                k - 1
+            };
+            (at(prev_k), at(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            out.push(DiffLine::Same(a[x as usize].clone()));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                out.push(DiffLine::Added(b[y as usize].clone()));
+            } else {
+                x -= 1;
+                out.push(DiffLine::Removed(a[x as usize].clone()));
+            }
+        }
+    }
+    out.reverse();
+    Some(out)
+}
+
+/// Renders a diff in unified style, keeping `context` unchanged lines around
+/// every change.
+pub fn format_diff(diff: &[DiffLine], context: usize) -> Vec<String> {
+    let changed: Vec<bool> = diff
+        .iter()
+        .map(|l| !matches!(l, DiffLine::Same(_)))
+        .collect();
+    let mut out = Vec::new();
+    let mut last_shown = None;
+    let (mut a_line, mut b_line) = (1, 1);
+    for (i, line) in diff.iter().enumerate() {
+        let lo = i.saturating_sub(context);
+        let hi = (i + context + 1).min(diff.len());
+        if changed[lo..hi].iter().any(|&c| c) {
+            if !matches!(last_shown, Some(l) if l + 1 == i) {
+                out.push(format!("@@ -{} +{} @@", a_line, b_line));
+            }
+            last_shown = Some(i);
+            out.push(match line {
+                DiffLine::Same(s) => format!(" {}", s),
+                DiffLine::Removed(s) => format!("-{}", s),
+                DiffLine::Added(s) => format!("+{}", s),
+            });
+        }
+        match line {
+            DiffLine::Same(_) => {
+                a_line += 1;
+                b_line += 1;
+            }
+            DiffLine::Removed(_) => a_line += 1,
+            DiffLine::Added(_) => b_line += 1,
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.chars().map(String::from).collect()
+    }
+
+    /// The two sides the diff was made from, to check it against them.
+    fn sides(diff: &[DiffLine]) -> (String, String) {
+        let (mut a, mut b) = (String::new(), String::new());
+        for line in diff {
+            match line {
+                DiffLine::Same(s) => {
+                    a.push_str(s);
+                    b.push_str(s);
+                }
+                DiffLine::Removed(s) => a.push_str(s),
+                DiffLine::Added(s) => b.push_str(s),
+            }
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn finds_the_shortest_diff() {
+        for (a, b, edits) in [
+            ("", "", 0),
+            ("abc", "abc", 0),
+            ("", "abc", 3),
+            ("abc", "", 3),
+            ("abcabba", "cbabac", 5),
+            ("xaby", "xacy", 2),
+        ] {
+            let diff = diff_lines(&lines(a), &lines(b)).unwrap();
+            assert_eq!(sides(&diff), (a.to_string(), b.to_string()));
+            let changed = diff
+                .iter()
+                .filter(|l| !matches!(l, DiffLine::Same(_)))
+                .count();
+            assert_eq!(changed, edits, "{} -> {}", a, b);
+        }
+    }
+
+    #[test]
+    fn gives_up_on_very_different_files() {
+        let a: Vec<String> = (0..MAX_EDITS).map(|i| format!("a{}", i)).collect();
+        let b: Vec<String> = (0..MAX_EDITS).map(|i| format!("b{}", i)).collect();
+        assert_eq!(diff_lines(&a, &b), None);
+    }
+
+    #[test]
+    fn format_keeps_context_around_changes() {
+        let diff = diff_lines(&lines("abcdefghij"), &lines("abXdefghiJ")).unwrap();
+        assert_eq!(
+            format_diff(&diff, 1),
+            [
+                "@@ -2 +2 @@",
+                " b",
+                "-c",
+                "+X",
+                " d",
+                "@@ -9 +9 @@",
+                " i",
+                "-j",
+                "+J"
+            ]
+        );
+        // With more context the two hunks join into one.
+        assert_eq!(format_diff(&diff, 3).len(), 13);
+        assert!(format_diff(&diff_lines(&lines("ab"), &lines("ab")).unwrap(), 3).is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    /// Sniffs the byte order mark first; without one the file is UTF-8 if it
+    /// is valid UTF-8 and Latin-1 otherwise, since every byte string is valid
+    /// Latin-1.
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        if bytes.starts_with(&[0xff, 0xfe]) {
+            Encoding::Utf16Le
+        } else if bytes.starts_with(&[0xfe, 0xff]) {
+            Encoding::Utf16Be
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Latin1
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" | "iso8859-1" => Some(Encoding::Latin1),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16" | "utf16" | "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "LATIN1",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+        }
+    }
+
+    /// Decodes `bytes` into text. A byte order mark is kept as a leading
+    /// U+FEFF so the caller can strip it and remember it was there.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+            Encoding::Utf16Le | Encoding::Utf16Be => char::decode_utf16(self.units(bytes))
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        }
+    }
+
+    /// Whether every byte of `bytes` decodes, so that none of them turn into
+    /// U+FFFD and get lost on the next write. Only Latin-1 takes anything.
+    pub fn valid(&self, bytes: &[u8]) -> bool {
+        match self {
+            Encoding::Utf8 => std::str::from_utf8(bytes).is_ok(),
+            Encoding::Latin1 => true,
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                bytes.len().is_multiple_of(2)
+                    && char::decode_utf16(self.units(bytes)).all(|c| c.is_ok())
+            }
+        }
+    }
+
+    /// The UTF-16 code units in `bytes`. An odd byte at the end is padded.
+    fn units<'a>(&self, bytes: &'a [u8]) -> impl Iterator<Item = u16> + 'a {
+        let little = *self == Encoding::Utf16Le;
+        bytes.chunks(2).map(move |pair| {
+            let pair = [pair[0], *pair.get(1).unwrap_or(&0)];
+            if little {
+                u16::from_le_bytes(pair)
+            } else {
+                u16::from_be_bytes(pair)
+            }
+        })
+    }
+
+    /// Encodes `text` back into bytes. Fails with the first character that
+    /// has no representation, which can only happen for Latin-1.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(c as u32)
+                        .map_err(|_| format!("Can't convert '{}' to {}", c, self.name()))
+                })
+                .collect(),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        assert_eq!(Encoding::detect(b""), Encoding::Utf8);
+        assert_eq!(Encoding::detect("año".as_bytes()), Encoding::Utf8);
+        assert_eq!(Encoding::detect(b"a\xf1o"), Encoding::Latin1);
+        assert_eq!(Encoding::detect(b"\xff\xfea\x00"), Encoding::Utf16Le);
+        assert_eq!(Encoding::detect(b"\xfe\xff\x00a"), Encoding::Utf16Be);
+    }
+
+    #[test]
+    fn decode_keeps_the_bom() {
+        assert_eq!(Encoding::Latin1.decode(b"a\xf1o"), "año");
+        assert_eq!(
+            Encoding::Utf16Le.decode(b"\xff\xfea\x00\xf1\x00"),
+            "\u{feff}añ"
+        );
+        assert_eq!(
+            Encoding::Utf16Be.decode(b"\xfe\xff\xd8\x3d\xde\x00"),
+            "\u{feff}😀"
+        );
+        // A lone surrogate and an odd byte out.
+        assert_eq!(Encoding::Utf16Le.decode(b"\x00\xd8a"), "\u{fffd}a");
+    }
+
+    #[test]
+    fn invalid_bytes_are_found() {
+        assert!(Encoding::Utf8.valid("año \u{fffd}".as_bytes()));
+        assert!(!Encoding::Utf8.valid(b"a\xf1o"));
+        assert!(Encoding::Latin1.valid(b"a\xf1o"));
+        assert!(Encoding::Utf16Le.valid(b"\xff\xfea\x00"));
+        assert!(!Encoding::Utf16Le.valid(b"\x00\xd8a\x00"));
+        assert!(!Encoding::Utf16Be.valid(b"\x00a\x00"));
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let text = "\u{feff}año 😀\n";
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(Encoding::detect(&bytes), encoding);
+            assert_eq!(encoding.decode(&bytes), text);
+        }
+        assert_eq!(Encoding::Latin1.encode("año").unwrap(), b"a\xf1o");
+        assert_eq!(
+            Encoding::Latin1.encode("a€"),
+            Err("Can't convert '€' to LATIN1".to_string())
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Encoding::from_name("UTF-16"), Some(Encoding::Utf16Be));
+        assert_eq!(Encoding::from_name("iso-8859-1"), Some(Encoding::Latin1));
+        assert_eq!(Encoding::from_name("koi8-r"), None);
+    }
+}
@@ -1,7 +1,4 @@
-use crate::{
-    file::is_file,
-    msg::{Msg, MsgType},
-};
+use crate::msg::{Msg, MsgType};
 use ncurses::*;
 use std::fs;
 
@@ -42,7 +39,7 @@ pub struct Explorer {
 
 impl Explorer {
     pub fn new(path: impl Into<String> + Copy) -> Self {
-        init_color(COLOR_BLACK as i16, 90, 90, 90);
+        init_color(COLOR_BLACK, 90, 90, 90);
         init_pair(1, COLOR_BLACK, COLOR_WHITE);
         init_pair(2, COLOR_WHITE, COLOR_BLUE);
         init_pair(3, COLOR_BLUE, COLOR_BLACK);
@@ -71,7 +68,7 @@ impl Explorer {
                 .to_str()
                 .expect("FAILED CONVERT TO STRING")
                 .to_string();
-        } else if !is_file(&p) {
+        } else if std::path::Path::new(&p).is_dir() {
         } else {
             if let Ok(current_dir) = std::env::current_dir() {
                 let file_path = current_dir.join(p);
@@ -109,8 +106,8 @@ impl Explorer {
         start_color();
         cbreak();
 
-        init_color(COLOR_BLACK as i16, 40, 40, 40);
-        init_color(COLOR_BLUE as i16, 40, 40, 1000);
+        init_color(COLOR_BLACK, 40, 40, 40);
+        init_color(COLOR_BLUE, 40, 40, 1000);
         init_pair(1, COLOR_BLACK, COLOR_WHITE);
         init_pair(2, COLOR_WHITE, COLOR_BLUE);
         init_pair(3, COLOR_BLUE, COLOR_BLACK);
@@ -174,19 +171,18 @@ impl Explorer {
         curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
         box_(self.win, 0, 0);
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-        mvwprintw(self.win, 0, 1, &self.path);
+        mvwaddstr(self.win, 0, 1, &self.path);
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
 
-        let mut counter = 0;
-        for i in self.selected..self.dirs.len() {
+        for (counter, i) in (self.selected..self.dirs.len()).enumerate() {
+            let counter = counter as i32;
             if self.selected == i {
                 wattron(self.win, COLOR_PAIR(1) | A_BOLD());
-                mvwprintw(self.win, counter + self.y, self.x, &self.dirs[i].path);
+                mvwaddstr(self.win, counter + self.y, self.x, &self.dirs[i].path);
                 wattroff(self.win, COLOR_PAIR(1) | A_BOLD());
             } else {
-                mvwprintw(self.win, counter + self.y, self.x, &self.dirs[i].path);
+                mvwaddstr(self.win, counter + self.y, self.x, &self.dirs[i].path);
             }
-            counter += 1;
         }
 
         self.msg.display(1, self.h - 2);
@@ -1,48 +1,729 @@
-use std::{fs, os::unix::fs::PermissionsExt};
+use crate::encoding::Encoding;
+use crate::history::{self, Edit, History};
+use crate::operator::Region;
+use crate::options::Options;
+use crate::texto::Texto;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    /// Picks the line ending the way vim does: DOS only if every `\n` comes
+    /// after a `\r`, old Mac only if there is no `\n` at all.
+    pub fn detect(content: &str) -> LineEnding {
+        let lf = content.matches('\n').count();
+        if lf == 0 {
+            if content.contains('\r') {
+                LineEnding::Cr
+            } else {
+                LineEnding::Lf
+            }
+        } else if content.matches("\r\n").count() == lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        match name {
+            "unix" => Some(LineEnding::Lf),
+            "dos" => Some(LineEnding::Crlf),
+            "mac" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "unix",
+            LineEnding::Crlf => "dos",
+            LineEnding::Cr => "mac",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// What the file looked like on disk, to notice when something else
+/// rewrites it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskState {
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub size: u64,
+    pub inode: u64,
+    pub dev: u64,
+}
+
+impl DiskState {
+    pub fn read(path: &str) -> Option<DiskState> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(DiskState {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.size(),
+            inode: metadata.ino(),
+            dev: metadata.dev(),
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct Archivo {
     pub path: String,
-    pub file: fs::File,
-    pub buffer: Vec<Vec<char>>,
+    /// `None` until a file that doesn't exist yet is saved for the first time.
+    pub file: Option<fs::File>,
+    pub buffer: Texto,
     pub width: usize,
     pub height: usize,
+    pub history: History,
+    pub line_ending: LineEnding,
+    /// Whether the file ended with a line break when it was read.
+    pub eol: bool,
+    /// Whether the file had no text at all when it was read. It is written
+    /// back that way as long as the buffer stays empty, where a file that
+    /// held one empty line gets its line break back.
+    pub empty: bool,
+    pub bom: bool,
+    pub encoding: Encoding,
+    /// The file has bytes the encoding can't decode. They were read as
+    /// U+FFFD, so writing the buffer would lose them.
+    pub illegal: bool,
+    pub new_file: bool,
+    pub readonly: bool,
+    /// On-disk state when the file was last read or written.
+    pub disk: Option<DiskState>,
+    /// Bumped on every change to the buffer, including undo and redo.
+    pub tick: u64,
+    /// Set when the fileformat or fileencoding is changed, which the undo
+    /// history doesn't track.
+    pub format_changed: bool,
+    /// Marks set with `m{a-z}`, and `<` and `>` left by VISUAL mode, as
+    /// `(y, x)`. They follow their line when lines are inserted or removed
+    /// above it.
+    pub marks: HashMap<char, (usize, usize)>,
+    /// Lines `:g` still has to visit, in order. Like marks they follow
+    /// their line, and are dropped when it is removed.
+    pub marked: VecDeque<usize>,
 }
 
 impl Archivo {
-    pub fn new(path: &str) -> Archivo {
-        let file = fs::File::open(path).unwrap();
-        let content = fs::read_to_string(path).unwrap();
+    pub fn new(path: &str) -> io::Result<Archivo> {
+        Archivo::open(path, None)
+    }
 
-        let mut buffer: Vec<Vec<char>> =
-            content.lines().map(|line| line.chars().collect()).collect();
-        if buffer.is_empty() {
-            buffer.push(Vec::<char>::new());
-        }
+    /// Opens `path`, decoding it with `encoding` or with the detected one.
+    /// A path that doesn't exist yet gives an empty buffer that creates the
+    /// file on the first save; any other error is returned.
+    pub fn open(path: &str, encoding: Option<Encoding>) -> io::Result<Archivo> {
+        let contents = Contents::read(path, encoding)?;
+        let width = (0..contents.buffer.len_lines())
+            .map(|y| contents.buffer.line_len(y))
+            .max()
+            .unwrap_or(0);
+        let height = contents.buffer.len_lines();
 
-        let width = buffer.iter().map(|line| line.len()).max().unwrap_or(0);
-        let height = buffer.len();
+        let history = undo_path(path)
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|data| History::deserialize(&data, contents.hash))
+            .unwrap_or_default();
 
-        Archivo {
+        Ok(Archivo {
             path: path.to_string(),
-            file,
-            buffer,
+            new_file: contents.file.is_none(),
+            file: contents.file,
+            buffer: contents.buffer,
             width,
             height,
+            history,
+            line_ending: contents.line_ending,
+            eol: contents.eol,
+            empty: contents.empty,
+            bom: contents.bom,
+            encoding: contents.encoding,
+            illegal: contents.illegal,
+            readonly: contents.readonly,
+            disk: DiskState::read(path),
+            tick: 0,
+            format_changed: false,
+            marks: HashMap::new(),
+            marked: VecDeque::new(),
+        })
+    }
+
+    /// An empty, read-only buffer for a path that couldn't be read.
+    pub fn empty(path: &str) -> Archivo {
+        Archivo {
+            path: path.to_string(),
+            file: None,
+            buffer: Texto::new(),
+            width: 0,
+            height: 1,
+            history: History::new(),
+            line_ending: LineEnding::Lf,
+            eol: true,
+            empty: true,
+            bom: false,
+            encoding: Encoding::Utf8,
+            illegal: false,
+            new_file: false,
+            readonly: true,
+            disk: None,
+            tick: 0,
+            format_changed: false,
+            marks: HashMap::new(),
+            marked: VecDeque::new(),
         }
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let flattened: String = self
-            .buffer
-            .iter()
-            .flat_map(|line| line.iter().cloned().chain(std::iter::once('\n')))
-            .collect();
+    /// Whether there are changes that haven't been written. Undoing back to
+    /// the saved state counts as unmodified again.
+    pub fn modified(&self) -> bool {
+        self.history.modified() || self.format_changed
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.format_changed |= line_ending != self.line_ending;
+        self.line_ending = line_ending;
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.format_changed |= encoding != self.encoding;
+        self.encoding = encoding;
+    }
+
+    /// Whether the file on disk is no longer the one that was read or saved.
+    pub fn changed_on_disk(&self) -> bool {
+        self.disk.is_some() && DiskState::read(&self.path) != self.disk
+    }
 
-        fs::write(&self.path, flattened)?;
+    /// Reads the file again, replacing the buffer as one undoable change.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let fresh = Contents::read(&self.path, Some(self.encoding))?;
+        self.replace_text(&fresh.buffer);
+        self.history.saved = self.history.cur;
 
+        self.file = fresh.file;
+        self.line_ending = fresh.line_ending;
+        self.eol = fresh.eol;
+        self.empty = fresh.empty;
+        self.bom = fresh.bom;
+        self.illegal = fresh.illegal;
+        self.readonly = fresh.readonly;
+        self.disk = DiskState::read(&self.path);
+        self.format_changed = false;
         Ok(())
     }
+
+    /// Replaces the whole buffer with `text` as one undoable change.
+    pub fn replace_text(&mut self, text: &Texto) {
+        self.history.commit();
+        self.begin_change(0, 0);
+        self.edit(Edit::Replace {
+            old: self.buffer.to_string().chars().collect(),
+            new: text.to_string().chars().collect(),
+        });
+        self.end_change();
+    }
+
+    /// The file as it is on disk now, split into lines the same way the
+    /// buffer is.
+    pub fn disk_lines(&self) -> io::Result<Vec<String>> {
+        let disk = Contents::read(&self.path, Some(self.encoding))?.buffer;
+        Ok((0..disk.len_lines()).map(|y| disk.line_string(y)).collect())
+    }
+
+    pub fn metadata(&self) -> Option<fs::Metadata> {
+        self.file.as_ref()?.metadata().ok()
+    }
+
+    pub fn begin_change(&mut self, y: usize, x: usize) {
+        self.history.begin((y, x));
+    }
+
+    pub fn end_change(&mut self) {
+        self.history.commit();
+    }
+
+    /// Starts a change that lasts until `release_changes`, whatever is
+    /// begun and ended inside it.
+    pub fn hold_changes(&mut self, y: usize, x: usize) {
+        self.history.hold((y, x));
+    }
+
+    pub fn release_changes(&mut self) {
+        self.history.release();
+    }
+
+    pub fn insert_char(&mut self, y: usize, x: usize, c: char) {
+        self.insert_text(y, x, &[c]);
+    }
+
+    pub fn insert_text(&mut self, y: usize, x: usize, text: &[char]) {
+        if text.is_empty() {
+            return;
+        }
+        let x = x.min(self.buffer.line_len(y));
+        self.edit(Edit::InsertText {
+            y,
+            x,
+            text: text.to_vec(),
+        });
+    }
+
+    /// Removes up to `len` characters starting at `x` and returns them.
+    pub fn remove_text(&mut self, y: usize, x: usize, len: usize) -> Vec<char> {
+        let line = self.buffer.line(y);
+        let end = (x + len).min(line.len());
+        if x >= end {
+            return Vec::new();
+        }
+        let text: Vec<char> = line[x..end].to_vec();
+        self.edit(Edit::RemoveText {
+            y,
+            x,
+            text: text.clone(),
+        });
+        text
+    }
+
+    pub fn insert_line(&mut self, y: usize, line: Vec<char>) {
+        self.edit(Edit::InsertLine { y, line });
+    }
+
+    /// Removes line `y` and returns its text. The buffer always keeps one
+    /// line, so removing the only one just empties it.
+    pub fn remove_line(&mut self, y: usize) -> Vec<char> {
+        if self.buffer.len_lines() == 1 {
+            let len = self.buffer.line_len(0);
+            return self.remove_text(0, 0, len);
+        }
+        let line = self.buffer.line(y);
+        self.edit(Edit::RemoveLine {
+            y,
+            line: line.clone(),
+        });
+        line
+    }
+
+    /// Removes lines `start..=end` and returns them.
+    pub fn remove_lines(&mut self, start: usize, end: usize) -> Vec<Vec<char>> {
+        (start..=end).map(|_| self.remove_line(start)).collect()
+    }
+
+    /// Inserts `lines` so that the first one becomes line `y`.
+    pub fn insert_lines(&mut self, y: usize, lines: Vec<Vec<char>>) {
+        for (i, line) in lines.into_iter().enumerate() {
+            self.insert_line(y + i, line);
+        }
+    }
+
+    /// Removes the text in `region`, joining the lines at its ends, and
+    /// returns it one entry per line.
+    pub fn remove_region(&mut self, region: &Region) -> Vec<String> {
+        let text = region.text(&self.buffer);
+        let (first, last) = region.lines();
+        if region.linewise {
+            self.remove_lines(first, last);
+            return text;
+        }
+        let (from, _) = region.columns(first, self.buffer.line_len(first));
+        let (_, to) = region.columns(last, self.buffer.line_len(last));
+        if first == last {
+            self.remove_text(first, from, to - from);
+            return text;
+        }
+        let len = self.buffer.line_len(first);
+        self.remove_text(first, from, len);
+        for _ in first + 1..last {
+            self.remove_line(first + 1);
+        }
+        let rest = self.remove_line(first + 1);
+        self.insert_text(first, from, &rest[to..]);
+        text
+    }
+
+    /// Moves through the undo tree to the state `target`, which may be on
+    /// another branch. Returns where the cursor should go.
+    pub fn undo_to(&mut self, target: usize) -> Option<(usize, usize)> {
+        if target >= self.history.nodes.len() || target == self.history.cur {
+            return None;
+        }
+        let (up, down) = self.history.path_to(target);
+        let mut cursor = None;
+        for n in up {
+            let change = self.history.nodes[n].change.clone();
+            for edit in change.edits.iter().rev() {
+                self.apply(&edit.inverse());
+            }
+            cursor = Some(change.cursor);
+        }
+        for n in down {
+            let change = self.history.nodes[n].change.clone();
+            for edit in change.edits.iter() {
+                self.apply(edit);
+            }
+            cursor = Some(change.cursor);
+        }
+        self.history.cur = target;
+        cursor
+    }
+
+    /// Undoes the last change and returns the cursor position it started at.
+    pub fn undo(&mut self) -> Option<(usize, usize)> {
+        let change = self.history.undo()?;
+        for edit in change.edits.iter().rev() {
+            self.apply(&edit.inverse());
+        }
+        Some(change.cursor)
+    }
+
+    pub fn redo(&mut self) -> Option<(usize, usize)> {
+        let change = self.history.redo()?;
+        for edit in change.edits.iter() {
+            self.apply(edit);
+        }
+        Some(change.cursor)
+    }
+
+    fn edit(&mut self, edit: Edit) {
+        self.apply(&edit);
+        let cursor = match &edit {
+            Edit::InsertText { y, x, .. } | Edit::RemoveText { y, x, .. } => (*y, *x),
+            Edit::InsertLine { y, .. } | Edit::RemoveLine { y, .. } => (*y, 0),
+            Edit::Replace { .. } => (0, 0),
+        };
+        self.history.record(edit, cursor);
+    }
+
+    fn apply(&mut self, edit: &Edit) {
+        self.tick += 1;
+        match edit {
+            Edit::InsertText { y, x, text } => {
+                self.buffer.insert(*y, *x, text);
+            }
+            Edit::RemoveText { y, x, text } => {
+                self.buffer.remove(*y, *x, text.len());
+            }
+            Edit::InsertLine { y, line } => {
+                self.buffer.insert_line(*y, line);
+                for mark in self.marks.values_mut() {
+                    if mark.0 >= *y {
+                        mark.0 += 1;
+                    }
+                }
+                for line in self.marked.iter_mut().filter(|line| **line >= *y) {
+                    *line += 1;
+                }
+            }
+            Edit::RemoveLine { y, .. } => {
+                self.buffer.remove_line(*y);
+                self.marks.retain(|_, mark| mark.0 != *y);
+                for mark in self.marks.values_mut() {
+                    if mark.0 > *y {
+                        mark.0 -= 1;
+                    }
+                }
+                self.marked.retain(|line| line != y);
+                for line in self.marked.iter_mut().filter(|line| **line > *y) {
+                    *line -= 1;
+                }
+            }
+            Edit::Replace { new, .. } => {
+                self.buffer = Texto::from_text(&new.iter().collect::<String>());
+                let len = self.buffer.len_lines();
+                self.marks.retain(|_, mark| mark.0 < len);
+                self.marked.retain(|line| *line < len);
+            }
+        }
+    }
+
+    /// The buffer as it is written to disk, with its BOM, line endings and
+    /// encoding.
+    fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut flattened = String::with_capacity(self.buffer.len_bytes() + 4);
+        if self.bom {
+            flattened.push('\u{feff}');
+        }
+        match self.line_ending {
+            LineEnding::Lf => flattened.push_str(&self.buffer.to_string()),
+            ending => {
+                for (y, line) in self.buffer.lines().enumerate() {
+                    if y > 0 {
+                        flattened.push_str(ending.as_str());
+                    }
+                    flattened.extend(line);
+                }
+            }
+        }
+        if self.eol && !(self.empty && self.buffer.len_chars() == 0) {
+            flattened.push_str(self.line_ending.as_str());
+        }
+
+        Ok(self.encoding.encode(&flattened)?)
+    }
+
+    pub fn save(&mut self, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes = self.to_bytes()?;
+        let target = write_file(&self.path, &bytes, options)?;
+        self.file = Some(fs::File::open(&target)?);
+        self.new_file = false;
+        self.illegal = false;
+        self.empty &= self.buffer.len_chars() == 0;
+        self.disk = DiskState::read(&self.path);
+        self.history.saved = self.history.cur;
+        self.format_changed = false;
+        self.save_undo(content_hash(&bytes, self.encoding))?;
+
+        Ok(())
+    }
+
+    /// Writes the buffer to another file, for `:w file`. The buffer keeps
+    /// its own path and stays modified. Returns the number of bytes written.
+    pub fn write_to(
+        &self,
+        path: &str,
+        options: &Options,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let bytes = self.to_bytes()?;
+        write_file(path, &bytes, options)?;
+        Ok(bytes.len())
+    }
+
+    /// Stores the undo tree next to the other rim state, keyed by the file's
+    /// path and tagged with a hash of what was just written.
+    fn save_undo(&self, hash: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(p) = undo_path(&self.path) {
+            if let Some(dir) = p.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_private(&p, self.history.serialize(hash).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// A file as it is on disk, decoded and split into lines.
+struct Contents {
+    /// `None` when the file doesn't exist.
+    file: Option<fs::File>,
+    buffer: Texto,
+    encoding: Encoding,
+    illegal: bool,
+    line_ending: LineEnding,
+    eol: bool,
+    empty: bool,
+    bom: bool,
+    readonly: bool,
+    /// What the undo file is tagged with.
+    hash: u64,
+}
+
+impl Contents {
+    /// Reads `path`, decoding it with `encoding` or with the detected one.
+    /// A path that doesn't exist reads as an empty file.
+    fn read(path: &str, encoding: Option<Encoding>) -> io::Result<Contents> {
+        let (file, bytes) = match fs::File::open(path) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                (Some(file), bytes)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (None, Vec::new()),
+            Err(err) => return Err(err),
+        };
+        // Opening for writing without truncating leaves the file untouched.
+        let readonly = file.is_some() && fs::OpenOptions::new().write(true).open(path).is_err();
+
+        let encoding = encoding.unwrap_or_else(|| Encoding::detect(&bytes));
+        let content = encoding.decode(&bytes);
+
+        let bom = content.starts_with('\u{feff}');
+        let text = content.strip_prefix('\u{feff}').unwrap_or(&content);
+        let line_ending = LineEnding::detect(text);
+        let empty = text.is_empty();
+        let eol = empty || text.ends_with(line_ending.as_str());
+        let text = text.strip_suffix(line_ending.as_str()).unwrap_or(text);
+        let buffer = match line_ending {
+            LineEnding::Lf => Texto::from_text(text),
+            _ => Texto::from_lines(text.split(line_ending.as_str())),
+        };
+
+        Ok(Contents {
+            file,
+            buffer,
+            encoding,
+            illegal: !encoding.valid(&bytes),
+            line_ending,
+            eol,
+            empty,
+            bom,
+            readonly,
+            hash: content_hash(&bytes, encoding),
+        })
+    }
+}
+
+/// Writes `bytes` to `path` and returns the file that was written, which
+/// differs from `path` for symlinks.
+fn write_file(
+    path: &str,
+    bytes: &[u8],
+    options: &Options,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // Write through symlinks instead of replacing them with a regular file.
+    let target = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    if let Some(dir) = target.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if !dir.exists() {
+            if !options.mkdir {
+                return Err(format!(
+                    "\"{}\" doesn't exist, :set mkdir to create it",
+                    dir.display()
+                )
+                .into());
+            }
+            fs::create_dir_all(dir)?;
+        }
+    }
+    if options.backup && target.exists() {
+        fs::copy(&target, backup_path(&target, options))?;
+    }
+    write_atomic(&target, bytes, None)?;
+    Ok(target)
+}
+
+/// Writes `bytes` to a temporary file in the same directory, flushes it to
+/// disk and renames it over `path`, so a crash or a full disk leaves either
+/// the old or the new contents but never a truncated file. The file gets
+/// `mode`, or else the mode bits of the file being replaced, and the owner
+/// of the file being replaced.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{}.rim{}.tmp", name, std::process::id()));
+    let metadata = fs::metadata(path).ok();
+    let mode = mode.or(metadata.as_ref().map(|m| m.permissions().mode() & 0o7777));
+
+    // The temporary file is never readable by more users than the file it
+    // becomes, not even before its mode is fixed up.
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode.unwrap_or(0o666) & 0o777)
+        .open(&tmp)
+    {
+        Ok(file) => file,
+        // A writable file in a directory we can't create files in can still
+        // be saved, just not atomically.
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied && path.exists() => {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(path)?;
+            file.write_all(bytes)?;
+            return file.sync_all();
+        }
+        Err(err) => return Err(err),
+    };
+
+    let result = (|| {
+        if let Some(metadata) = &metadata {
+            // Only root can give a file away; keeping our own uid is fine.
+            let _ = std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()));
+        }
+        // Puts back the bits the umask took, and the setuid ones chown cleared.
+        if let Some(mode) = mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Writes one of rim's own files, which can hold text from the files being
+/// edited, so that only the user can read it. A file left readable by an
+/// older version is made private before anything is written to it.
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(bytes)
+}
+
+/// `file~` next to the file, or `%path%to%file~` inside `backupdir`.
+fn backup_path(path: &Path, options: &Options) -> PathBuf {
+    match options.backupdir() {
+        Some(dir) => {
+            let _ = fs::create_dir_all(&dir);
+            dir.join(format!("{}~", path.display().to_string().replace('/', "%")))
+        }
+        None => {
+            let mut name = path.as_os_str().to_owned();
+            name.push("~");
+            PathBuf::from(name)
+        }
+    }
+}
+
+/// Identifies file contents for the undo file. The encoding is part of it
+/// because the same bytes decode to different text, and different columns.
+fn content_hash(bytes: &[u8], encoding: Encoding) -> u64 {
+    history::hash(bytes) ^ history::hash(encoding.name().as_bytes())
+}
+
+/// Directory for rim's own files, following the XDG base directory spec.
+pub fn state_dir(sub: &str) -> Option<PathBuf> {
+    let base = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".local/state"),
+    };
+    Some(base.join("rim").join(sub))
+}
+
+/// Turns a path into a flat file name, `/home/a/b.rs` becomes `%home%a%b.rs`.
+pub fn escape_path(path: &str) -> String {
+    let full = fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| path.to_string());
+    full.replace('/', "%")
+}
+
+fn undo_path(path: &str) -> Option<PathBuf> {
+    Some(state_dir("undo")?.join(escape_path(path)))
 }
 
 pub fn format_permissions(permissions: fs::Permissions, is_directory: bool) -> String {
@@ -80,3 +761,184 @@ pub fn is_file(path: impl AsRef<str>) -> bool {
     let path = std::path::Path::new(path.as_ref());
     path.is_file()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search;
+
+    /// Opens `bytes` as a file and gives back what saving it would write.
+    fn round_trip(name: &str, bytes: &[u8]) -> (Archivo, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("rim-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let archivo = Archivo::open(path.to_str().unwrap(), None).unwrap();
+        let written = archivo.to_bytes().unwrap();
+        fs::remove_file(&path).unwrap();
+        (archivo, written)
+    }
+
+    #[test]
+    fn detects_line_endings() {
+        assert_eq!(LineEnding::detect(""), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect("a\r\nb\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\rb\r"), LineEnding::Cr);
+    }
+
+    #[test]
+    fn files_are_written_back_unchanged() {
+        let files: &[&[u8]] = &[
+            b"",
+            b"\n",
+            b"\n\n",
+            b"a",
+            b"a\n",
+            b"a\nb",
+            b"a\r\nb\r\n",
+            b"a\r\nb",
+            b"a\rb\r",
+            b"a\r\nb\n",
+            b"\xef\xbb\xbfa\n",
+            b"\xef\xbb\xbf",
+        ];
+        for (i, bytes) in files.iter().enumerate() {
+            let (_, written) = round_trip(&format!("same{}", i), bytes);
+            assert_eq!(&written, bytes, "{:?}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn format_is_read_from_the_file() {
+        let (archivo, _) = round_trip("crlf", b"\xef\xbb\xbfone\r\ntwo");
+        assert_eq!(archivo.line_ending, LineEnding::Crlf);
+        assert!(archivo.bom);
+        assert!(!archivo.eol);
+        assert_eq!(archivo.buffer.to_string(), "one\ntwo");
+
+        let (archivo, _) = round_trip("blank", b"\n");
+        assert!(archivo.eol);
+        assert!(!archivo.empty);
+        assert_eq!(archivo.buffer.len_lines(), 1);
+    }
+
+    #[test]
+    fn illegal_bytes_are_flagged() {
+        let path = std::env::temp_dir().join(format!("rim-{}-illegal", std::process::id()));
+        fs::write(&path, b"a\xf1o\n").unwrap();
+        let name = path.to_str().unwrap();
+        let detected = Archivo::open(name, None).unwrap();
+        assert_eq!(detected.encoding, Encoding::Latin1);
+        assert!(!detected.illegal);
+        let forced = Archivo::open(name, Some(Encoding::Utf8)).unwrap();
+        assert!(forced.illegal);
+        assert_eq!(forced.buffer.to_string(), "a\u{fffd}o");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn an_emptied_buffer_keeps_one_line_break() {
+        let (mut archivo, _) = round_trip("emptied", b"a\n");
+        let len = archivo.buffer.line_len(0);
+        archivo.remove_text(0, 0, len);
+        assert_eq!(archivo.to_bytes().unwrap(), b"\n");
+        archivo.set_line_ending(LineEnding::Crlf);
+        assert_eq!(archivo.to_bytes().unwrap(), b"\r\n");
+    }
+
+    #[test]
+    fn missing_files_open_empty() {
+        let path = std::env::temp_dir().join(format!("rim-{}-missing", std::process::id()));
+        let archivo = Archivo::open(path.to_str().unwrap(), None).unwrap();
+        assert!(archivo.new_file);
+        assert!(!archivo.readonly);
+        assert!(archivo.file.is_none());
+        assert_eq!(archivo.buffer.len_lines(), 1);
+        assert_eq!(archivo.buffer.len_chars(), 0);
+        assert!(!archivo.modified());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn undo_and_redo_restore_text_and_cursor() {
+        let (mut archivo, _) = round_trip("undo", b"one\ntwo\n");
+        archivo.begin_change(1, 2);
+        archivo.insert_text(1, 3, &['!']);
+        archivo.insert_line(2, "three".chars().collect());
+        archivo.end_change();
+        archivo.begin_change(0, 1);
+        archivo.remove_text(0, 0, 2);
+        archivo.end_change();
+        assert_eq!(archivo.buffer.to_string(), "e\ntwo!\nthree");
+
+        assert_eq!(archivo.undo(), Some((0, 1)));
+        assert_eq!(archivo.buffer.to_string(), "one\ntwo!\nthree");
+        assert_eq!(archivo.undo(), Some((1, 2)));
+        assert_eq!(archivo.buffer.to_string(), "one\ntwo");
+        assert!(!archivo.modified());
+        assert_eq!(archivo.undo(), None);
+
+        assert_eq!(archivo.redo(), Some((1, 2)));
+        assert_eq!(archivo.buffer.to_string(), "one\ntwo!\nthree");
+        assert_eq!(archivo.redo(), Some((0, 1)));
+        assert_eq!(archivo.buffer.to_string(), "e\ntwo!\nthree");
+        assert_eq!(archivo.redo(), None);
+        assert!(archivo.modified());
+    }
+
+    #[test]
+    fn marked_lines_follow_deletes() {
+        // What `:g/x/d` and `:v/x/d` do, one marked line at a time.
+        let delete = |invert: bool| {
+            let (mut archivo, _) = round_trip("global", b"x1\nx2\na\nx3\nb\nb\nx4\n");
+            let re = search::compile("x", false, false).unwrap();
+            let last = archivo.buffer.len_lines() - 1;
+            archivo.marked = search::matching_lines(&archivo.buffer, &re, (0, last), invert);
+            while let Some(y) = archivo.marked.pop_front() {
+                archivo.remove_line(y);
+            }
+            archivo.buffer.to_string()
+        };
+        assert_eq!(delete(false), "a\nb\nb");
+        assert_eq!(delete(true), "x1\nx2\nx3\nx4");
+    }
+
+    #[test]
+    fn reload_reads_the_file_again() {
+        let path = std::env::temp_dir().join(format!("rim-{}-reload", std::process::id()));
+        let name = path.to_str().unwrap();
+        fs::write(&path, "one\n").unwrap();
+        let mut archivo = Archivo::open(name, None).unwrap();
+        fs::write(&path, "one\r\ntwo").unwrap();
+        assert_eq!(archivo.disk_lines().unwrap(), ["one", "two"]);
+        archivo.reload().unwrap();
+        assert_eq!(archivo.buffer.to_string(), "one\ntwo");
+        assert_eq!(archivo.line_ending, LineEnding::Crlf);
+        assert!(!archivo.eol);
+        assert!(!archivo.modified());
+        assert!(!archivo.changed_on_disk());
+        // The old text is a change away.
+        archivo.undo();
+        assert_eq!(archivo.buffer.to_string(), "one");
+        assert!(archivo.modified());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saving_keeps_the_mode() {
+        let path = std::env::temp_dir().join(format!("rim-{}-mode", std::process::id()));
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let name = path.to_str().unwrap();
+        let mut archivo = Archivo::open(name, None).unwrap();
+        archivo.insert_text(0, 3, &['!']);
+        archivo.write_to(name, &Options::default()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"old!\n");
+        assert_eq!(mode(&path), 0o640);
+        // An explicit mode wins.
+        write_atomic(&path, b"new\n", Some(0o600)).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,519 @@
+/// A single primitive modification of `Archivo::buffer`. Every edit knows how
+/// to build its inverse, so a list of them can be replayed in both directions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    InsertText {
+        y: usize,
+        x: usize,
+        text: Vec<char>,
+    },
+    RemoveText {
+        y: usize,
+        x: usize,
+        text: Vec<char>,
+    },
+    InsertLine {
+        y: usize,
+        line: Vec<char>,
+    },
+    RemoveLine {
+        y: usize,
+        line: Vec<char>,
+    },
+    /// The whole buffer, as text joined by `\n`, replaced at once.
+    Replace {
+        old: Vec<char>,
+        new: Vec<char>,
+    },
+}
+
+impl Edit {
+    pub fn inverse(&self) -> Edit {
+        match self {
+            Edit::InsertText { y, x, text } => Edit::RemoveText {
+                y: *y,
+                x: *x,
+                text: text.clone(),
+            },
+            Edit::RemoveText { y, x, text } => Edit::InsertText {
+                y: *y,
+                x: *x,
+                text: text.clone(),
+            },
+            Edit::InsertLine { y, line } => Edit::RemoveLine {
+                y: *y,
+                line: line.clone(),
+            },
+            Edit::RemoveLine { y, line } => Edit::InsertLine {
+                y: *y,
+                line: line.clone(),
+            },
+            Edit::Replace { old, new } => Edit::Replace {
+                old: new.clone(),
+                new: old.clone(),
+            },
+        }
+    }
+}
+
+/// One undoable step: all the edits made by a single command or insert session,
+/// plus the cursor position where the change started.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub edits: Vec<Edit>,
+    pub cursor: (usize, usize),
+}
+
+impl Change {
+    pub fn new(cursor: (usize, usize)) -> Self {
+        Self {
+            edits: Vec::new(),
+            cursor,
+        }
+    }
+}
+
+/// A state in the undo tree. Node 0 is the unmodified file and has no change;
+/// every other node holds the change that leads to it from `parent`.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub parent: usize,
+    pub change: Change,
+    pub time: u64,
+    pub last_child: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    pub nodes: Vec<Node>,
+    pub cur: usize,
+    pub current: Option<Change>,
+    /// The state that matches the file on disk.
+    pub saved: usize,
+    /// While above zero commits are held back, so everything a command like
+    /// `:g` does is undone in one step.
+    pub held: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                parent: 0,
+                change: Change::new((0, 0)),
+                time: now(),
+                last_child: None,
+            }],
+            cur: 0,
+            current: None,
+            saved: 0,
+            held: 0,
+        }
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a transaction. Nested calls are folded into the one already open,
+    /// which lets `o` and the insert session that follows it undo together.
+    pub fn begin(&mut self, cursor: (usize, usize)) {
+        if self.current.is_none() {
+            self.current = Some(Change::new(cursor));
+        }
+    }
+
+    /// Closes the open transaction. Empty transactions are dropped so that
+    /// entering and leaving INSERT mode doesn't leave a no-op undo step.
+    pub fn commit(&mut self) {
+        if self.held > 0 {
+            return;
+        }
+        if let Some(change) = self.current.take() {
+            if !change.edits.is_empty() {
+                self.push(change);
+            }
+        }
+    }
+
+    /// Opens a transaction that the commits inside it don't close.
+    pub fn hold(&mut self, cursor: (usize, usize)) {
+        self.begin(cursor);
+        self.held += 1;
+    }
+
+    pub fn release(&mut self) {
+        self.held = self.held.saturating_sub(1);
+        self.commit();
+    }
+
+    pub fn record(&mut self, edit: Edit, cursor: (usize, usize)) {
+        match self.current.as_mut() {
+            Some(change) => change.edits.push(edit),
+            None => {
+                let mut change = Change::new(cursor);
+                change.edits.push(edit);
+                self.push(change);
+            }
+        }
+    }
+
+    /// Adds a new state as a child of the current one. Older children are kept,
+    /// so undoing and then editing starts a new branch instead of losing one.
+    fn push(&mut self, change: Change) {
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            parent: self.cur,
+            change,
+            time: now(),
+            last_child: None,
+        });
+        self.nodes[self.cur].last_child = Some(idx);
+        self.cur = idx;
+    }
+
+    pub fn undo(&mut self) -> Option<Change> {
+        self.commit();
+        if self.cur == 0 {
+            return None;
+        }
+        let node = self.cur;
+        self.cur = self.nodes[node].parent;
+        self.nodes[self.cur].last_child = Some(node);
+        Some(self.nodes[node].change.clone())
+    }
+
+    /// Redoes along the branch that was most recently undone or created.
+    pub fn redo(&mut self) -> Option<Change> {
+        self.commit();
+        let child = self.nodes[self.cur].last_child?;
+        self.cur = child;
+        Some(self.nodes[child].change.clone())
+    }
+
+    /// Returns the nodes to undo (walking up) and to redo (walking down) to get
+    /// from the current state to `target`, crossing branches if necessary.
+    pub fn path_to(&mut self, target: usize) -> (Vec<usize>, Vec<usize>) {
+        self.commit();
+        let ancestors = |mut n: usize| {
+            let mut v = vec![n];
+            while n != 0 {
+                n = self.nodes[n].parent;
+                v.push(n);
+            }
+            v
+        };
+        let from = ancestors(self.cur);
+        let to = ancestors(target);
+        let common = *from.iter().find(|n| to.contains(n)).unwrap_or(&0);
+
+        let up = from.into_iter().take_while(|&n| n != common).collect();
+        let mut down: Vec<usize> = to.into_iter().take_while(|&n| n != common).collect();
+        down.reverse();
+        (up, down)
+    }
+
+    /// Whether the buffer differs from the last loaded or saved state.
+    pub fn modified(&self) -> bool {
+        self.cur != self.saved || self.current.as_ref().is_some_and(|c| !c.edits.is_empty())
+    }
+
+    /// Leaves of the tree, i.e. the tip of every branch, oldest first.
+    pub fn leaves(&self) -> Vec<usize> {
+        (1..self.nodes.len())
+            .filter(|&n| !self.nodes.iter().any(|node| node.parent == n))
+            .collect()
+    }
+
+    pub fn depth(&self, mut n: usize) -> usize {
+        let mut depth = 0;
+        while n != 0 {
+            n = self.nodes[n].parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    pub fn serialize(&self, hash: u64) -> String {
+        let mut out = format!("rim-undo 1\nhash {:016x}\ncur {}\n", hash, self.cur);
+        for node in self.nodes.iter().skip(1) {
+            out.push_str(&format!(
+                "node {} {} {} {} {}\n",
+                node.parent,
+                node.time,
+                node.change.cursor.0,
+                node.change.cursor.1,
+                node.change.edits.len()
+            ));
+            for edit in node.change.edits.iter() {
+                let line = match edit {
+                    Edit::InsertText { y, x, text } => format!("I {} {} {}", y, x, escape(text)),
+                    Edit::RemoveText { y, x, text } => format!("R {} {} {}", y, x, escape(text)),
+                    Edit::InsertLine { y, line } => format!("i {} 0 {}", y, escape(line)),
+                    Edit::RemoveLine { y, line } => format!("r {} 0 {}", y, escape(line)),
+                    // Both texts in one field, split after the old one.
+                    Edit::Replace { old, new } => {
+                        format!("B {} 0 {}{}", old.len(), escape(old), escape(new))
+                    }
+                };
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Parses an undo file written by `serialize`. Returns `None` when the file
+    /// is malformed or was written for different file contents.
+    pub fn deserialize(data: &str, hash: u64) -> Option<History> {
+        let mut lines = data.lines();
+        if lines.next()? != "rim-undo 1" {
+            return None;
+        }
+        let stored = u64::from_str_radix(lines.next()?.strip_prefix("hash ")?, 16).ok()?;
+        if stored != hash {
+            return None;
+        }
+        let cur: usize = lines.next()?.strip_prefix("cur ")?.parse().ok()?;
+
+        let mut history = History::new();
+        while let Some(line) = lines.next() {
+            let f: Vec<&str> = line.strip_prefix("node ")?.split(' ').collect();
+            if f.len() != 5 {
+                return None;
+            }
+            let parent: usize = f[0].parse().ok()?;
+            let mut change = Change::new((f[2].parse().ok()?, f[3].parse().ok()?));
+            for _ in 0..f[4].parse::<usize>().ok()? {
+                let mut e = lines.next()?.splitn(4, ' ');
+                let kind = e.next()?;
+                let y: usize = e.next()?.parse().ok()?;
+                let x: usize = e.next()?.parse().ok()?;
+                let mut text = unescape(e.next().unwrap_or(""));
+                change.edits.push(match kind {
+                    "I" => Edit::InsertText { y, x, text },
+                    "R" => Edit::RemoveText { y, x, text },
+                    "i" => Edit::InsertLine { y, line: text },
+                    "r" => Edit::RemoveLine { y, line: text },
+                    "B" if y <= text.len() => Edit::Replace {
+                        new: text.split_off(y),
+                        old: text,
+                    },
+                    _ => return None,
+                });
+            }
+            let idx = history.nodes.len();
+            if parent >= idx {
+                return None;
+            }
+            history.nodes.push(Node {
+                parent,
+                change,
+                time: f[1].parse().ok()?,
+                last_child: None,
+            });
+            history.nodes[parent].last_child = Some(idx);
+        }
+        if cur >= history.nodes.len() {
+            return None;
+        }
+        history.cur = cur;
+        history.saved = cur;
+        Some(history)
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// How long ago `time` was, for lists like `:undolist`.
+pub fn ago(time: u64) -> String {
+    let ago = now().saturating_sub(time);
+    if ago < 100 {
+        format!("{} seconds ago", ago)
+    } else if ago < 6000 {
+        format!("{} minutes ago", ago / 60)
+    } else {
+        format!("{} hours ago", ago / 3600)
+    }
+}
+
+/// FNV-1a, used to tell whether a file still matches its undo file.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+fn escape(text: &[char]) -> String {
+    let mut out = String::new();
+    for c in text {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(*c),
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> Vec<char> {
+    let mut out = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    /// A tree with a branch: an edit, an undo and another edit.
+    fn branched() -> History {
+        let mut history = History::new();
+        history.begin((0, 0));
+        history.record(
+            Edit::InsertText {
+                y: 0,
+                x: 0,
+                text: chars("a \\ b\nc\r"),
+            },
+            (0, 0),
+        );
+        history.record(
+            Edit::InsertLine {
+                y: 1,
+                line: chars("line one"),
+            },
+            (0, 0),
+        );
+        history.commit();
+        history.undo();
+        history.record(
+            Edit::RemoveLine {
+                y: 2,
+                line: Vec::new(),
+            },
+            (2, 3),
+        );
+        history
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let history = branched();
+        let data = history.serialize(42);
+        let loaded = History::deserialize(&data, 42).unwrap();
+        assert_eq!(loaded.cur, history.cur);
+        assert_eq!(loaded.saved, history.cur);
+        assert_eq!(loaded.nodes.len(), history.nodes.len());
+        for (a, b) in loaded.nodes.iter().zip(history.nodes.iter()) {
+            assert_eq!(a.parent, b.parent);
+            assert_eq!(a.time, b.time);
+            assert_eq!(a.change.cursor, b.change.cursor);
+            assert_eq!(a.change.edits, b.change.edits);
+        }
+        assert_eq!(loaded.serialize(42), data);
+    }
+
+    #[test]
+    fn deserialize_rejects_other_contents() {
+        let data = branched().serialize(42);
+        assert!(History::deserialize(&data, 43).is_none());
+        assert!(History::deserialize("rim-undo 2\nhash 0\ncur 0\n", 0).is_none());
+        assert!(History::deserialize(&data.replace("cur 2", "cur 9"), 42).is_none());
+        assert!(History::deserialize(&data.replace("node 0", "node 5"), 42).is_none());
+    }
+
+    #[test]
+    fn replace_is_kept_whole() {
+        let replace = Edit::Replace {
+            old: chars("one two\nthree"),
+            new: chars("four\n\nfive six\\"),
+        };
+        let mut history = History::new();
+        history.record(replace.clone(), (0, 0));
+        let loaded = History::deserialize(&history.serialize(7), 7).unwrap();
+        assert_eq!(loaded.nodes[1].change.edits, vec![replace.clone()]);
+        assert_eq!(replace.inverse().inverse(), replace);
+        assert!(History::deserialize(
+            "rim-undo 1\nhash 0000000000000007\ncur 1\nnode 0 0 0 0 1\nB 9 0 ab\n",
+            7
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn modified_follows_undo_and_redo() {
+        let mut history = History::new();
+        assert!(!history.modified());
+        history.begin((0, 0));
+        history.record(
+            Edit::InsertText {
+                y: 0,
+                x: 0,
+                text: chars("a"),
+            },
+            (0, 0),
+        );
+        // An open change counts before it is committed.
+        assert!(history.modified());
+        history.commit();
+        assert!(history.modified());
+        history.undo();
+        assert!(!history.modified());
+        history.redo();
+        assert!(history.modified());
+
+        // Once saved, the state before it is the modified one.
+        history.saved = history.cur;
+        assert!(!history.modified());
+        history.undo();
+        assert!(history.modified());
+        history.redo();
+        assert!(!history.modified());
+
+        // So is a new branch that leaves the saved state behind.
+        history.undo();
+        history.record(
+            Edit::InsertLine {
+                y: 0,
+                line: chars("b"),
+            },
+            (0, 0),
+        );
+        assert!(history.modified());
+    }
+
+    #[test]
+    fn hash_tells_contents_apart() {
+        assert_eq!(hash(b"abc"), hash(b"abc"));
+        assert_ne!(hash(b"abc"), hash(b"abd"));
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+    }
+}
@@ -0,0 +1,23 @@
+pub mod clipboard;
+pub mod cmdline;
+pub mod command;
+pub mod constants;
+pub mod diff;
+pub mod encoding;
+pub mod explorer;
+pub mod file;
+pub mod history;
+pub mod mode;
+pub mod motion;
+pub mod msg;
+pub mod operator;
+pub mod options;
+pub mod registers;
+pub mod repeat;
+pub mod search;
+pub mod substitute;
+pub mod swap;
+pub mod texto;
+pub mod textobject;
+pub mod ui;
+pub mod visual;
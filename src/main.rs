@@ -1,42 +1,39 @@
-pub mod constants;
-pub mod explorer;
-pub mod file;
-pub mod msg;
-pub mod ui;
-
 use std::env;
 
-use file::is_file;
+use editor_texto::{explorer, history, swap, ui};
 
 fn main() -> Result<(), std::io::Error> {
     let args: Vec<String> = env::args().collect();
 
+    if args.len() == 2 && args[1] == "-r" {
+        list_swap_files();
+        return Ok(());
+    }
+    // `rim -r file` just opens the file, which offers to recover it.
+    let args: Vec<String> = args.into_iter().filter(|arg| arg != "-r").collect();
+
     if args.len() < 2 {
         let mut explorer = explorer::Explorer::raw("");
         explorer.get_files()?;
         explorer.display();
         let a = explorer.update_raw();
-        match a {
-            Some(p) => {
-                let mut state = ui::State::new(&p);
-                state.display();
-                state.update();
-            }
-            None => (),
+        if let Some(p) = a {
+            let mut state = ui::State::new(&p);
+            state.display();
+            state.update();
         }
     } else {
-        if !is_file(&args[1]) {
+        // Anything that isn't a directory is opened, including files that
+        // don't exist yet.
+        if std::path::Path::new(&args[1]).is_dir() {
             let mut explorer = explorer::Explorer::raw(&args[1]);
             explorer.get_files()?;
             explorer.display();
             let a = explorer.update_raw();
-            match a {
-                Some(p) => {
-                    let mut state = ui::State::new(&p);
-                    state.display();
-                    state.update();
-                }
-                None => (),
+            if let Some(p) = a {
+                let mut state = ui::State::new(&p);
+                state.display();
+                state.update();
             }
         } else {
             let mut state = ui::State::new(&args[1]);
@@ -47,3 +44,36 @@ fn main() -> Result<(), std::io::Error> {
 
     Ok(())
 }
+
+/// `rim -r`: lists the swap files that can be recovered.
+fn list_swap_files() {
+    let dir = swap::swap_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    let swaps = swap::list();
+    if swaps.is_empty() {
+        println!("No swap files found in {}", dir);
+        return;
+    }
+    println!("Swap files found in {}:", dir);
+    for (i, found) in swaps.iter().enumerate() {
+        let name = found.path.file_name().unwrap_or_default().to_string_lossy();
+        println!("{}.  {}", i + 1, name);
+        println!("        file: {}", found.file);
+        println!(
+            "    modified: {}",
+            if found.modified { "YES" } else { "no" }
+        );
+        println!(
+            "     process: {} on {}{}",
+            found.pid,
+            found.host,
+            if found.running() {
+                " (STILL RUNNING)"
+            } else {
+                ""
+            }
+        );
+        println!("        time: {}", history::ago(found.time));
+    }
+}
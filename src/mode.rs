@@ -0,0 +1,35 @@
+use crate::visual::VisualKind;
+
+/// What the keys typed do. `State::update` reads every key in one loop and
+/// hands it to the handler of the current mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    /// `R`, typing over the text.
+    Replace,
+    Visual(VisualKind),
+    /// Typing a `:` command.
+    CommandLine,
+    /// After an operator, waiting for its motion or text object.
+    OperatorPending,
+    /// Typing the pattern of `/` or `?`.
+    Search,
+}
+
+impl Mode {
+    /// What the status bar shows.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Replace => "REPLACE",
+            Mode::Visual(VisualKind::Char) => "VISUAL",
+            Mode::Visual(VisualKind::Line) => "V-LINE",
+            Mode::Visual(VisualKind::Block) => "V-BLOCK",
+            Mode::CommandLine => "COMMAND",
+            Mode::OperatorPending => "O-PENDING",
+            Mode::Search => "SEARCH",
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use crate::texto::Texto;
+use ncurses::{KEY_DOWN, KEY_LEFT, KEY_RIGHT, KEY_UP};
+
+/// A cursor movement in NORMAL mode, which is also what an operator like
+/// `d` or `y` works over.
+///
+/// Positions may be one past the last character of a line, where the line
+/// break is; motions treat it as white space, as vim does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Motion {
+    /// `h`
+    Left,
+    /// `l`
+    Right,
+    /// `k`
+    Up,
+    /// `j`
+    Down,
+    /// `0`
+    LineStart,
+    /// `^`
+    FirstNonBlank,
+    /// `$`
+    LineEnd,
+    /// `w`, or `W` for a WORD, which is anything between white space.
+    WordForward { big: bool },
+    /// `b`, `B`
+    WordBackward { big: bool },
+    /// `e`, `E`
+    WordEnd { big: bool },
+    /// `ge`, `gE`
+    WordEndBackward { big: bool },
+    /// `gg`, or line `count`.
+    FirstLine,
+    /// `G`, or line `count`.
+    LastLine,
+    /// `}`
+    ParagraphForward,
+    /// `{`
+    ParagraphBackward,
+    /// `H`, or `count` lines below the top of the screen.
+    ScreenTop,
+    /// `M`
+    ScreenMiddle,
+    /// `L`, or `count` lines above the bottom of the screen.
+    ScreenBottom,
+    /// `f`, `F`, `t` and `T` followed by `ch`. `till` stops next to it.
+    /// `repeat` is set for `;` and `,`, which skip a `ch` right next to the
+    /// cursor so that repeating `t` moves on.
+    Find {
+        ch: char,
+        forward: bool,
+        till: bool,
+        repeat: bool,
+    },
+    /// `%` jumps to the bracket matching the one under or after the cursor.
+    MatchPair,
+    /// `{count}%` goes to that percentage of the buffer.
+    Percent,
+}
+
+/// Where the cursor is and what else a motion may depend on.
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub cursor: (usize, usize),
+    /// The column `j` and `k` try to keep, `usize::MAX` after `$`.
+    pub want: usize,
+    /// The count typed before the motion, if any.
+    pub count: Option<usize>,
+    /// Set when an operator is pending. The last word `w` moves over then
+    /// ends at the line break, so `dw` doesn't join lines.
+    pub operator: bool,
+    /// The first line on screen and how many fit, for `H`, `M` and `L`.
+    pub top: usize,
+    pub rows: usize,
+}
+
+impl Motion {
+    /// The motion for a key typed in NORMAL mode.
+    pub fn from_key(ch: i32) -> Option<Motion> {
+        Some(match ch {
+            104 | KEY_LEFT => Motion::Left,
+            108 | KEY_RIGHT => Motion::Right,
+            107 | KEY_UP => Motion::Up,
+            106 | KEY_DOWN => Motion::Down,
+            48 => Motion::LineStart,
+            94 => Motion::FirstNonBlank,
+            36 => Motion::LineEnd,
+            119 => Motion::WordForward { big: false },
+            87 => Motion::WordForward { big: true },
+            66 => Motion::WordBackward { big: true },
+            69 => Motion::WordEnd { big: true },
+            98 => Motion::WordBackward { big: false },
+            101 => Motion::WordEnd { big: false },
+            71 => Motion::LastLine,
+            125 => Motion::ParagraphForward,
+            123 => Motion::ParagraphBackward,
+            72 => Motion::ScreenTop,
+            77 => Motion::ScreenMiddle,
+            76 => Motion::ScreenBottom,
+            37 => Motion::MatchPair,
+            _ => return None,
+        })
+    }
+
+    /// The motion for a key typed after `g`.
+    pub fn from_g_key(ch: i32) -> Option<Motion> {
+        match ch {
+            103 => Some(Motion::FirstLine),
+            101 => Some(Motion::WordEndBackward { big: false }),
+            69 => Some(Motion::WordEndBackward { big: true }),
+            _ => None,
+        }
+    }
+
+    /// Whether an operator works on whole lines with this motion, like `dj`.
+    pub fn linewise(&self) -> bool {
+        matches!(
+            self,
+            Motion::Up
+                | Motion::Down
+                | Motion::FirstLine
+                | Motion::LastLine
+                | Motion::ScreenTop
+                | Motion::ScreenMiddle
+                | Motion::ScreenBottom
+                | Motion::Percent
+        )
+    }
+
+    /// Whether the character the motion ends on is part of what an operator
+    /// works on, like `de` but not `dw`.
+    pub fn inclusive(&self) -> bool {
+        matches!(
+            self,
+            Motion::WordEnd { .. }
+                | Motion::WordEndBackward { .. }
+                | Motion::Find { forward: true, .. }
+                | Motion::MatchPair
+        )
+    }
+
+    /// The find again for `;`, or going the other way for `,`.
+    pub fn repeat(&self, reverse: bool) -> Motion {
+        match *self {
+            Motion::Find {
+                ch, forward, till, ..
+            } => Motion::Find {
+                ch,
+                forward: forward != reverse,
+                till,
+                repeat: true,
+            },
+            motion => motion,
+        }
+    }
+
+    /// Where the motion takes the cursor. `None` when it can't move at all,
+    /// like `j` on the last line.
+    pub fn apply(&self, buffer: &Texto, ctx: Context) -> Option<(usize, usize)> {
+        let (y, x) = ctx.cursor;
+        let count = ctx.count.unwrap_or(1).max(1);
+        let last = buffer.len_lines() - 1;
+        Some(match self {
+            Motion::Left => {
+                if x == 0 {
+                    return None;
+                }
+                (y, x.saturating_sub(count))
+            }
+            Motion::Right => {
+                let len = buffer.line_len(y);
+                if x >= len {
+                    return None;
+                }
+                (y, x.saturating_add(count).min(len))
+            }
+            Motion::Up => {
+                if y == 0 {
+                    return None;
+                }
+                let y = y.saturating_sub(count);
+                (y, ctx.want.min(buffer.line_len(y)))
+            }
+            Motion::Down => {
+                if y == last {
+                    return None;
+                }
+                let y = y.saturating_add(count).min(last);
+                (y, ctx.want.min(buffer.line_len(y)))
+            }
+            Motion::LineStart => (y, 0),
+            Motion::FirstNonBlank => (y, first_non_blank(buffer, y)),
+            Motion::LineEnd => {
+                let y = y.saturating_add(count - 1).min(last);
+                (y, buffer.line_len(y))
+            }
+            Motion::WordForward { big } => {
+                let mut pos = (y, x);
+                for i in 0..count {
+                    let stop = ctx.operator && i + 1 == count;
+                    pos = word_forward(buffer, pos, *big, stop)?;
+                }
+                pos
+            }
+            Motion::WordBackward { big } => {
+                let mut pos = (y, x);
+                for _ in 0..count {
+                    pos = word_backward(buffer, pos, *big)?;
+                }
+                pos
+            }
+            Motion::WordEnd { big } => {
+                let mut pos = (y, x);
+                for _ in 0..count {
+                    pos = word_end(buffer, pos, *big)?;
+                }
+                pos
+            }
+            Motion::WordEndBackward { big } => {
+                let mut pos = (y, x);
+                for _ in 0..count {
+                    pos = word_end_backward(buffer, pos, *big)?;
+                }
+                pos
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let y = match (ctx.count, self) {
+                    (Some(n), _) => n.clamp(1, last + 1) - 1,
+                    (None, Motion::FirstLine) => 0,
+                    (None, _) => last,
+                };
+                (y, first_non_blank(buffer, y))
+            }
+            Motion::ParagraphForward => {
+                let empty = |y: usize| buffer.line_len(y) == 0;
+                let mut y = y;
+                for _ in 0..count {
+                    if y == last {
+                        break;
+                    }
+                    while y < last && empty(y) {
+                        y += 1;
+                    }
+                    while y < last && !empty(y) {
+                        y += 1;
+                    }
+                }
+                // Past the last paragraph it is the end of the buffer.
+                (y, if empty(y) { 0 } else { buffer.line_len(y) })
+            }
+            Motion::ParagraphBackward => {
+                let empty = |y: usize| buffer.line_len(y) == 0;
+                let mut y = y;
+                for _ in 0..count {
+                    while y > 0 && empty(y) {
+                        y -= 1;
+                    }
+                    while y > 0 && !empty(y) {
+                        y -= 1;
+                    }
+                }
+                (y, 0)
+            }
+            Motion::ScreenTop | Motion::ScreenMiddle | Motion::ScreenBottom => {
+                let top = ctx.top.min(last);
+                let bottom = top.saturating_add(ctx.rows.max(1) - 1).min(last);
+                let y = match self {
+                    Motion::ScreenTop => top.saturating_add(count - 1).min(bottom),
+                    Motion::ScreenBottom => bottom.saturating_sub(count - 1).max(top),
+                    _ => (top + bottom) / 2,
+                };
+                (y, first_non_blank(buffer, y))
+            }
+            Motion::Find {
+                ch,
+                forward,
+                till,
+                repeat,
+            } => {
+                let line = buffer.line(y);
+                // A repeated `t` would find the character it stopped at.
+                let skip = usize::from(*till && *repeat);
+                let found = if *forward {
+                    (x + 1 + skip..line.len())
+                        .filter(|&i| line[i] == *ch)
+                        .nth(count - 1)?
+                } else {
+                    (0..x.min(line.len()).saturating_sub(skip))
+                        .rev()
+                        .filter(|&i| line[i] == *ch)
+                        .nth(count - 1)?
+                };
+                match (till, forward) {
+                    (false, _) => (y, found),
+                    (true, true) => (y, found - 1),
+                    (true, false) => (y, found + 1),
+                }
+            }
+            Motion::MatchPair => match_pair(buffer, (y, x))?,
+            Motion::Percent => {
+                if count > 100 {
+                    return None;
+                }
+                let y = ((count * (last + 1)).div_ceil(100)).clamp(1, last + 1) - 1;
+                (y, first_non_blank(buffer, y))
+            }
+        })
+    }
+}
+
+pub fn first_non_blank(buffer: &Texto, y: usize) -> usize {
+    buffer
+        .line(y)
+        .iter()
+        .take_while(|c| c.is_whitespace())
+        .count()
+}
+
+/// The kind of character for word motions: 0 for white space and line
+/// breaks, 1 for punctuation, 2 for keyword characters. In a WORD anything
+/// that isn't white space is the same kind.
+pub fn class(c: Option<char>, big: bool) -> u8 {
+    match c {
+        None => 0,
+        Some(c) if c.is_whitespace() => 0,
+        Some(_) if big => 1,
+        Some(c) if c.is_alphanumeric() || c == '_' => 2,
+        Some(_) => 1,
+    }
+}
+
+fn class_at(buffer: &Texto, (y, x): (usize, usize), big: bool) -> u8 {
+    class(buffer.char_at(y, x), big)
+}
+
+/// Whether `(y, x)` is on an empty line, which counts as a word of its own.
+fn empty_line(buffer: &Texto, (y, x): (usize, usize)) -> bool {
+    x == 0 && buffer.line_len(y) == 0
+}
+
+/// The next position, going through the line break at the end of each line.
+pub fn next(buffer: &Texto, (y, x): (usize, usize)) -> Option<(usize, usize)> {
+    if x < buffer.line_len(y) {
+        Some((y, x + 1))
+    } else if y + 1 < buffer.len_lines() {
+        Some((y + 1, 0))
+    } else {
+        None
+    }
+}
+
+/// The previous position, going through the line break of the line above.
+pub fn prev(buffer: &Texto, (y, x): (usize, usize)) -> Option<(usize, usize)> {
+    let x = x.min(buffer.line_len(y));
+    if x > 0 {
+        Some((y, x - 1))
+    } else if y > 0 {
+        Some((y - 1, buffer.line_len(y - 1)))
+    } else {
+        None
+    }
+}
+
+/// The start of the next word, or of an empty line. At the end of the
+/// buffer it stops after the last character, and with `stop` at the first
+/// line break.
+fn word_forward(
+    buffer: &Texto,
+    mut pos: (usize, usize),
+    big: bool,
+    stop: bool,
+) -> Option<(usize, usize)> {
+    let start = class_at(buffer, pos, big);
+    let first = pos;
+    let step = |pos: (usize, usize)| {
+        let at_break = pos.1 >= buffer.line_len(pos.0);
+        match next(buffer, pos) {
+            Some(p) if stop && (at_break || p.1 == buffer.line_len(p.0)) => Err(p),
+            Some(p) => Ok(p),
+            None => Err(pos),
+        }
+    };
+    if start != 0 {
+        while class_at(buffer, pos, big) == start {
+            match step(pos) {
+                Ok(p) => pos = p,
+                Err(p) => return (p != first).then_some(p),
+            }
+        }
+    } else {
+        match step(pos) {
+            Ok(p) => pos = p,
+            Err(p) => return (p != first).then_some(p),
+        }
+    }
+    while class_at(buffer, pos, big) == 0 && !empty_line(buffer, pos) {
+        match step(pos) {
+            Ok(p) => pos = p,
+            Err(p) => return (p != first).then_some(p),
+        }
+    }
+    Some(pos)
+}
+
+/// The start of the word before the cursor, or of the one it is in.
+fn word_backward(buffer: &Texto, pos: (usize, usize), big: bool) -> Option<(usize, usize)> {
+    let mut pos = prev(buffer, pos)?;
+    while class_at(buffer, pos, big) == 0 {
+        if empty_line(buffer, pos) {
+            return Some(pos);
+        }
+        match prev(buffer, pos) {
+            Some(p) => pos = p,
+            None => return Some(pos),
+        }
+    }
+    let kind = class_at(buffer, pos, big);
+    while let Some(p) = prev(buffer, pos) {
+        if class_at(buffer, p, big) != kind {
+            break;
+        }
+        pos = p;
+    }
+    Some(pos)
+}
+
+/// Where `cw` on a word ends: like `e`, except that on the last character of
+/// a word the first count is that word, not the next one.
+pub fn change_word_end(
+    buffer: &Texto,
+    pos: (usize, usize),
+    count: usize,
+    big: bool,
+) -> (usize, usize) {
+    let kind = class_at(buffer, pos, big);
+    let mut end = if class_at(buffer, (pos.0, pos.1 + 1), big) == kind {
+        word_end(buffer, pos, big).unwrap_or(pos)
+    } else {
+        pos
+    };
+    for _ in 1..count {
+        end = word_end(buffer, end, big).unwrap_or(end);
+    }
+    end
+}
+
+/// The end of the word before the cursor.
+fn word_end_backward(buffer: &Texto, pos: (usize, usize), big: bool) -> Option<(usize, usize)> {
+    let kind = class_at(buffer, pos, big);
+    let mut pos = prev(buffer, pos)?;
+    if kind != 0 {
+        while class_at(buffer, pos, big) == kind {
+            match prev(buffer, pos) {
+                Some(p) => pos = p,
+                None => return Some(pos),
+            }
+        }
+    }
+    while class_at(buffer, pos, big) == 0 && !empty_line(buffer, pos) {
+        match prev(buffer, pos) {
+            Some(p) => pos = p,
+            None => return Some(pos),
+        }
+    }
+    Some(pos)
+}
+
+/// The bracket matching the first one at or after the cursor on its line.
+/// Brackets inside strings or comments aren't told apart.
+fn match_pair(buffer: &Texto, (y, x): (usize, usize)) -> Option<(usize, usize)> {
+    const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+    let line = buffer.line(y);
+    let start = (x..line.len()).find(|&i| "()[]{}".contains(line[i]))?;
+    let c = line[start];
+    let (open, close, forward) = PAIRS.iter().find_map(|&(open, close)| {
+        if c == open {
+            Some((open, close, true))
+        } else if c == close {
+            Some((open, close, false))
+        } else {
+            None
+        }
+    })?;
+    let mut depth = 0;
+    let mut pos = (y, start);
+    loop {
+        match buffer.char_at(pos.0, pos.1) {
+            Some(c) if c == open => depth += if forward { 1 } else { -1 },
+            Some(c) if c == close => depth += if forward { -1 } else { 1 },
+            _ => (),
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+        pos = if forward {
+            next(buffer, pos)?
+        } else {
+            prev(buffer, pos)?
+        };
+    }
+}
+
+/// The last character of the word the cursor is in, or of the next one.
+fn word_end(buffer: &Texto, pos: (usize, usize), big: bool) -> Option<(usize, usize)> {
+    let mut pos = next(buffer, pos)?;
+    while class_at(buffer, pos, big) == 0 {
+        pos = next(buffer, pos)?;
+    }
+    let kind = class_at(buffer, pos, big);
+    while let Some(p) = next(buffer, pos) {
+        if class_at(buffer, p, big) != kind {
+            break;
+        }
+        pos = p;
+    }
+    Some(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(cursor: (usize, usize), count: Option<usize>, operator: bool) -> Context {
+        Context {
+            cursor,
+            want: cursor.1,
+            count,
+            operator,
+            top: 0,
+            rows: 20,
+        }
+    }
+
+    fn go(text: &str, motion: Motion, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        motion.apply(&Texto::from_text(text), ctx(cursor, None, false))
+    }
+
+    const W: Motion = Motion::WordForward { big: false };
+    const BIG_W: Motion = Motion::WordForward { big: true };
+    const B: Motion = Motion::WordBackward { big: false };
+    const E: Motion = Motion::WordEnd { big: false };
+    const GE: Motion = Motion::WordEndBackward { big: false };
+
+    #[test]
+    fn word_forward() {
+        let text = "foo.bar baz\n\n  qux";
+        assert_eq!(go(text, W, (0, 0)), Some((0, 3)));
+        assert_eq!(go(text, W, (0, 3)), Some((0, 4)));
+        assert_eq!(go(text, BIG_W, (0, 0)), Some((0, 8)));
+        // An empty line is a word, the indent after it isn't.
+        assert_eq!(go(text, W, (0, 8)), Some((1, 0)));
+        assert_eq!(go(text, W, (1, 0)), Some((2, 2)));
+        assert_eq!(go(text, W, (2, 2)), Some((2, 5)));
+        assert_eq!(go(text, W, (2, 5)), None);
+    }
+
+    #[test]
+    fn word_backward() {
+        let text = "foo.bar baz\n\n  qux";
+        assert_eq!(go(text, B, (2, 2)), Some((1, 0)));
+        assert_eq!(go(text, B, (1, 0)), Some((0, 8)));
+        assert_eq!(go(text, B, (0, 9)), Some((0, 8)));
+        assert_eq!(go(text, B, (0, 8)), Some((0, 4)));
+        assert_eq!(
+            go(text, Motion::WordBackward { big: true }, (0, 8)),
+            Some((0, 0))
+        );
+        assert_eq!(go(text, B, (0, 0)), None);
+    }
+
+    #[test]
+    fn word_end() {
+        let text = "foo.bar baz\n\n  qux";
+        assert_eq!(go(text, E, (0, 0)), Some((0, 2)));
+        assert_eq!(go(text, E, (0, 2)), Some((0, 3)));
+        assert_eq!(
+            go(text, Motion::WordEnd { big: true }, (0, 0)),
+            Some((0, 6))
+        );
+        // `e` skips empty lines.
+        assert_eq!(go(text, E, (0, 10)), Some((2, 4)));
+        assert_eq!(go(text, E, (2, 4)), None);
+        assert_eq!(go(text, GE, (2, 3)), Some((1, 0)));
+        assert_eq!(go(text, GE, (0, 9)), Some((0, 6)));
+        assert_eq!(go(text, GE, (0, 4)), Some((0, 3)));
+    }
+
+    #[test]
+    fn counts_repeat_the_motion() {
+        let buffer = Texto::from_text("a b c d");
+        let apply =
+            |motion: Motion, cursor, count| motion.apply(&buffer, ctx(cursor, count, false));
+        assert_eq!(apply(W, (0, 0), Some(2)), Some((0, 4)));
+        assert_eq!(apply(B, (0, 6), Some(3)), Some((0, 0)));
+        assert_eq!(apply(E, (0, 0), Some(2)), Some((0, 4)));
+        assert_eq!(apply(W, (0, 0), Some(9)), None);
+    }
+
+    #[test]
+    fn find_and_match_pair() {
+        let text = "f(a, (b)) x";
+        let find = |ch, forward, till| Motion::Find {
+            ch,
+            forward,
+            till,
+            repeat: false,
+        };
+        assert_eq!(go(text, find('b', true, false), (0, 0)), Some((0, 6)));
+        assert_eq!(go(text, find('b', true, true), (0, 0)), Some((0, 5)));
+        assert_eq!(go(text, find('a', false, true), (0, 6)), Some((0, 3)));
+        assert_eq!(go(text, find('z', true, false), (0, 0)), None);
+        assert_eq!(go(text, Motion::MatchPair, (0, 0)), Some((0, 8)));
+        assert_eq!(go(text, Motion::MatchPair, (0, 7)), Some((0, 5)));
+        assert_eq!(go(text, Motion::MatchPair, (0, 9)), None);
+    }
+
+    #[test]
+    fn huge_counts_stop_at_the_end() {
+        let buffer = Texto::from_text("a\nb\nc");
+        let apply = |motion: Motion| motion.apply(&buffer, ctx((1, 0), Some(usize::MAX), false));
+        assert_eq!(apply(Motion::LineEnd), Some((2, 1)));
+        assert_eq!(apply(Motion::ScreenTop), Some((2, 0)));
+        assert_eq!(apply(Motion::ScreenBottom), Some((0, 0)));
+        assert_eq!(apply(Motion::Down), Some((2, 0)));
+        assert_eq!(apply(Motion::Right), Some((1, 1)));
+        assert_eq!(apply(Motion::LastLine), Some((2, 0)));
+    }
+
+    #[test]
+    fn paragraphs_and_lines() {
+        let text = "a\nb\n\nc\n\n\nd";
+        assert_eq!(go(text, Motion::ParagraphForward, (0, 0)), Some((2, 0)));
+        assert_eq!(go(text, Motion::ParagraphForward, (2, 0)), Some((4, 0)));
+        assert_eq!(go(text, Motion::ParagraphBackward, (6, 0)), Some((5, 0)));
+        assert_eq!(go(text, Motion::LastLine, (0, 0)), Some((6, 0)));
+        assert_eq!(go("  x\ny", Motion::FirstNonBlank, (0, 3)), Some((0, 2)));
+        assert_eq!(go("  x\ny", Motion::LineEnd, (0, 0)), Some((0, 3)));
+    }
+
+    #[test]
+    fn dw_stops_at_the_line_break() {
+        let buffer = Texto::from_text("foo bar\n  baz");
+        let w = |cursor, count, operator| W.apply(&buffer, ctx(cursor, count, operator));
+        // Alone, `w` goes on to the next line.
+        assert_eq!(w((0, 4), None, false), Some((1, 2)));
+        // After an operator the last word ends at the line break...
+        assert_eq!(w((0, 4), None, true), Some((0, 7)));
+        // ...but only the last one of a count.
+        assert_eq!(w((0, 0), Some(2), true), Some((0, 7)));
+        assert_eq!(w((0, 0), Some(3), true), Some((1, 5)));
+        // On the last word of the buffer it stops after it.
+        assert_eq!(w((1, 2), None, true), Some((1, 5)));
+        assert_eq!(w((1, 5), None, true), None);
+    }
+
+    #[test]
+    fn cw_changes_to_the_end_of_the_word() {
+        let buffer = Texto::from_text("foo.bar  baz\nqux");
+        let cw = |cursor, count| change_word_end(&buffer, cursor, count, false);
+        assert_eq!(cw((0, 0), 1), (0, 2));
+        assert_eq!(cw((0, 1), 1), (0, 2));
+        // On the last character the word is that character.
+        assert_eq!(cw((0, 2), 1), (0, 2));
+        assert_eq!(cw((0, 3), 1), (0, 3));
+        assert_eq!(cw((0, 0), 3), (0, 6));
+        assert_eq!(cw((0, 9), 2), (1, 2));
+        assert_eq!(change_word_end(&buffer, (0, 0), 1, true), (0, 6));
+    }
+}
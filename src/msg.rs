@@ -31,12 +31,12 @@ impl Msg {
         match self.typ {
             MsgType::Info => {
                 wattron(self.win, COLOR_PAIR(1));
-                mvwprintw(self.win, y, x, &self.txt);
+                mvwaddstr(self.win, y, x, &self.txt);
                 wattroff(self.win, COLOR_PAIR(1));
             }
             MsgType::Error => {
                 wattron(self.win, COLOR_PAIR(5));
-                mvwprintw(self.win, y, x, &self.txt);
+                mvwaddstr(self.win, y, x, &self.txt);
                 wattroff(self.win, COLOR_PAIR(5));
             }
         };
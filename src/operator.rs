@@ -0,0 +1,209 @@
+use crate::motion::first_non_blank;
+use crate::texto::Texto;
+
+/// What `d`, `y` and the other operators work on: the characters from
+/// `start` up to, not including, `end`, or the whole lines from `start.0`
+/// to `end.0` when `linewise`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub linewise: bool,
+}
+
+impl Region {
+    /// The region between the cursor and where a motion took it, in either
+    /// order. `inclusive` takes in the character the motion ended on.
+    ///
+    /// Like vim, an exclusive motion that ends at the start of a line stops
+    /// at the end of the line above instead, and if it also started at or
+    /// before the first non-blank it takes whole lines.
+    pub fn between(
+        buffer: &Texto,
+        from: (usize, usize),
+        to: (usize, usize),
+        inclusive: bool,
+        linewise: bool,
+    ) -> Region {
+        let (start, mut end) = if to < from { (to, from) } else { (from, to) };
+        if linewise {
+            return Region {
+                start,
+                end,
+                linewise,
+            };
+        }
+        if inclusive {
+            end.1 = (end.1 + 1).min(buffer.line_len(end.0).max(end.1));
+        } else if end.1 == 0 && end.0 > start.0 {
+            end = (end.0 - 1, buffer.line_len(end.0 - 1));
+            if start.1 <= first_non_blank(buffer, start.0) {
+                return Region {
+                    start,
+                    end,
+                    linewise: true,
+                };
+            }
+        }
+        Region {
+            start,
+            end,
+            linewise,
+        }
+    }
+
+    /// The lines the region touches.
+    pub fn lines(&self) -> (usize, usize) {
+        (self.start.0, self.end.0)
+    }
+
+    /// The text in the region, one entry per line.
+    pub fn text(&self, buffer: &Texto) -> Vec<String> {
+        let (first, last) = self.lines();
+        (first..=last)
+            .map(|y| {
+                let line = buffer.line(y);
+                let (from, to) = self.columns(y, line.len());
+                line[from..to].iter().collect()
+            })
+            .collect()
+    }
+
+    /// The columns of line `y`, which is `len` long, inside the region.
+    pub fn columns(&self, y: usize, len: usize) -> (usize, usize) {
+        if self.linewise {
+            return (0, len);
+        }
+        let from = if y == self.start.0 { self.start.1 } else { 0 };
+        let to = if y == self.end.0 { self.end.1 } else { len };
+        (from.min(len), to.min(len).max(from.min(len)))
+    }
+}
+
+/// Commands that take a motion or text object after them, `d2w`, `yip`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// `d`
+    Delete,
+    /// `c`
+    Change,
+    /// `y`
+    Yank,
+    /// `>`
+    ShiftRight,
+    /// `<`
+    ShiftLeft,
+    /// `gu`
+    Lowercase,
+    /// `gU`
+    Uppercase,
+    /// `g~`
+    ToggleCase,
+    /// `=`
+    Indent,
+}
+
+impl Operator {
+    pub fn from_key(ch: i32) -> Option<Operator> {
+        Some(match ch {
+            100 => Operator::Delete,
+            99 => Operator::Change,
+            121 => Operator::Yank,
+            62 => Operator::ShiftRight,
+            60 => Operator::ShiftLeft,
+            61 => Operator::Indent,
+            _ => return None,
+        })
+    }
+
+    /// The operator for a key typed after `g`.
+    pub fn from_g_key(ch: i32) -> Option<Operator> {
+        match ch {
+            117 => Some(Operator::Lowercase),
+            85 => Some(Operator::Uppercase),
+            126 => Some(Operator::ToggleCase),
+            _ => None,
+        }
+    }
+
+    /// The key that, typed again, makes the operator work on lines: `dd`,
+    /// `>>`, `gUU`.
+    pub fn line_key(&self) -> i32 {
+        match self {
+            Operator::Delete => 100,
+            Operator::Change => 99,
+            Operator::Yank => 121,
+            Operator::ShiftRight => 62,
+            Operator::ShiftLeft => 60,
+            Operator::Lowercase => 117,
+            Operator::Uppercase => 85,
+            Operator::ToggleCase => 126,
+            Operator::Indent => 61,
+        }
+    }
+
+    /// Whether it is typed after `g`.
+    pub fn g_prefixed(&self) -> bool {
+        matches!(
+            self,
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: (usize, usize), end: (usize, usize), linewise: bool) -> Region {
+        Region {
+            start,
+            end,
+            linewise,
+        }
+    }
+
+    #[test]
+    fn between_orders_and_takes_the_last_char() {
+        let buffer = Texto::from_text("foo bar\nbaz");
+        let between = |from, to, inclusive| Region::between(&buffer, from, to, inclusive, false);
+        assert_eq!(
+            between((0, 4), (0, 1), false),
+            region((0, 1), (0, 4), false)
+        );
+        assert_eq!(between((0, 0), (0, 2), true), region((0, 0), (0, 3), false));
+        assert_eq!(between((0, 4), (0, 6), true), region((0, 4), (0, 7), false));
+        assert_eq!(
+            Region::between(&buffer, (1, 2), (0, 5), false, true),
+            region((0, 5), (1, 2), true)
+        );
+    }
+
+    #[test]
+    fn between_stops_before_the_next_line() {
+        let buffer = Texto::from_text("  foo bar\nbaz");
+        let between = |from, to| Region::between(&buffer, from, to, false, false);
+        // `dw` on the last word of a line doesn't join the lines.
+        assert_eq!(between((0, 6), (1, 0)), region((0, 6), (0, 9), false));
+        // From the first non-blank it takes the whole line.
+        assert_eq!(between((0, 2), (1, 0)), region((0, 2), (0, 9), true));
+        assert_eq!(between((0, 0), (1, 0)), region((0, 0), (0, 9), true));
+    }
+
+    #[test]
+    fn region_text_and_columns() {
+        let buffer = Texto::from_text("one\ntwo\nthree");
+        let chars = region((0, 1), (2, 2), false);
+        assert_eq!(chars.text(&buffer), ["ne", "two", "th"]);
+        assert_eq!(chars.columns(1, 3), (0, 3));
+        let lines = region((0, 1), (1, 0), true);
+        assert_eq!(lines.text(&buffer), ["one", "two"]);
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(Operator::from_key(100), Some(Operator::Delete));
+        assert_eq!(Operator::from_key(119), None);
+        assert_eq!(Operator::from_g_key(85), Some(Operator::Uppercase));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::clipboard::Provider;
+use std::fs;
+use std::path::PathBuf;
+
+/// Editor-wide settings, changed with `:set` or from `~/.config/rim/rimrc`.
+/// Settings that belong to a single file (fileformat, fileencoding) live on
+/// `Archivo` instead.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Keep a copy of the previous version of a file when saving over it.
+    pub backup: bool,
+    /// Where backups go. When unset they are written next to the file.
+    pub backupdir: Option<String>,
+    /// Create missing parent directories when saving a new file.
+    pub mkdir: bool,
+    /// Reload files changed outside rim when the buffer has no changes.
+    pub autoread: bool,
+    /// Keep a swap file with the unsaved changes of the buffer.
+    pub swapfile: bool,
+    /// Keys typed before the swap file is written again. 0 turns it off.
+    pub updatecount: usize,
+    /// Milliseconds without typing before the swap file is written.
+    pub updatetime: i32,
+    /// Spaces added or removed by `>` and `<`.
+    pub shiftwidth: usize,
+    /// Searches ignore case...
+    pub ignorecase: bool,
+    /// ...unless the pattern has an uppercase letter.
+    pub smartcase: bool,
+    /// Highlight every match of the last search.
+    pub hlsearch: bool,
+    /// Show where the pattern matches while it is typed.
+    pub incsearch: bool,
+    /// Searches continue from the other end of the file.
+    pub wrapscan: bool,
+    /// What the `"+` and `"*` registers use to reach the system clipboard.
+    pub clipboard: Provider,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            backup: false,
+            backupdir: None,
+            mkdir: false,
+            autoread: false,
+            swapfile: true,
+            updatecount: 200,
+            updatetime: 4000,
+            shiftwidth: 4,
+            ignorecase: true,
+            smartcase: true,
+            hlsearch: true,
+            incsearch: true,
+            wrapscan: true,
+            clipboard: Provider::Auto,
+        }
+    }
+}
+
+impl Options {
+    /// Reads `set ...` lines from the rimrc file. Lines starting with `"` are
+    /// comments, like in a vimrc.
+    pub fn load() -> Options {
+        let mut options = Options::default();
+        if let Some(path) = config_path() {
+            if let Ok(content) = fs::read_to_string(path) {
+                for line in content.lines().map(str::trim) {
+                    if let Some(arg) = line.strip_prefix("set ") {
+                        let _ = options.set(arg.trim());
+                    }
+                }
+            }
+        }
+        options
+    }
+
+    /// Applies one `:set` argument: `name`, `noname`, `name?` or `name=value`.
+    /// Returns a message to show, if any.
+    pub fn set(&mut self, arg: &str) -> Result<Option<String>, String> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        if value.is_none() {
+            if let Some(info) = self.set_flag(name) {
+                return Ok(info);
+            }
+        }
+        match (name, value) {
+            ("updatecount" | "uc", Some(value)) => self.updatecount = number(name, value)?,
+            ("updatecount" | "uc" | "updatecount?" | "uc?", None) => {
+                return Ok(Some(format!("updatecount={}", self.updatecount)))
+            }
+            ("updatetime" | "ut", Some(value)) => self.updatetime = number(name, value)?,
+            ("updatetime" | "ut" | "updatetime?" | "ut?", None) => {
+                return Ok(Some(format!("updatetime={}", self.updatetime)))
+            }
+            ("shiftwidth" | "sw", Some(value)) => self.shiftwidth = number(name, value)?,
+            ("shiftwidth" | "sw" | "shiftwidth?" | "sw?", None) => {
+                return Ok(Some(format!("shiftwidth={}", self.shiftwidth)))
+            }
+            ("backupdir" | "bdir", Some(value)) => {
+                self.backupdir = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_string())
+                }
+            }
+            ("backupdir" | "bdir" | "backupdir?" | "bdir?", None) => {
+                return Ok(Some(format!(
+                    "backupdir={}",
+                    self.backupdir.as_deref().unwrap_or("")
+                )))
+            }
+            ("clipboard" | "cb", Some(value)) => {
+                self.clipboard = Provider::from_name(value)
+                    .ok_or_else(|| format!("Invalid argument: {}={}", name, value))?
+            }
+            ("clipboard" | "cb" | "clipboard?" | "cb?", None) => {
+                return Ok(Some(format!("clipboard={}", self.clipboard.name())))
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+        Ok(None)
+    }
+
+    /// `name`, `noname` and `name?` for the on/off options. Returns `None`
+    /// when `name` isn't one of them, otherwise the message to show.
+    fn set_flag(&mut self, name: &str) -> Option<Option<String>> {
+        let (query, name) = match name.strip_suffix('?') {
+            Some(name) => (true, name),
+            None => (false, name),
+        };
+        let (on, short) = match name.strip_prefix("no") {
+            Some(rest) if !query => (false, rest),
+            _ => (true, name),
+        };
+        let (name, flag) = match short {
+            "backup" | "bk" => ("backup", &mut self.backup),
+            "mkdir" => ("mkdir", &mut self.mkdir),
+            "autoread" | "ar" => ("autoread", &mut self.autoread),
+            "swapfile" | "swf" => ("swapfile", &mut self.swapfile),
+            "ignorecase" | "ic" => ("ignorecase", &mut self.ignorecase),
+            "smartcase" | "scs" => ("smartcase", &mut self.smartcase),
+            "hlsearch" | "hls" => ("hlsearch", &mut self.hlsearch),
+            "incsearch" | "is" => ("incsearch", &mut self.incsearch),
+            "wrapscan" | "ws" => ("wrapscan", &mut self.wrapscan),
+            _ => return None,
+        };
+        if query {
+            let prefix = if *flag { "" } else { "no" };
+            return Some(Some(format!("{}{}", prefix, name)));
+        }
+        *flag = on;
+        Some(None)
+    }
+
+    pub fn backupdir(&self) -> Option<PathBuf> {
+        let dir = self.backupdir.as_ref()?;
+        match (dir.strip_prefix("~/"), std::env::var("HOME")) {
+            (Some(rest), Ok(home)) => Some(PathBuf::from(home).join(rest)),
+            _ => Some(PathBuf::from(dir)),
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Number required after =: {}={}", name, value))
+}
+
+fn config_path() -> Option<PathBuf> {
+    let base = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+    };
+    Some(base.join("rim").join("rimrc"))
+}
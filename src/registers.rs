@@ -0,0 +1,281 @@
+/// Text kept by a yank or a delete.
+#[derive(Debug, Clone, Default)]
+pub struct Register {
+    pub text: Vec<String>,
+    /// Whole lines, as opposed to a piece of a line.
+    pub linewise: bool,
+    /// A block, one piece of a line for each line it was on.
+    pub blockwise: bool,
+}
+
+impl Register {
+    /// The keys recorded by `q`, as text that can be put and edited. Enter
+    /// starts a new line.
+    pub fn from_keys(keys: &[i32]) -> Register {
+        let text: String = keys
+            .iter()
+            .filter_map(|&ch| char::from_u32(ch as u32))
+            .collect();
+        Register {
+            text: text.split('\n').map(str::to_string).collect(),
+            linewise: false,
+            blockwise: false,
+        }
+    }
+
+    /// The text as keys for `@`. Whole lines end with Enter.
+    pub fn keys(&self) -> Vec<i32> {
+        let mut text = self.text.join("\n");
+        if self.linewise {
+            text.push('\n');
+        }
+        text.chars().map(|c| c as i32).collect()
+    }
+
+    /// Adds `other` at the end, as `"A` does. Whole lines and blocks go on
+    /// lines of their own; pieces of lines join the last line. Only a block
+    /// added to a block stays one.
+    fn append(&mut self, other: Register) {
+        if self.text.is_empty() {
+            *self = other;
+            return;
+        }
+        if self.linewise || other.linewise || self.blockwise || other.blockwise {
+            self.text.extend(other.text);
+        } else {
+            let mut lines = other.text.into_iter();
+            if let (Some(last), Some(first)) = (self.text.last_mut(), lines.next()) {
+                last.push_str(&first);
+            }
+            self.text.extend(lines);
+        }
+        self.linewise |= other.linewise;
+        self.blockwise &= other.blockwise;
+    }
+}
+
+/// The registers yanks and deletes go to, named like vim's: `"` is the
+/// last one used, `0` the last yank, `1` to `9` the last deletes of whole
+/// lines or more than one line, `-` the last smaller delete, `a` to `z`
+/// are only written when asked for and `_` throws the text away. `+` and
+/// `*` keep what was sent to the system clipboard and primary selection.
+#[derive(Debug, Default)]
+pub struct Registers {
+    pub unnamed: Register,
+    /// `0` to `9`
+    numbered: [Register; 10],
+    /// `-`
+    small_delete: Register,
+    /// `a` to `z`
+    named: [Register; 26],
+    /// `+`
+    clipboard: Register,
+    /// `*`
+    primary: Register,
+}
+
+impl Registers {
+    /// Whether `name` can be typed after `"`.
+    pub fn valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || "\"-_+*".contains(name)
+    }
+
+    /// Keeps text that was yanked, or deleted if `delete`, in register
+    /// `name`. An uppercase name appends to the register. The unnamed
+    /// register gets a copy unless the text went to `_`.
+    pub fn store(&mut self, name: Option<char>, register: Register, delete: bool) {
+        match name {
+            Some('_') => return,
+            Some(c @ 'a'..='z') => self.named[c as usize - 'a' as usize] = register.clone(),
+            Some(c @ 'A'..='Z') => {
+                let named = &mut self.named[c.to_ascii_lowercase() as usize - 'a' as usize];
+                named.append(register);
+                self.unnamed = named.clone();
+                return;
+            }
+            Some(c @ '0'..='9') => self.numbered[c as usize - '0' as usize] = register.clone(),
+            Some('-') => self.small_delete = register.clone(),
+            Some('+') => self.clipboard = register.clone(),
+            Some('*') => self.primary = register.clone(),
+            _ if !delete => self.numbered[0] = register.clone(),
+            _ if register.linewise || register.text.len() > 1 => {
+                self.numbered[1..].rotate_right(1);
+                self.numbered[1] = register.clone();
+            }
+            _ => self.small_delete = register.clone(),
+        }
+        self.unnamed = register;
+    }
+
+    /// Keeps a macro recorded with `q{name}`. Unlike a yank it leaves the
+    /// unnamed register alone, unless that is where it goes.
+    pub fn record(&mut self, name: char, register: Register) {
+        if name == '"' {
+            self.unnamed = register;
+            return;
+        }
+        let unnamed = std::mem::take(&mut self.unnamed);
+        self.store(Some(name), register, false);
+        self.unnamed = unnamed;
+    }
+
+    /// Replaces `"+`, or `"*` with `primary`, with what the system
+    /// clipboard holds. The unnamed register is left alone.
+    pub fn set_clipboard(&mut self, primary: bool, register: Register) {
+        if primary {
+            self.primary = register;
+        } else {
+            self.clipboard = register;
+        }
+    }
+
+    /// The register called `name`, or the unnamed one. Empty registers are
+    /// `None`.
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        let register = match name {
+            None | Some('"') => &self.unnamed,
+            Some(c @ '0'..='9') => &self.numbered[c as usize - '0' as usize],
+            Some(c) if c.is_ascii_alphabetic() => {
+                &self.named[c.to_ascii_lowercase() as usize - 'a' as usize]
+            }
+            Some('-') => &self.small_delete,
+            Some('+') => &self.clipboard,
+            Some('*') => &self.primary,
+            _ => return None,
+        };
+        (!register.text.is_empty()).then_some(register)
+    }
+
+    /// The registers that hold something, in the order `:registers` shows
+    /// them.
+    pub fn list(&self) -> Vec<(char, &Register)> {
+        "\"0123456789abcdefghijklmnopqrstuvwxyz-*+"
+            .chars()
+            .filter_map(|c| Some((c, self.get(Some(c))?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Register {
+        Register {
+            text: text.split('\n').map(str::to_string).collect(),
+            linewise: false,
+            blockwise: false,
+        }
+    }
+
+    fn lines(text: &[&str]) -> Register {
+        Register {
+            text: text.iter().map(|line| line.to_string()).collect(),
+            linewise: true,
+            blockwise: false,
+        }
+    }
+
+    fn text(registers: &Registers, name: char) -> Option<(Vec<String>, bool)> {
+        let register = registers.get(Some(name))?;
+        Some((register.text.clone(), register.linewise))
+    }
+
+    #[test]
+    fn uppercase_appends() {
+        let mut registers = Registers::default();
+        registers.store(Some('a'), chars("one"), false);
+        registers.store(Some('A'), chars("two\nthree"), false);
+        let joined = Some((vec!["onetwo".to_string(), "three".to_string()], false));
+        assert_eq!(text(&registers, 'a'), joined);
+        assert_eq!(text(&registers, '"'), joined);
+        // Whole lines go below, and make the register linewise.
+        registers.store(Some('A'), lines(&["four"]), false);
+        assert_eq!(
+            text(&registers, 'a'),
+            Some((
+                vec![
+                    "onetwo".to_string(),
+                    "three".to_string(),
+                    "four".to_string()
+                ],
+                true
+            ))
+        );
+        // Appending to an empty register is storing.
+        registers.store(Some('B'), chars("five"), false);
+        assert_eq!(
+            text(&registers, 'b'),
+            Some((vec!["five".to_string()], false))
+        );
+    }
+
+    #[test]
+    fn blocks_append_as_lines() {
+        let block = |text: &str| Register {
+            blockwise: true,
+            ..chars(text)
+        };
+        let mut registers = Registers::default();
+        registers.store(Some('a'), block("ab\ncd"), false);
+        registers.store(Some('A'), block("ef"), false);
+        let a = registers.get(Some('a')).unwrap();
+        assert_eq!(
+            (a.text.join(" "), a.blockwise),
+            ("ab cd ef".to_string(), true)
+        );
+        registers.store(Some('A'), chars("gh"), false);
+        let a = registers.get(Some('a')).unwrap();
+        assert_eq!(
+            (a.text.join(" "), a.blockwise),
+            ("ab cd ef gh".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn deletes_shift_numbered_registers() {
+        let mut registers = Registers::default();
+        for n in 1..=10 {
+            registers.store(None, lines(&[&n.to_string()]), true);
+        }
+        for (name, n) in ('1'..='9').zip((2..=10).rev()) {
+            assert_eq!(
+                text(&registers, name),
+                Some((vec![n.to_string()], true)),
+                "register {}",
+                name
+            );
+        }
+        // Text over more than one line counts as a big delete.
+        registers.store(None, chars("a\nb"), true);
+        assert_eq!(text(&registers, '1').unwrap().0, ["a", "b"]);
+        assert_eq!(text(&registers, '2').unwrap().0, ["10"]);
+        // A yank goes to 0 and leaves them alone.
+        registers.store(None, chars("yank"), false);
+        assert_eq!(text(&registers, '0').unwrap().0, ["yank"]);
+        assert_eq!(text(&registers, '1').unwrap().0, ["a", "b"]);
+    }
+
+    #[test]
+    fn small_deletes_go_to_minus() {
+        let mut registers = Registers::default();
+        registers.store(None, lines(&["line"]), true);
+        registers.store(None, chars("word"), true);
+        assert_eq!(text(&registers, '-').unwrap().0, ["word"]);
+        assert_eq!(text(&registers, '"').unwrap().0, ["word"]);
+        assert_eq!(text(&registers, '1').unwrap().0, ["line"]);
+        assert_eq!(text(&registers, '2'), None);
+    }
+
+    #[test]
+    fn black_hole_keeps_nothing() {
+        let mut registers = Registers::default();
+        registers.store(None, chars("kept"), false);
+        registers.store(Some('_'), lines(&["gone"]), true);
+        registers.store(Some('_'), chars("gone"), true);
+        assert_eq!(text(&registers, '"').unwrap().0, ["kept"]);
+        assert_eq!(text(&registers, '1'), None);
+        assert_eq!(text(&registers, '-'), None);
+        assert_eq!(text(&registers, '_'), None);
+    }
+}
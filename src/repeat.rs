@@ -0,0 +1,153 @@
+use crate::operator::Operator;
+
+/// The last change, kept as the keys that made it so `.` can type them
+/// again. Its counts are kept apart, multiplied into one, as `.` may be
+/// given a new count that replaces them all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repeat {
+    pub count: Option<usize>,
+    pub keys: Vec<i32>,
+}
+
+impl Repeat {
+    /// The change made by the NORMAL mode command typed as `keys`, or
+    /// `None` for commands `.` doesn't repeat: undo and redo, `:` commands,
+    /// VISUAL mode and `.` itself. The counts before the command, after
+    /// its register and after an operator are taken out of the keys.
+    pub fn from_keys(keys: &[i32]) -> Option<Repeat> {
+        let mut rest = keys;
+        let mut count = take_count(&mut rest);
+        let mut kept = Vec::new();
+        if rest.first() == Some(&34) {
+            kept.extend(rest.get(..2)?);
+            rest = &rest[2..];
+            count = multiply(count, take_count(&mut rest));
+        }
+        let operator = match rest {
+            // u Ctrl-R : . v V Ctrl-V
+            [117 | 18 | 58 | 46 | 118 | 86 | 22, ..] => return None,
+            // g- g+ gv
+            [103, 45 | 43 | 118, ..] => return None,
+            [] => return None,
+            [103, key, ..] if Operator::from_g_key(*key).is_some() => 2,
+            [key, ..] if Operator::from_key(*key).is_some() => 1,
+            _ => 0,
+        };
+        kept.extend(&rest[..operator]);
+        rest = &rest[operator..];
+        if operator > 0 {
+            count = multiply(count, take_count(&mut rest));
+        }
+        kept.extend(rest);
+        Some(Repeat { count, keys: kept })
+    }
+
+    /// The keys to run, with `count` in place of the one typed first.
+    pub fn keys(&self, count: Option<usize>) -> Vec<i32> {
+        let mut keys: Vec<i32> = match count.or(self.count) {
+            Some(count) => count.to_string().bytes().map(i32::from).collect(),
+            None => Vec::new(),
+        };
+        keys.extend(&self.keys);
+        keys
+    }
+}
+
+/// Takes the count at the start of `keys` off them. `0` only counts after
+/// another digit.
+fn take_count(keys: &mut &[i32]) -> Option<usize> {
+    let digits = keys
+        .iter()
+        .enumerate()
+        .take_while(|&(i, &ch)| (49..=57).contains(&ch) || (i > 0 && ch == 48))
+        .count();
+    let count = keys[..digits]
+        .iter()
+        .fold(None, |count: Option<usize>, &ch| {
+            Some(
+                count
+                    .unwrap_or(0)
+                    .saturating_mul(10)
+                    .saturating_add((ch - 48) as usize),
+            )
+        });
+    *keys = &keys[digits..];
+    count
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(text: &str) -> Vec<i32> {
+        text.chars().map(|c| c as i32).collect()
+    }
+
+    fn repeat(text: &str) -> Option<(Option<usize>, String)> {
+        Repeat::from_keys(&keys(text)).map(|r| {
+            let text = r.keys.iter().map(|&ch| ch as u8 as char).collect();
+            (r.count, text)
+        })
+    }
+
+    #[test]
+    fn counts_are_multiplied_and_taken_out() {
+        assert_eq!(repeat("dw"), Some((None, "dw".to_string())));
+        assert_eq!(repeat("3dw"), Some((Some(3), "dw".to_string())));
+        assert_eq!(repeat("d3w"), Some((Some(3), "dw".to_string())));
+        assert_eq!(repeat("2d3w"), Some((Some(6), "dw".to_string())));
+        assert_eq!(repeat("2\"a3dw"), Some((Some(6), "\"adw".to_string())));
+        assert_eq!(repeat("2gU3w"), Some((Some(6), "gUw".to_string())));
+        assert_eq!(repeat("10x"), Some((Some(10), "x".to_string())));
+        assert_eq!(
+            repeat("99999999999999999999x"),
+            Some((Some(usize::MAX), "x".to_string()))
+        );
+    }
+
+    #[test]
+    fn only_counts_are_taken_out() {
+        // 0 after an operator is a motion, and digits after other
+        // commands are what they type.
+        assert_eq!(repeat("d0"), Some((None, "d0".to_string())));
+        assert_eq!(repeat("r5"), Some((None, "r5".to_string())));
+        assert_eq!(repeat("i12\x1b"), Some((None, "i12\x1b".to_string())));
+        assert_eq!(repeat("c2w34\x1b"), Some((Some(2), "cw34\x1b".to_string())));
+    }
+
+    #[test]
+    fn some_commands_are_not_repeated() {
+        for text in [
+            "u",
+            "\x12",
+            ":s/a/b/\n",
+            ".",
+            "vd",
+            "Vd",
+            "g-",
+            "g+",
+            "gvd",
+            "2u",
+            "\"a",
+        ] {
+            assert_eq!(repeat(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn a_new_count_replaces_the_old_one() {
+        let change = Repeat::from_keys(&keys("d3w")).unwrap();
+        assert_eq!(change.keys(None), keys("3dw"));
+        assert_eq!(change.keys(Some(2)), keys("2dw"));
+        let change = Repeat::from_keys(&keys("dw")).unwrap();
+        assert_eq!(change.keys(None), keys("dw"));
+        assert_eq!(change.keys(Some(12)), keys("12dw"));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::texto::Texto;
+use regex::{Regex, RegexBuilder};
+use std::collections::VecDeque;
+
+/// The last pattern searched for, used by `n`, `N` and empty patterns.
+#[derive(Debug, Clone)]
+pub struct LastSearch {
+    pub pattern: String,
+    pub forward: bool,
+}
+
+/// Compiles a search pattern. Patterns use the syntax of the regex crate,
+/// plus a few things from vim: `\<` and `\>` for the start and end of a
+/// word, and `\c` or `\C` anywhere to ignore case or not.
+///
+/// With `ignorecase` the case is ignored, unless `smartcase` is also set
+/// and the pattern has an uppercase letter.
+pub fn compile(pattern: &str, ignorecase: bool, smartcase: bool) -> Result<Regex, String> {
+    let mut translated = String::with_capacity(pattern.len());
+    let mut ignore = ignorecase && !(smartcase && has_upper(pattern));
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('<' | '>') => translated.push_str(r"\b"),
+                Some('c') => ignore = true,
+                Some('C') => ignore = false,
+                Some(c) => {
+                    translated.push('\\');
+                    translated.push(c);
+                }
+                None => translated.push_str(r"\\"),
+            }
+        } else {
+            translated.push(c);
+        }
+    }
+    RegexBuilder::new(&translated)
+        .case_insensitive(ignore)
+        .build()
+        .map_err(|_| format!("Invalid pattern: {}", pattern))
+}
+
+/// Whether the pattern has an uppercase letter that isn't part of an
+/// escape like `\S` or `\W`.
+fn has_upper(pattern: &str) -> bool {
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Char columns where `re` matches in `line`, with the length of each
+/// match in chars.
+pub fn matches(re: &Regex, line: &str) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    let mut col = 0;
+    let mut byte = 0;
+    for m in re.find_iter(line) {
+        col += line[byte..m.start()].chars().count();
+        byte = m.start();
+        out.push((col, m.as_str().chars().count()));
+    }
+    out
+}
+
+/// The first match after `(y, x)`, or before it when going backwards,
+/// wrapping around the ends of the buffer. Returns where it is and whether
+/// the search wrapped.
+pub fn find(
+    buffer: &Texto,
+    re: &Regex,
+    (y, x): (usize, usize),
+    forward: bool,
+) -> Option<((usize, usize), bool)> {
+    let len = buffer.len_lines();
+    let cols = |line: usize| -> Vec<usize> {
+        matches(re, &buffer.line_string(line))
+            .into_iter()
+            .map(|(col, _)| col)
+            .collect()
+    };
+    if forward {
+        if let Some(col) = cols(y).into_iter().find(|&col| col > x) {
+            return Some(((y, col), false));
+        }
+        for i in 1..=len {
+            let line = (y + i) % len;
+            let wrapped = y + i >= len;
+            let found = cols(line)
+                .into_iter()
+                .find(|&col| line != y || !wrapped || col <= x);
+            if let Some(col) = found {
+                return Some(((line, col), wrapped));
+            }
+        }
+    } else {
+        if let Some(col) = cols(y).into_iter().rev().find(|&col| col < x) {
+            return Some(((y, col), false));
+        }
+        for i in 1..=len {
+            let line = (y + len - i) % len;
+            let wrapped = i > y;
+            let found = cols(line)
+                .into_iter()
+                .rev()
+                .find(|&col| line != y || !wrapped || col >= x);
+            if let Some(col) = found {
+                return Some(((line, col), wrapped));
+            }
+        }
+    }
+    None
+}
+
+/// The first line with a match, starting after `from` and wrapping around
+/// the end of the buffer, or before it when going backwards. `from` itself
+/// is checked last.
+pub fn find_line(buffer: &Texto, re: &Regex, from: usize, forward: bool) -> Option<usize> {
+    let len = buffer.len_lines();
+    (1..=len)
+        .map(|i| {
+            if forward {
+                (from + i) % len
+            } else {
+                (from + len - i) % len
+            }
+        })
+        .find(|&y| re.is_match(&buffer.line_string(y)))
+}
+
+/// The lines from `start` to `end` where `re` matches, or doesn't when
+/// `invert`, for `:g` and `:v`.
+pub fn matching_lines(
+    buffer: &Texto,
+    re: &Regex,
+    (start, end): (usize, usize),
+    invert: bool,
+) -> VecDeque<usize> {
+    (start..=end)
+        .filter(|&y| re.is_match(&buffer.line_string(y)) != invert)
+        .collect()
+}
+
+/// Makes a pattern that finds `word` as a whole word, for `*` and `#`.
+pub fn word_pattern(word: &str) -> String {
+    format!(r"\<{}\>", regex::escape(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_follows_the_options() {
+        let is_match = |pattern, ignorecase, smartcase, text| {
+            compile(pattern, ignorecase, smartcase)
+                .unwrap()
+                .is_match(text)
+        };
+        assert!(!is_match("foo", false, false, "FOO"));
+        assert!(is_match("foo", true, false, "FOO"));
+        assert!(is_match("foo", true, true, "FOO"));
+        // An uppercase letter turns smartcase on, but escapes don't count.
+        assert!(!is_match("Foo", true, true, "FOO"));
+        assert!(is_match("Foo", true, false, "FOO"));
+        assert!(is_match(r"\Sfoo", true, true, "XFOO"));
+        // \c and \C win over the options.
+        assert!(is_match(r"foo\c", false, false, "FOO"));
+        assert!(!is_match(r"\Cfoo", true, false, "FOO"));
+        assert!(is_match(r"\<foo\>", false, false, "a foo b"));
+        assert!(!is_match(r"\<foo\>", false, false, "afoob"));
+        assert!(compile("(", false, false).is_err());
+    }
+
+    #[test]
+    fn matches_are_in_chars() {
+        let re = compile("b+", false, false).unwrap();
+        assert_eq!(matches(&re, "ábbcñb"), [(1, 2), (5, 1)]);
+        assert_eq!(matches(&re, "none"), []);
+    }
+
+    #[test]
+    fn find_wraps_around() {
+        let buffer = Texto::from_text("x a\nb\na x");
+        let re = compile("x", false, false).unwrap();
+        assert_eq!(find(&buffer, &re, (0, 0), true), Some(((2, 2), false)));
+        assert_eq!(find(&buffer, &re, (2, 2), true), Some(((0, 0), true)));
+        assert_eq!(find(&buffer, &re, (2, 2), false), Some(((0, 0), false)));
+        assert_eq!(find(&buffer, &re, (0, 0), false), Some(((2, 2), true)));
+        // The only match is found again after going all the way round.
+        let re = compile("b", false, false).unwrap();
+        assert_eq!(find(&buffer, &re, (1, 0), true), Some(((1, 0), true)));
+        assert_eq!(find(&buffer, &re, (1, 0), false), Some(((1, 0), true)));
+        let re = compile("z", false, false).unwrap();
+        assert_eq!(find(&buffer, &re, (1, 0), true), None);
+    }
+}
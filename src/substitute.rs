@@ -0,0 +1,182 @@
+use crate::command;
+use regex::Captures;
+
+/// A parsed `:s/pattern/replacement/flags`.
+#[derive(Debug, Clone, Default)]
+pub struct Substitute {
+    /// Empty means the last search pattern.
+    pub pattern: String,
+    pub replacement: String,
+    /// `g`, every match in the line instead of the first one.
+    pub global: bool,
+    /// `c`, ask before each substitution.
+    pub confirm: bool,
+    /// `i` or `I`, ignore case or not whatever 'ignorecase' says.
+    pub ignorecase: Option<bool>,
+    /// A count after the flags, `:s/a/b/g 3`.
+    pub count: String,
+}
+
+/// Parses the arguments of `:s`. The first character is the delimiter, so
+/// `:s#/usr#/opt#` works too.
+pub fn parse(args: &str) -> Result<Substitute, String> {
+    let Some(delim) = args.chars().next() else {
+        return Ok(Substitute::default());
+    };
+    let (pattern, rest) = command::split_pattern(args)?;
+    let mut chars = rest.chars().peekable();
+
+    // The replacement keeps its escapes, they are handled by `expand`.
+    let mut replacement = String::new();
+    let mut closed_replacement = false;
+    loop {
+        match chars.next() {
+            Some('\\') => {
+                replacement.push('\\');
+                if let Some(c) = chars.next() {
+                    replacement.push(c);
+                }
+            }
+            Some(c) if c == delim => {
+                closed_replacement = true;
+                break;
+            }
+            Some(c) => replacement.push(c),
+            None => break,
+        }
+    }
+
+    let mut sub = Substitute {
+        pattern,
+        replacement,
+        ..Substitute::default()
+    };
+    if !closed_replacement {
+        return Ok(sub);
+    }
+    while let Some(&c) = chars.peek() {
+        match c {
+            'g' => sub.global = true,
+            'c' => sub.confirm = true,
+            'i' => sub.ignorecase = Some(true),
+            'I' => sub.ignorecase = Some(false),
+            _ => break,
+        }
+        chars.next();
+    }
+    let rest: String = chars.collect();
+    let rest = rest.trim();
+    if !rest.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Trailing characters: {}", rest));
+    }
+    sub.count = rest.to_string();
+    Ok(sub)
+}
+
+/// Case changes asked for in the replacement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Keep,
+    Upper,
+    Lower,
+}
+
+/// Builds the text that replaces a match. Like vim, `&` and `\0` are the
+/// whole match and `\1`..`\9` the groups; `\u` and `\l` change the case of
+/// the next character, `\U` and `\L` of everything up to `\E` or `\e`; `\r`
+/// and `\n` break the line and `\&` is a literal `&`.
+pub fn expand(replacement: &str, caps: &Captures) -> String {
+    let mut out = String::new();
+    let mut next = Case::Keep;
+    let mut all = Case::Keep;
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push(&mut out, &caps[0], &mut next, all),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => {
+                    let group = d.to_digit(10).unwrap() as usize;
+                    let text = caps.get(group).map_or("", |m| m.as_str());
+                    push(&mut out, text, &mut next, all);
+                }
+                Some('u') => next = Case::Upper,
+                Some('l') => next = Case::Lower,
+                Some('U') => all = Case::Upper,
+                Some('L') => all = Case::Lower,
+                Some('E' | 'e') => all = Case::Keep,
+                Some('r' | 'n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => push(&mut out, &c.to_string(), &mut next, all),
+                None => out.push('\\'),
+            },
+            c => push(&mut out, &c.to_string(), &mut next, all),
+        }
+    }
+    out
+}
+
+/// Adds `text` to `out` with the case changes that are pending.
+fn push(out: &mut String, text: &str, next: &mut Case, all: Case) {
+    for c in text.chars() {
+        let case = if *next != Case::Keep { *next } else { all };
+        *next = Case::Keep;
+        match case {
+            Case::Keep => out.push(c),
+            Case::Upper => out.extend(c.to_uppercase()),
+            Case::Lower => out.extend(c.to_lowercase()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn replace(pattern: &str, text: &str, replacement: &str) -> String {
+        let re = Regex::new(pattern).unwrap();
+        expand(replacement, &re.captures(text).unwrap())
+    }
+
+    #[test]
+    fn expand_groups() {
+        assert_eq!(replace("(a)(b)", "ab", r"\2\1"), "ba");
+        assert_eq!(replace("(a)b", "ab", r"[&|\0|\&|\9]"), "[ab|ab|&|]");
+        assert_eq!(replace("a", "a", r"x\\y\"), r"x\y\");
+    }
+
+    #[test]
+    fn expand_case_escapes() {
+        assert_eq!(
+            replace(r"(\w+) (\w+)", "foo bar", r"\u\1 \U\2\E!"),
+            "Foo BAR!"
+        );
+        assert_eq!(replace(r"\w+", "FOO", r"\l&"), "fOO");
+        assert_eq!(replace(r"\w+", "FOO", r"\L&\e-&"), "foo-FOO");
+        // `\u` wins over `\L` for the next character.
+        assert_eq!(replace(r"\w+", "FOO", r"\L\u&"), "Foo");
+        assert_eq!(replace(r"\w+", "x", r"\Uab\Ec"), "ABc");
+    }
+
+    #[test]
+    fn expand_line_breaks() {
+        assert_eq!(replace(",", ",", r"\r"), "\n");
+        assert_eq!(replace(",", ",", r"a\nb\tc"), "a\nb\tc");
+    }
+
+    #[test]
+    fn parse_flags_and_count() {
+        let sub = parse("/a/b/gI 3").unwrap();
+        assert_eq!((sub.pattern.as_str(), sub.replacement.as_str()), ("a", "b"));
+        assert!(sub.global && !sub.confirm);
+        assert_eq!(sub.ignorecase, Some(false));
+        assert_eq!(sub.count, "3");
+
+        let sub = parse(r"#a\#b#c\#d  ").unwrap();
+        assert_eq!(
+            (sub.pattern.as_str(), sub.replacement.as_str()),
+            ("a#b", r"c\#d  ")
+        );
+        assert_eq!(parse("/a/b/x").unwrap_err(), "Trailing characters: x");
+    }
+}
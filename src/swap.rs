@@ -0,0 +1,305 @@
+use crate::encoding::Encoding;
+use crate::file::{self, Archivo, LineEnding};
+use crate::history;
+use crate::texto::Texto;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Suffixes tried in order when a swap file for the path already exists,
+/// like vim's `.swp`, `.swo`, `.swn`...
+const SUFFIXES: [&str; 6] = ["swp", "swo", "swn", "swm", "swl", "swk"];
+
+/// A copy of a buffer that hasn't been saved yet, written now and then while
+/// editing so the changes survive a crash or a dropped connection.
+///
+/// The file is a few `key value` header lines, an empty line and the text
+/// of the buffer with its lines joined by `\n`. Until the buffer is first
+/// changed it only says who is editing the file, with `text 0` and no text.
+#[derive(Debug)]
+pub struct Swap {
+    /// Where the swap file itself is.
+    pub path: PathBuf,
+    /// The file being edited.
+    pub file: String,
+    pub pid: u32,
+    pub host: String,
+    pub time: u64,
+    pub cursor: (usize, usize),
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub eol: bool,
+    pub bom: bool,
+    pub modified: bool,
+    /// `None` when the swap file was written before any change.
+    pub text: Option<Texto>,
+}
+
+impl Swap {
+    /// Writes the buffer of `archivo` to the swap file at `path`.
+    pub fn write(path: &Path, archivo: &Archivo, cursor: (usize, usize)) -> io::Result<()> {
+        let mut data = header(archivo, cursor);
+        data.push('\n');
+        data.push_str(&archivo.buffer.to_string());
+        save(path, &data)
+    }
+
+    /// Writes a swap file at `path` that only claims the file, for when
+    /// there are no changes to keep yet.
+    pub fn claim(path: &Path, archivo: &Archivo, cursor: (usize, usize)) -> io::Result<()> {
+        save(path, &format!("{}text 0\n\n", header(archivo, cursor)))
+    }
+
+    pub fn read(path: &Path) -> Option<Swap> {
+        let data = fs::read_to_string(path).ok()?;
+        let (header, text) = data.split_once("\n\n")?;
+        let mut lines = header.lines();
+        if lines.next()? != "rim-swap 1" {
+            return None;
+        }
+        let mut swap = Swap {
+            path: path.to_path_buf(),
+            file: String::new(),
+            pid: 0,
+            host: String::new(),
+            time: 0,
+            cursor: (0, 0),
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            eol: true,
+            bom: false,
+            modified: true,
+            text: Some(Texto::from_text(text)),
+        };
+        for line in lines {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "file" => swap.file = value.to_string(),
+                "pid" => swap.pid = value.parse().ok()?,
+                "host" => swap.host = value.to_string(),
+                "time" => swap.time = value.parse().ok()?,
+                "cursor" => {
+                    let (y, x) = value.split_once(' ')?;
+                    swap.cursor = (y.parse().ok()?, x.parse().ok()?);
+                }
+                "encoding" => swap.encoding = Encoding::from_name(value)?,
+                "fileformat" => swap.line_ending = LineEnding::from_name(value)?,
+                "eol" => swap.eol = value == "1",
+                "bom" => swap.bom = value == "1",
+                "modified" => swap.modified = value == "1",
+                "text" if value == "0" => swap.text = None,
+                _ => (),
+            }
+        }
+        Some(swap)
+    }
+
+    /// Whether the rim that wrote this swap file is still running. Processes
+    /// on other machines sharing the home directory can't be checked, so
+    /// they are assumed to be alive.
+    pub fn running(&self) -> bool {
+        if self.host != hostname() {
+            return true;
+        }
+        if self.pid == 0 || self.pid == std::process::id() {
+            return false;
+        }
+        // Signal 0 only checks that the process exists; EPERM means it does
+        // but belongs to someone else.
+        let alive = unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0;
+        alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    /// Whether recovering would give back exactly what `archivo` holds,
+    /// which is also the case when there is no text to recover.
+    pub fn same_as(&self, archivo: &Archivo) -> bool {
+        let Some(text) = &self.text else {
+            return true;
+        };
+        self.encoding == archivo.encoding
+            && self.line_ending == archivo.line_ending
+            && self.eol == archivo.eol
+            && self.bom == archivo.bom
+            && text.len_chars() == archivo.buffer.len_chars()
+            && text.to_string() == archivo.buffer.to_string()
+    }
+
+    /// Puts the saved text back into `archivo` as one undoable change.
+    pub fn restore(&self, archivo: &mut Archivo) {
+        if let Some(text) = &self.text {
+            archivo.replace_text(text);
+        }
+        archivo.set_encoding(self.encoding);
+        archivo.set_line_ending(self.line_ending);
+        archivo.format_changed |= self.eol != archivo.eol || self.bom != archivo.bom;
+        archivo.eol = self.eol;
+        archivo.bom = self.bom;
+    }
+
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The header lines, up to the empty line before the text.
+fn header(archivo: &Archivo, cursor: (usize, usize)) -> String {
+    format!(
+        "rim-swap 1\nfile {}\npid {}\nhost {}\ntime {}\ncursor {} {}\nencoding {}\nfileformat {}\neol {}\nbom {}\nmodified {}\n",
+        absolute(&archivo.path),
+        std::process::id(),
+        hostname(),
+        history::now(),
+        cursor.0,
+        cursor.1,
+        archivo.encoding.name(),
+        archivo.line_ending.name(),
+        archivo.eol as u8,
+        archivo.bom as u8,
+        archivo.modified() as u8,
+    )
+}
+
+fn save(path: &Path, data: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // The buffer may hold things that aren't meant for other users.
+    file::write_atomic(path, data.as_bytes(), Some(0o600))
+}
+
+pub fn swap_dir() -> Option<PathBuf> {
+    file::state_dir("swap")
+}
+
+/// Swap files that already exist for `path`.
+pub fn find(path: &str) -> Vec<Swap> {
+    let Some(dir) = swap_dir() else {
+        return Vec::new();
+    };
+    let name = file::escape_path(path);
+    SUFFIXES
+        .iter()
+        .filter_map(|suffix| Swap::read(&dir.join(format!("{}.{}", name, suffix))))
+        .collect()
+}
+
+/// The first swap file name for `path` that isn't taken yet.
+pub fn free_slot(path: &str) -> Option<PathBuf> {
+    let dir = swap_dir()?;
+    let name = file::escape_path(path);
+    SUFFIXES
+        .iter()
+        .map(|suffix| dir.join(format!("{}.{}", name, suffix)))
+        .find(|p| !p.exists())
+}
+
+/// Every swap file in the swap directory, oldest first, for `rim -r`.
+pub fn list() -> Vec<Swap> {
+    let mut swaps: Vec<Swap> = swap_dir()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Swap::read(&entry.ok()?.path()))
+        .collect();
+    swaps.sort_by_key(|swap| swap.time);
+    swaps
+}
+
+fn absolute(path: &str) -> String {
+    fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ok = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0;
+    if !ok {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file holding `text`, opened, and a path for its swap file.
+    fn open(name: &str, text: &str) -> (Archivo, PathBuf) {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("rim-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        let archivo = Archivo::open(path.to_str().unwrap(), None).unwrap();
+        fs::remove_file(&path).unwrap();
+        (
+            archivo,
+            dir.join(format!("rim-{}-{}.swp", std::process::id(), name)),
+        )
+    }
+
+    #[test]
+    fn swap_files_are_read_back() {
+        let (mut archivo, path) = open("swap", "one\ntwo\n");
+        archivo.begin_change(0, 0);
+        archivo.insert_text(1, 3, &['!']);
+        archivo.end_change();
+        archivo.set_line_ending(LineEnding::Crlf);
+        Swap::write(&path, &archivo, (1, 3)).unwrap();
+        let swap = Swap::read(&path).unwrap();
+        swap.remove();
+        assert_eq!(swap.file, absolute(&archivo.path));
+        assert_eq!(swap.pid, std::process::id());
+        assert_eq!(swap.cursor, (1, 3));
+        assert_eq!(swap.line_ending, LineEnding::Crlf);
+        assert!(swap.modified);
+        assert_eq!(swap.text.as_ref().unwrap().to_string(), "one\ntwo!");
+        assert!(swap.same_as(&archivo));
+
+        let (mut fresh, _) = open("swap", "one\ntwo\n");
+        assert!(!swap.same_as(&fresh));
+        swap.restore(&mut fresh);
+        assert!(swap.same_as(&fresh));
+        assert!(fresh.modified());
+    }
+
+    #[test]
+    fn claims_have_no_text() {
+        let (archivo, path) = open("claim", "one\n");
+        Swap::claim(&path, &archivo, (0, 0)).unwrap();
+        let swap = Swap::read(&path).unwrap();
+        swap.remove();
+        assert!(!swap.modified);
+        assert!(swap.text.is_none());
+        assert!(swap.same_as(&archivo));
+    }
+
+    #[test]
+    fn running_editors_are_detected() {
+        let (archivo, path) = open("running", "one\n");
+        Swap::claim(&path, &archivo, (0, 0)).unwrap();
+        let mut swap = Swap::read(&path).unwrap();
+        swap.remove();
+        // This process wrote it, so it isn't another rim.
+        assert!(!swap.running());
+        // A process that is gone left it behind.
+        swap.pid = u32::MAX >> 1;
+        assert!(!swap.running());
+        swap.pid = 1;
+        assert!(swap.running());
+        // Processes on other machines can't be checked.
+        swap.pid = u32::MAX >> 1;
+        swap.host.push_str("-elsewhere");
+        assert!(swap.running());
+    }
+
+    #[test]
+    fn other_files_are_not_swap_files() {
+        let path = std::env::temp_dir().join(format!("rim-{}-other.swp", std::process::id()));
+        fs::write(&path, "just some text\n\nmore").unwrap();
+        assert!(Swap::read(&path).is_none());
+        fs::write(&path, "rim-swap 1\npid x\n\n").unwrap();
+        assert!(Swap::read(&path).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use ropey::Rope;
+
+/// Text storage for an open file. Lines are kept in a rope joined by `\n`
+/// (with no trailing newline), so inserting or removing text anywhere costs
+/// O(log n) instead of shifting every line after it.
+///
+/// Positions are given as `(y, x)` pairs of line and char column, the same
+/// way `State::idx_y` and `State::idx_x` address the buffer.
+#[derive(Debug, Clone, Default)]
+pub struct Texto {
+    rope: Rope,
+}
+
+impl Texto {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the storage from text whose lines are separated by `\n`.
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            rope: Rope::from_str(text),
+        }
+    }
+
+    pub fn from_lines<I, S>(lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut builder = ropey::RopeBuilder::new();
+        for (i, line) in lines.into_iter().enumerate() {
+            if i > 0 {
+                builder.append("\n");
+            }
+            builder.append(line.as_ref());
+        }
+        Self {
+            rope: builder.finish(),
+        }
+    }
+
+    /// Number of lines. An empty buffer still has one (empty) line.
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    /// Length of line `y` in chars, not counting the line break.
+    pub fn line_len(&self, y: usize) -> usize {
+        let line = self.rope.line(y);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    pub fn line(&self, y: usize) -> Vec<char> {
+        let len = self.line_len(y);
+        self.rope.line(y).chars().take(len).collect()
+    }
+
+    pub fn line_string(&self, y: usize) -> String {
+        let len = self.line_len(y);
+        self.rope.line(y).slice(..len).to_string()
+    }
+
+    pub fn char_at(&self, y: usize, x: usize) -> Option<char> {
+        if y < self.len_lines() && x < self.line_len(y) {
+            Some(self.rope.char(self.pos_to_char(y, x)))
+        } else {
+            None
+        }
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = Vec<char>> + '_ {
+        (0..self.len_lines()).map(|y| self.line(y))
+    }
+
+    /// Inserts `text` (which must not contain `\n`) into line `y` at column `x`.
+    pub fn insert(&mut self, y: usize, x: usize, text: &[char]) {
+        let idx = self.pos_to_char(y, x);
+        self.rope.insert(idx, &text.iter().collect::<String>());
+    }
+
+    /// Removes `len` chars of line `y` starting at column `x`.
+    pub fn remove(&mut self, y: usize, x: usize, len: usize) {
+        let idx = self.pos_to_char(y, x);
+        self.rope.remove(idx..idx + len);
+    }
+
+    /// Inserts a new line so that it becomes line `y`.
+    pub fn insert_line(&mut self, y: usize, line: &[char]) {
+        let mut text: String = line.iter().collect();
+        if y < self.len_lines() {
+            text.push('\n');
+            self.rope.insert(self.rope.line_to_char(y), &text);
+        } else {
+            text.insert(0, '\n');
+            self.rope.insert(self.rope.len_chars(), &text);
+        }
+    }
+
+    /// Removes line `y` together with its line break. The last remaining line
+    /// is only emptied, since the buffer always holds at least one line.
+    pub fn remove_line(&mut self, y: usize) {
+        let start = self.rope.line_to_char(y);
+        if y + 1 < self.len_lines() {
+            self.rope.remove(start..self.rope.line_to_char(y + 1));
+        } else if y > 0 {
+            self.rope.remove(start - 1..self.rope.len_chars());
+        } else {
+            self.rope.remove(..);
+        }
+    }
+
+    /// The text of lines `from..to`, joined by `\n`.
+    pub fn lines_text(&self, from: usize, to: usize) -> String {
+        let end = if to < self.len_lines() {
+            self.rope.line_to_char(to) - 1
+        } else {
+            self.rope.len_chars()
+        };
+        self.rope
+            .slice(self.rope.line_to_char(from)..end)
+            .to_string()
+    }
+
+    pub fn line_to_char(&self, y: usize) -> usize {
+        self.rope.line_to_char(y)
+    }
+
+    pub fn char_to_line(&self, idx: usize) -> usize {
+        self.rope.char_to_line(idx)
+    }
+
+    pub fn char_to_byte(&self, idx: usize) -> usize {
+        self.rope.char_to_byte(idx)
+    }
+
+    pub fn byte_to_char(&self, idx: usize) -> usize {
+        self.rope.byte_to_char(idx)
+    }
+
+    pub fn pos_to_char(&self, y: usize, x: usize) -> usize {
+        self.rope.line_to_char(y) + x
+    }
+
+    pub fn char_to_pos(&self, idx: usize) -> (usize, usize) {
+        let y = self.rope.char_to_line(idx);
+        (y, idx - self.rope.line_to_char(y))
+    }
+}
+
+impl std::fmt::Display for Texto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.rope.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
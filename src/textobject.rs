@@ -0,0 +1,521 @@
+use crate::motion::{class, next, prev};
+use crate::operator::Region;
+use crate::texto::Texto;
+use regex::Regex;
+use std::sync::LazyLock;
+
+static TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)([A-Za-z][\w:.-]*)[^>]*?(/?)>").unwrap());
+
+/// How many lines above and below the cursor tag objects look at.
+const TAG_LINES: usize = 1000;
+
+/// A piece of text around the cursor, typed after an operator as `i` or
+/// `a` and a key: `iw` is the inner word, `ap` a paragraph with the blank
+/// lines after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextObject {
+    /// `iw`, `aw`
+    Word { big: bool },
+    /// `is`, `as`
+    Sentence,
+    /// `ip`, `ap`
+    Paragraph,
+    /// `i"`, `a'`, ``i` ``
+    Quote(char),
+    /// `i(`, `a{`, `i[`, `i<`, with `b` and `B` for `(` and `{`
+    Block(char, char),
+    /// `it`, `at`
+    Tag,
+}
+
+impl TextObject {
+    /// The object for the key typed after `i` or `a`. Tag objects only
+    /// make sense in markup, so they need `tags`.
+    pub fn from_key(ch: i32, tags: bool) -> Option<TextObject> {
+        Some(match ch {
+            119 => TextObject::Word { big: false },
+            87 => TextObject::Word { big: true },
+            115 => TextObject::Sentence,
+            112 => TextObject::Paragraph,
+            // " ' `
+            34 | 39 | 96 => TextObject::Quote(ch as u8 as char),
+            40 | 41 | 98 => TextObject::Block('(', ')'),
+            123 | 125 | 66 => TextObject::Block('{', '}'),
+            91 | 93 => TextObject::Block('[', ']'),
+            60 | 62 => TextObject::Block('<', '>'),
+            116 if tags => TextObject::Tag,
+            _ => return None,
+        })
+    }
+
+    /// The region of `count` objects at `(y, x)`. `around` is `a`, which
+    /// takes in the white space around the object.
+    pub fn select(
+        &self,
+        buffer: &Texto,
+        (y, x): (usize, usize),
+        count: usize,
+        around: bool,
+    ) -> Option<Region> {
+        match self {
+            TextObject::Word { big } => word(buffer, (y, x), count, *big, around),
+            TextObject::Sentence => sentence(buffer, (y, x), count, around),
+            TextObject::Paragraph => paragraph(buffer, y, count, around),
+            TextObject::Quote(quote) => self::quote(buffer, (y, x), *quote, around),
+            TextObject::Block(open, close) => block(buffer, (y, x), (*open, *close), count, around),
+            TextObject::Tag => tag(buffer, (y, x), count, around),
+        }
+    }
+}
+
+/// Words, and the white space between them, are runs of characters of the
+/// same class. Word objects don't go past the end of the line.
+fn word(
+    buffer: &Texto,
+    (y, x): (usize, usize),
+    count: usize,
+    big: bool,
+    around: bool,
+) -> Option<Region> {
+    let line = buffer.line(y);
+    if line.is_empty() {
+        return None;
+    }
+    let x = x.min(line.len() - 1);
+    let kind = |i: usize| class(Some(line[i]), big);
+    let run_end = |from: usize| {
+        let k = kind(from);
+        (from..line.len())
+            .find(|&i| kind(i) != k)
+            .unwrap_or(line.len())
+    };
+    let mut start = x;
+    while start > 0 && kind(start - 1) == kind(x) {
+        start -= 1;
+    }
+    let on_blank = kind(x) == 0;
+    let mut end = run_end(x);
+    if around && on_blank && end < line.len() {
+        // White space and the word after it.
+        end = run_end(end);
+    } else if around && end < line.len() && kind(end) == 0 {
+        end = run_end(end);
+    } else if around {
+        // No white space after the word, take the one before it.
+        while start > 0 && kind(start - 1) == 0 {
+            start -= 1;
+        }
+    }
+    for _ in 1..count {
+        if end >= line.len() {
+            break;
+        }
+        end = run_end(end);
+        if around && end < line.len() && kind(end) == 0 {
+            end = run_end(end);
+        }
+    }
+    Some(Region {
+        start: (y, start),
+        end: (y, end),
+        linewise: false,
+    })
+}
+
+/// A paragraph is a run of lines that aren't blank, or a run of blank
+/// lines.
+fn paragraph(buffer: &Texto, y: usize, count: usize, around: bool) -> Option<Region> {
+    let last = buffer.len_lines() - 1;
+    let blank = |y: usize| buffer.line(y).iter().all(|c| c.is_whitespace());
+    let run_end = |from: usize| {
+        let kind = blank(from);
+        let mut end = from;
+        while end < last && blank(end + 1) == kind {
+            end += 1;
+        }
+        end
+    };
+    let kind = blank(y);
+    let mut start = y;
+    while start > 0 && blank(start - 1) == kind {
+        start -= 1;
+    }
+    let mut end = run_end(y);
+    for _ in 1..count {
+        if end == last {
+            return None;
+        }
+        end = run_end(end + 1);
+    }
+    if around && end < last {
+        end = run_end(end + 1);
+    } else if around && !kind {
+        // Nothing after the last paragraph, take the blank lines before it.
+        while start > 0 && blank(start - 1) {
+            start -= 1;
+        }
+    }
+    Some(Region {
+        start: (start, 0),
+        end: (end, buffer.line_len(end)),
+        linewise: true,
+    })
+}
+
+/// Sentences end at `.`, `!` or `?`, maybe followed by closing brackets
+/// and quotes, and then white space or the end of the line. They don't go
+/// past the paragraph. Like vim, the white space between two sentences
+/// counts as a sentence for `is`.
+fn sentence(buffer: &Texto, (y, x): (usize, usize), count: usize, around: bool) -> Option<Region> {
+    let blank = |y: usize| buffer.line(y).iter().all(|c| c.is_whitespace());
+    if blank(y) {
+        return None;
+    }
+    let mut first = y;
+    while first > 0 && !blank(first - 1) {
+        first -= 1;
+    }
+    let mut last = y;
+    while last + 1 < buffer.len_lines() && !blank(last + 1) {
+        last += 1;
+    }
+    let offset = buffer.pos_to_char(first, 0);
+    let text: Vec<char> = (first..=last)
+        .flat_map(|y| {
+            let mut line = buffer.line(y);
+            line.push('\n');
+            line
+        })
+        .collect();
+    let end = text.len() - 1;
+
+    // The text is split in runs that are either a sentence or white space.
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < end {
+        let start = i;
+        if text[i].is_whitespace() {
+            while i < end && text[i].is_whitespace() {
+                i += 1;
+            }
+        } else {
+            while i < end {
+                let c = text[i];
+                i += 1;
+                if ".!?".contains(c) {
+                    while i < end && ")]\"'".contains(text[i]) {
+                        i += 1;
+                    }
+                    if text[i].is_whitespace() {
+                        break;
+                    }
+                }
+            }
+            // White space at the end of the paragraph isn't part of it.
+            while i > start && text[i - 1].is_whitespace() {
+                i -= 1;
+            }
+        }
+        runs.push((start, i, text[start].is_whitespace()));
+    }
+    let cursor = buffer.pos_to_char(y, x) - offset;
+    // Runs alternate, a sentence always ends before white space.
+    let n = runs.iter().position(|&(_, end, _)| cursor < end)?;
+    let (mut start, _, white) = runs[n];
+    let stop = if !around {
+        runs.get(n.saturating_add(count - 1))?.1
+    } else if white {
+        // The white space and the sentences after it.
+        runs.get(n.saturating_add(count.saturating_mul(2) - 1))?.1
+    } else {
+        let last = n.saturating_add((count - 1).saturating_mul(2));
+        runs.get(last)?;
+        match runs.get(last.saturating_add(1)) {
+            Some(&(_, end, _)) => end,
+            None => {
+                // No white space after it, take the one before it.
+                if n > 0 {
+                    start = runs[n - 1].0;
+                }
+                runs[last].1
+            }
+        }
+    };
+    Some(Region {
+        start: buffer.char_to_pos(offset + start),
+        end: buffer.char_to_pos(offset + stop),
+        linewise: false,
+    })
+}
+
+/// Quotes pair up from the start of the line, skipping the ones escaped
+/// with a backslash. Outside of a quoted string the first one after the
+/// cursor is used. `a` takes the white space after the closing quote too,
+/// or else the one before the opening quote.
+fn quote(buffer: &Texto, (y, x): (usize, usize), quote: char, around: bool) -> Option<Region> {
+    let line = buffer.line(y);
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (i, &c) in line.iter().enumerate() {
+        if c == quote && !escaped {
+            quotes.push(i);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| x <= close)?;
+    let (mut start, mut end) = (open + 1, close);
+    if around {
+        start = open;
+        end = close + 1;
+        let white = |i: usize| line.get(i).is_some_and(|c| *c == ' ' || *c == '\t');
+        if white(end) {
+            while white(end) {
+                end += 1;
+            }
+        } else {
+            while start > 0 && white(start - 1) {
+                start -= 1;
+            }
+        }
+    }
+    Some(Region {
+        start: (y, start),
+        end: (y, end),
+        linewise: false,
+    })
+}
+
+/// The `count`th pair of brackets around the cursor. Like vim, when the
+/// opening bracket ends its line and the closing one starts its own, the
+/// inner block is the lines in between.
+fn block(
+    buffer: &Texto,
+    cursor: (usize, usize),
+    (open, close): (char, char),
+    count: usize,
+    around: bool,
+) -> Option<Region> {
+    let at = |pos: (usize, usize)| buffer.char_at(pos.0, pos.1);
+    let mut from = cursor;
+    // On a closing bracket the pair is the one it closes.
+    if at(cursor) == Some(close) {
+        from = prev(buffer, cursor)?;
+    }
+    let mut start = None;
+    for _ in 0..count {
+        let mut pos = match start {
+            Some(start) => prev(buffer, start)?,
+            None => from,
+        };
+        let mut depth = 0;
+        loop {
+            match at(pos) {
+                Some(c) if c == close && pos != cursor => depth += 1,
+                Some(c) if c == open && depth == 0 => break,
+                Some(c) if c == open => depth -= 1,
+                _ => (),
+            }
+            pos = prev(buffer, pos)?;
+        }
+        start = Some(pos);
+    }
+    let start = start?;
+    let mut end = next(buffer, start)?;
+    let mut depth = 0;
+    loop {
+        match at(end) {
+            Some(c) if c == open => depth += 1,
+            Some(c) if c == close && depth == 0 => break,
+            Some(c) if c == close => depth -= 1,
+            _ => (),
+        }
+        end = next(buffer, end)?;
+    }
+    if around {
+        return Some(Region {
+            start,
+            end: (end.0, end.1 + 1),
+            linewise: false,
+        });
+    }
+    let before = buffer.line(end.0)[..end.1]
+        .iter()
+        .all(|c| c.is_whitespace());
+    if start.1 + 1 == buffer.line_len(start.0) && before && end.0 > start.0 + 1 {
+        return Some(Region {
+            start: (start.0 + 1, 0),
+            end: (end.0 - 1, buffer.line_len(end.0 - 1)),
+            linewise: true,
+        });
+    }
+    Some(Region {
+        start: next(buffer, start)?,
+        end,
+        linewise: false,
+    })
+}
+
+/// The `count`th element around the cursor, from its opening tag to the
+/// matching closing one. Tags that close themselves or are never closed
+/// don't count. Only the `TAG_LINES` lines on either side of the cursor
+/// are looked at.
+fn tag(buffer: &Texto, (y, x): (usize, usize), count: usize, around: bool) -> Option<Region> {
+    let first = y.saturating_sub(TAG_LINES);
+    let last = (y + TAG_LINES + 1).min(buffer.len_lines());
+    let text = buffer.lines_text(first, last);
+    let base = buffer.char_to_byte(buffer.line_to_char(first));
+    let cursor = buffer.char_to_byte(buffer.pos_to_char(y, x)) - base;
+    let mut open: Vec<(&str, usize, usize)> = Vec::new();
+    let mut elements = Vec::new();
+    for caps in TAG.captures_iter(&text) {
+        let whole = caps.get(0).unwrap();
+        let name = caps.get(2).unwrap().as_str();
+        if !caps[3].is_empty() {
+            continue;
+        }
+        if caps[1].is_empty() {
+            open.push((name, whole.start(), whole.end()));
+        } else if let Some(i) = open
+            .iter()
+            .rposition(|(open, ..)| open.eq_ignore_ascii_case(name))
+        {
+            let (_, start, inner) = open[i];
+            open.truncate(i);
+            elements.push((start, inner, whole.start(), whole.end()));
+        }
+    }
+    let mut around_cursor: Vec<_> = elements
+        .into_iter()
+        .filter(|&(start, _, _, end)| start <= cursor && cursor < end)
+        .collect();
+    around_cursor.sort_by_key(|&(start, ..)| std::cmp::Reverse(start));
+    let (start, inner, close, end) = *around_cursor.get(count - 1)?;
+    let (start, end) = if around { (start, end) } else { (inner, close) };
+    let pos = |byte: usize| buffer.char_to_pos(buffer.byte_to_char(base + byte));
+    Some(Region {
+        start: pos(start),
+        end: pos(end),
+        linewise: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text `object` selects, lines joined by `\n`.
+    fn select(
+        text: &str,
+        object: TextObject,
+        cursor: (usize, usize),
+        count: usize,
+        around: bool,
+    ) -> Option<String> {
+        let buffer = Texto::from_text(text);
+        let region = object.select(&buffer, cursor, count, around)?;
+        Some(region.text(&buffer).join("\n"))
+    }
+
+    #[test]
+    fn sentences() {
+        let text = "One. Two (yes!)  three?\nFour.\n\nFive.";
+        let is = |cursor, count| select(text, TextObject::Sentence, cursor, count, false);
+        let as_ = |cursor, count| select(text, TextObject::Sentence, cursor, count, true);
+        assert_eq!(is((0, 1), 1).as_deref(), Some("One."));
+        assert_eq!(is((0, 6), 1).as_deref(), Some("Two (yes!)"));
+        // The white space between sentences is one too.
+        assert_eq!(is((0, 16), 1).as_deref(), Some("  "));
+        assert_eq!(is((0, 0), 3).as_deref(), Some("One. Two (yes!)"));
+        assert_eq!(as_((0, 0), 1).as_deref(), Some("One. "));
+        assert_eq!(as_((0, 4), 1).as_deref(), Some(" Two (yes!)"));
+        assert_eq!(is((0, 20), 1).as_deref(), Some("three?"));
+        // The last one of the paragraph takes the white space before it.
+        assert_eq!(as_((1, 0), 1).as_deref(), Some("\nFour."));
+        assert_eq!(is((2, 0), 1), None);
+        assert_eq!(is((3, 0), 2), None);
+        assert_eq!(is((0, 0), usize::MAX), None);
+        assert_eq!(as_((0, 0), usize::MAX), None);
+        assert_eq!(as_((0, 4), usize::MAX), None);
+    }
+
+    #[test]
+    fn quotes() {
+        let text = r#"a "b \" c" d 'e'"#;
+        let quote = |ch, cursor, around| select(text, TextObject::Quote(ch), cursor, 1, around);
+        assert_eq!(quote('"', (0, 4), false).as_deref(), Some(r#"b \" c"#));
+        assert_eq!(quote('"', (0, 4), true).as_deref(), Some(r#""b \" c" "#));
+        // Before the quotes the first pair after the cursor is used.
+        assert_eq!(quote('"', (0, 0), false).as_deref(), Some(r#"b \" c"#));
+        assert_eq!(quote('\'', (0, 15), true).as_deref(), Some(" 'e'"));
+        assert_eq!(quote('"', (0, 12), false), None);
+    }
+
+    #[test]
+    fn blocks() {
+        let text = "f(a, (b), c) [x]";
+        let paren = TextObject::Block('(', ')');
+        assert_eq!(select(text, paren, (0, 6), 1, false).as_deref(), Some("b"));
+        assert_eq!(
+            select(text, paren, (0, 6), 2, false).as_deref(),
+            Some("a, (b), c")
+        );
+        assert_eq!(select(text, paren, (0, 6), 1, true).as_deref(), Some("(b)"));
+        // On a bracket the pair is the one it opens or closes.
+        assert_eq!(select(text, paren, (0, 7), 1, false).as_deref(), Some("b"));
+        assert_eq!(
+            select(text, paren, (0, 11), 1, true).as_deref(),
+            Some("(a, (b), c)")
+        );
+        assert_eq!(select(text, paren, (0, 6), 3, false), None);
+        assert_eq!(select(text, paren, (0, 14), 1, false), None);
+        let square = TextObject::Block('[', ']');
+        assert_eq!(
+            select(text, square, (0, 14), 1, false).as_deref(),
+            Some("x")
+        );
+    }
+
+    #[test]
+    fn blocks_of_whole_lines() {
+        let text = "fn f() {\n    a;\n    b;\n}";
+        let brace = TextObject::Block('{', '}');
+        let buffer = Texto::from_text(text);
+        let region = brace.select(&buffer, (1, 4), 1, false).unwrap();
+        assert!(region.linewise);
+        assert_eq!(region.lines(), (1, 2));
+        assert_eq!(
+            select(text, brace, (1, 4), 1, true).as_deref(),
+            Some("{\n    a;\n    b;\n}")
+        );
+    }
+
+    #[test]
+    fn tags() {
+        let text = "<div id=\"x\">\n  <p>one <b>two</b><br/></p>\n</DIV>";
+        let it = |cursor, count| select(text, TextObject::Tag, cursor, count, false);
+        let at = |cursor, count| select(text, TextObject::Tag, cursor, count, true);
+        assert_eq!(it((1, 12), 1).as_deref(), Some("two"));
+        assert_eq!(at((1, 12), 1).as_deref(), Some("<b>two</b>"));
+        assert_eq!(it((1, 6), 1).as_deref(), Some("one <b>two</b><br/>"));
+        assert_eq!(it((1, 12), 2).as_deref(), Some("one <b>two</b><br/>"));
+        // Closing tags match whatever the case.
+        assert_eq!(at((1, 12), 3).as_deref(), Some(text));
+        assert_eq!(it((1, 12), 4), None);
+        // A tag that closes itself isn't an element.
+        assert_eq!(it((1, 21), 1).as_deref(), Some("one <b>two</b><br/>"));
+        assert_eq!(select("<a>x", TextObject::Tag, (0, 3), 1, false), None);
+    }
+
+    #[test]
+    fn tags_far_from_the_cursor_are_not_seen() {
+        let mut text = String::from("<a>\n");
+        text.push_str(&"x\n".repeat(TAG_LINES + 1));
+        text.push_str("</a>");
+        assert_eq!(select(&text, TextObject::Tag, (1, 0), 1, false), None);
+        assert!(select(&text, TextObject::Tag, (TAG_LINES, 0), 1, false).is_some());
+    }
+}
@@ -1,10 +1,82 @@
+use crate::clipboard;
+use crate::cmdline::{CmdHistory, CmdLine, CmdLineEvent};
+use crate::command;
 use crate::constants::{obtener_nombre_lenguaje, reserved_words, Lenguaje};
+use crate::diff;
+use crate::encoding::Encoding;
 use crate::explorer;
-use crate::file::{format_permissions, Archivo};
+use crate::file::{format_permissions, Archivo, DiskState, LineEnding};
+use crate::history;
+use crate::mode::Mode;
+use crate::motion::{self, Context, Motion};
+use crate::msg::{Msg, MsgType};
+use crate::operator::{Operator, Region};
+use crate::options::Options;
+use crate::registers::{Register, Registers};
+use crate::repeat::Repeat;
+use crate::search::{self, LastSearch};
+use crate::substitute::{self, Substitute};
+use crate::swap::{self, Swap};
+use crate::textobject::TextObject;
+use crate::visual::{Visual, VisualKind};
 use ncurses::*;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::path::PathBuf;
 
 const START_X: i32 = 5; // x=0 in the editor
 const START_Y: i32 = 1; // y=0 in the editor
+/// The largest count a command takes, like vim.
+const MAX_COUNT: usize = 999_999_999;
+
+/// Refusal to write a buffer read with bytes its encoding can't decode.
+const ILLEGAL_BYTES: &str = "Illegal bytes in the file would be lost (add ! to override)";
+
+/// Control characters (a NUL from a misdetected encoding, say) can't be sent
+/// to ncurses as they are, so they are drawn as `?`.
+fn printable(line: String) -> String {
+    if line.chars().any(|c| c.is_control() && c != '\t') {
+        line.chars()
+            .map(|c| if c.is_control() && c != '\t' { '?' } else { c })
+            .collect()
+    } else {
+        line
+    }
+}
+
+/// The character a key types, for commands that read one: printable ASCII
+/// and Tab. Function keys and control keys are `None`.
+fn typed_char(ch: i32) -> Option<char> {
+    ((32..127).contains(&ch) || ch == 9).then_some(ch as u8 as char)
+}
+
+/// The line typed after `:`, `/` or `?`.
+#[derive(Debug)]
+struct Prompt {
+    line: CmdLine,
+    /// `/` as opposed to `?`.
+    forward: bool,
+    /// Where the cursor and the view were, to go back to after 'incsearch'.
+    origin: (usize, usize, i32),
+    /// The highlight before the search started.
+    highlight: Option<Regex>,
+}
+
+/// A block `I`, `A` or `c` in progress. The text typed on the first line
+/// goes to the other lines when INSERT mode ends.
+#[derive(Debug)]
+struct BlockInsert {
+    first: usize,
+    last: usize,
+    /// The column on each line, `usize::MAX` for the end of the line.
+    col: usize,
+    /// Pad short lines with spaces instead of skipping them.
+    pad: bool,
+    /// Where the text goes on the first line.
+    at: usize,
+    /// Length of the first line and number of lines before typing.
+    before: (usize, usize),
+}
 
 #[derive(Debug)]
 pub struct State {
@@ -14,7 +86,7 @@ pub struct State {
     pub win: WINDOW,
     pub x: i32,
     pub y: i32,
-    pub mode: bool,
+    pub mode: Mode,
     pub idx_x: usize,
     pub idx_y: usize,
     pub start: i32,
@@ -22,6 +94,65 @@ pub struct State {
     pub explorer: explorer::Explorer,
     pub lang: Lenguaje,
     pub reserved: Vec<String>,
+    pub msg: Msg,
+    pub options: Options,
+    /// Last on-disk version of the file the user was told about.
+    pub disk_warned: Option<DiskState>,
+    /// Swap file this instance keeps up to date, if any.
+    pub swap: Option<PathBuf>,
+    /// `archivo.tick` when the swap file was last written.
+    pub swap_tick: u64,
+    /// Keys typed since the swap file was last written.
+    pub swap_keys: usize,
+    /// Set by `:q` and friends to leave `update`.
+    pub quit: bool,
+    pub cmd_history: CmdHistory,
+    pub registers: Registers,
+    pub search: Option<LastSearch>,
+    pub search_history: CmdHistory,
+    /// Matches of this are highlighted, see 'hlsearch'.
+    pub highlight: Option<Regex>,
+    /// The match `:s///c` is asking about, as line, column and length.
+    pub current_match: Option<(usize, usize, usize)>,
+    pub last_substitute: Option<Substitute>,
+    /// Keys queued by `:normal`, read before the terminal.
+    pub keys: VecDeque<i32>,
+    /// How many `:normal` are running. While any is, running out of queued
+    /// keys reads as Esc.
+    pub replaying: usize,
+    /// Set while `:g` runs its command.
+    pub global_busy: bool,
+    /// Substitutions and lines changed by `:s` during `:g`, reported once
+    /// at the end.
+    pub substitutions: (usize, usize),
+    /// The column `j` and `k` try to keep.
+    pub curswant: usize,
+    /// The last `f`, `F`, `t` or `T`, for `;` and `,`.
+    pub last_find: Option<Motion>,
+    /// The register typed with `"` for the command being run.
+    pub register: Option<char>,
+    /// The selection being shown, in VISUAL mode.
+    pub visual: Option<Visual>,
+    /// The last selection, for `gv`.
+    pub last_visual: Option<Visual>,
+    /// The operator waiting for a motion in OPERATOR-PENDING mode, with
+    /// the count typed before it.
+    pub pending: Option<(Operator, Option<usize>)>,
+    /// The line being typed in COMMAND and SEARCH mode.
+    prompt: Option<Prompt>,
+    block: Option<BlockInsert>,
+    /// What was typed over in REPLACE mode, one entry per character typed:
+    /// the character that was there, or `None` where the line grew.
+    pub replaced: Vec<Option<char>>,
+    /// The keys of the NORMAL mode command being run, with `archivo.tick`
+    /// from before it, to tell whether it changed the buffer.
+    recording: Option<(u64, Vec<i32>)>,
+    /// The last change, for `.`.
+    pub last_change: Option<Repeat>,
+    /// The register `q` is recording into, and the keys typed so far.
+    pub macro_keys: Option<(char, Vec<i32>)>,
+    /// The register last run with `@`, for `@@`.
+    pub last_macro: Option<char>,
 }
 
 impl State {
@@ -32,41 +163,311 @@ impl State {
         raw();
         start_color();
         cbreak();
-        init_color(COLOR_BLACK as i16, 110, 110, 110);
-        init_color(COLOR_BLUE as i16, 40, 40, 1000);
+        init_color(COLOR_BLACK, 110, 110, 110);
+        init_color(COLOR_BLUE, 40, 40, 1000);
         init_pair(1, COLOR_BLACK, COLOR_WHITE);
         init_pair(2, COLOR_WHITE, COLOR_BLUE);
         init_pair(3, COLOR_BLUE, COLOR_BLACK);
         init_pair(5, COLOR_RED, COLOR_BLACK);
+        init_pair(6, COLOR_BLACK, COLOR_YELLOW);
+        init_pair(7, COLOR_BLACK, COLOR_CYAN);
 
         let w = getmaxx(stdscr());
         let h = getmaxy(stdscr());
         let end = h - 5;
 
         let win = newwin(h, w, 0, 0);
-        let p = path.into();
-        let mut explorer = explorer::Explorer::new(&p);
-        explorer.get_files().expect("EXPLORER CANT READ DIRS");
-        let file = p.split("/").last().unwrap();
-        let lengauaje = file.split(".").last().unwrap();
-        let lang = obtener_nombre_lenguaje(lengauaje).unwrap();
-        let reserved = reserved_words(&lang);
+        let p: String = path.into();
 
-        State {
-            archivo: Archivo::new(&p),
+        let mut state = State {
+            archivo: Archivo::empty(&p),
             w,
             h,
             win,
             x: START_X,
             y: START_Y,
-            mode: false,
+            mode: Mode::Normal,
             idx_x: 0,
             idx_y: 0,
             start: 0,
             end,
-            explorer,
-            lang,
-            reserved,
+            explorer: explorer::Explorer::new(&p),
+            lang: Lenguaje::Undefined,
+            reserved: Vec::new(),
+            msg: Msg::new(win, "", MsgType::Info),
+            options: Options::load(),
+            disk_warned: None,
+            swap: None,
+            swap_tick: 0,
+            swap_keys: 0,
+            quit: false,
+            cmd_history: CmdHistory::load("cmd"),
+            registers: Registers::default(),
+            search: None,
+            search_history: CmdHistory::load("search"),
+            highlight: None,
+            current_match: None,
+            last_substitute: None,
+            keys: VecDeque::new(),
+            replaying: 0,
+            global_busy: false,
+            substitutions: (0, 0),
+            curswant: 0,
+            last_find: None,
+            register: None,
+            visual: None,
+            last_visual: None,
+            pending: None,
+            prompt: None,
+            block: None,
+            replaced: Vec::new(),
+            recording: None,
+            last_change: None,
+            macro_keys: None,
+            last_macro: None,
+        };
+        state.edit_file(&p, None);
+        state
+    }
+
+    /// Replaces the buffer with the file at `path`, as for `:e path`,
+    /// decoded with `encoding` or with the one detected.
+    fn edit_file(&mut self, path: &str, encoding: Option<Encoding>) {
+        self.close_swap();
+        let mut explorer = explorer::Explorer::new(path);
+        let listed = explorer.get_files();
+        let file = path.split("/").last().unwrap();
+        let lengauaje = file.split(".").last().unwrap();
+        let lang = obtener_nombre_lenguaje(lengauaje).unwrap();
+        let reserved = reserved_words(&lang);
+
+        let archivo = match Archivo::open(path, encoding) {
+            Ok(archivo) if archivo.new_file => {
+                self.msg
+                    .update(format!("\"{}\" [New]", path), MsgType::Info);
+                archivo
+            }
+            Ok(archivo) if archivo.illegal => {
+                self.msg
+                    .update(format!("\"{}\" [ILLEGAL BYTE]", path), MsgType::Error);
+                archivo
+            }
+            Ok(archivo) if archivo.readonly => {
+                self.msg
+                    .update(format!("\"{}\" [readonly]", path), MsgType::Info);
+                archivo
+            }
+            Ok(archivo) => {
+                self.msg.update("", MsgType::Info);
+                archivo
+            }
+            Err(err) => {
+                self.msg
+                    .update(format!("\"{}\" {}", path, err), MsgType::Error);
+                Archivo::empty(path)
+            }
+        };
+        if let Err(err) = listed {
+            self.msg.update(err.to_string(), MsgType::Error);
+        }
+
+        self.disk_warned = archivo.disk;
+        self.archivo = archivo;
+        self.explorer = explorer;
+        self.lang = lang;
+        self.reserved = reserved;
+        self.idx_x = 0;
+        self.idx_y = 0;
+        self.x = START_X;
+        self.y = START_Y;
+        self.start = 0;
+        wclear(self.win);
+        self.open_swap();
+    }
+
+    /// Deals with swap files left for the same file, by a rim that is still
+    /// running or by one that died, and then starts this instance's own.
+    fn open_swap(&mut self) {
+        if !self.options.swapfile || self.options.updatecount == 0 {
+            return;
+        }
+        for found in swap::find(&self.archivo.path) {
+            if found.running() {
+                let question = format!(
+                    "\"{}\" is being edited by rim (pid {} on {}). [o]pen read-only, [e]dit anyway, [q]uit",
+                    self.archivo.path, found.pid, found.host
+                );
+                match self.ask_swap(&question, &[111, 101, 113]) {
+                    // o / Esc
+                    111 | 27 => {
+                        self.archivo.readonly = true;
+                        return;
+                    }
+                    // q
+                    113 => {
+                        endwin();
+                        std::process::exit(0);
+                    }
+                    _ => (),
+                }
+            } else if found.same_as(&self.archivo) {
+                // Left behind after a save; there is nothing to get back.
+                found.remove();
+            } else {
+                let question = format!(
+                    "Found a swap file for \"{}\" from {}. [r]ecover, [d]elete it, [e]dit anyway, [q]uit",
+                    self.archivo.path,
+                    history::ago(found.time)
+                );
+                match self.ask_swap(&question, &[114, 100, 101, 113]) {
+                    // Esc leaves the swap file for later and opens the file
+                    // read-only.
+                    27 => {
+                        self.archivo.readonly = true;
+                        return;
+                    }
+                    // r
+                    114 => {
+                        found.restore(&mut self.archivo);
+                        found.remove();
+                        self.goto(found.cursor.0, found.cursor.1);
+                        self.msg.update(
+                            format!(
+                                "Recovered \"{}\", check the changes and :w to keep them",
+                                self.archivo.path
+                            ),
+                            MsgType::Info,
+                        );
+                    }
+                    // d
+                    100 => found.remove(),
+                    // q
+                    113 => {
+                        endwin();
+                        std::process::exit(0);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        // The text is only written once there are changes to keep, which a
+        // recovered buffer already has.
+        self.swap = swap::free_slot(&self.archivo.path);
+        if self.archivo.modified() {
+            self.swap_tick = u64::MAX;
+            self.write_swap();
+            return;
+        }
+        self.swap_tick = self.archivo.tick;
+        if let Some(path) = &self.swap {
+            if let Err(err) = Swap::claim(path, &self.archivo, (self.idx_y, self.idx_x)) {
+                self.msg.update(
+                    format!("Unable to write swap file, no recovery: {}", err),
+                    MsgType::Error,
+                );
+                self.swap = None;
+            }
+        }
+    }
+
+    /// Asks `question` until one of `keys` or Esc is typed. Running out of
+    /// keys in `:normal` reads as Esc, so that can't loop forever.
+    fn ask_swap(&mut self, question: &str, keys: &[i32]) -> i32 {
+        self.display();
+        loop {
+            let ch = self.ask(question);
+            if keys.contains(&ch) || ch == 27 {
+                wclear(self.win);
+                return ch;
+            }
+        }
+    }
+
+    /// Writes the swap file if the buffer changed since the last time.
+    fn write_swap(&mut self) {
+        self.swap_keys = 0;
+        if self.archivo.tick == self.swap_tick {
+            return;
+        }
+        if let Some(path) = &self.swap {
+            let cursor = (self.idx_y, self.idx_x);
+            match Swap::write(path, &self.archivo, cursor) {
+                Ok(()) => self.swap_tick = self.archivo.tick,
+                Err(err) => {
+                    self.msg.update(
+                        format!("Unable to write swap file, no recovery: {}", err),
+                        MsgType::Error,
+                    );
+                    self.swap = None;
+                }
+            }
+        }
+    }
+
+    /// Removes the swap file when the buffer is closed.
+    fn close_swap(&mut self) {
+        if let Some(path) = self.swap.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// The next key queued by `:normal`. Once they run out it is Esc, which
+    /// ends whatever the keys left unfinished.
+    fn queued_key(&mut self) -> Option<i32> {
+        match self.keys.pop_front() {
+            Some(ch) => Some(ch),
+            None if self.replaying > 0 => Some(27),
+            None => None,
+        }
+    }
+
+    /// Reads a key without the idle handling of `read_key`, for prompts.
+    fn getch(&mut self) -> i32 {
+        let ch = match self.queued_key() {
+            Some(ch) => ch,
+            None => self.typed_key(wgetch(self.win)),
+        };
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(ch);
+        }
+        ch
+    }
+
+    /// Keeps a key read from the terminal while `q` records.
+    fn typed_key(&mut self, ch: i32) -> i32 {
+        if let Some((_, keys)) = &mut self.macro_keys {
+            keys.push(ch);
+        }
+        ch
+    }
+
+    /// Waits for the next key, writing the swap file after 'updatecount'
+    /// keys or when nothing was typed for 'updatetime' milliseconds.
+    fn read_key(&mut self) -> i32 {
+        if let Some(ch) = self.queued_key() {
+            return ch;
+        }
+        if self.swap_keys >= self.options.updatecount {
+            self.write_swap();
+        }
+        let timeout = if self.swap.is_some() && self.options.updatetime > 0 {
+            self.options.updatetime
+        } else {
+            -1
+        };
+        loop {
+            wtimeout(self.win, timeout);
+            let ch = wgetch(self.win);
+            wtimeout(self.win, -1);
+            if ch != ERR {
+                self.swap_keys += 1;
+                return self.typed_key(ch);
+            }
+            self.write_swap();
+            if self.swap.is_none() {
+                self.display_msg();
+                wrefresh(self.win);
+            }
         }
     }
 
@@ -87,145 +488,282 @@ impl State {
             self.idx_y = 0;
             self.start = 0;
             self.explorer = explorer::Explorer::new(&self.archivo.path);
-            self.explorer.get_files().unwrap();
+            if let Err(err) = self.explorer.get_files() {
+                self.msg.update(err.to_string(), MsgType::Error);
+            }
         }
 
         curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+        wmove(self.win, 0, 0);
+        wclrtoeol(self.win);
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-        mvwprintw(self.win, 0, 1, &self.archivo.path);
+        mvwaddstr(self.win, 0, 1, &self.archivo.path);
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
+        if self.archivo.modified() {
+            waddstr(self.win, " [+]");
+        }
+        if self.archivo.readonly {
+            waddstr(self.win, " [readonly]");
+        }
+        if self.archivo.illegal {
+            waddstr(self.win, " [ILLEGAL BYTE]");
+        }
 
         if self.lang == Lenguaje::Undefined {
             for (idx, _i) in (self.start..self.end + self.start).enumerate() {
-                mvwprintw(self.win, (idx + 1) as i32, 1, "~");
+                mvwaddstr(self.win, (idx + 1) as i32, 1, "~");
             }
             for (_idx, i) in (self.start..self.end + self.start).enumerate() {
-                if i > (self.archivo.buffer.len() - 1) as i32 {
+                if i > (self.archivo.buffer.len_lines() - 1) as i32 {
                     break;
                 }
 
                 let format = if i < 10 {
                     format!(
                         "{}     ",
-                        self.archivo.buffer[i as usize]
-                            .iter()
-                            .cloned()
-                            .collect::<String>()
+                        printable(self.archivo.buffer.line_string(i as usize))
                     )
                 } else if i < 100 {
                     format!(
                         "{}    ",
-                        self.archivo.buffer[i as usize]
-                            .iter()
-                            .cloned()
-                            .collect::<String>()
+                        printable(self.archivo.buffer.line_string(i as usize))
                     )
                 } else if i < 1000 {
                     format!(
                         "{}   ",
-                        self.archivo.buffer[i as usize]
-                            .iter()
-                            .cloned()
-                            .collect::<String>()
+                        printable(self.archivo.buffer.line_string(i as usize))
                     )
                 } else {
-                    self.archivo.buffer[i as usize]
-                        .iter()
-                        .collect::<String>()
-                        .to_string()
+                    printable(self.archivo.buffer.line_string(i as usize))
                 };
 
-                mvwprintw(self.win, (_idx + 1) as i32, 1, &i.to_string());
-                mvwprintw(self.win, (_idx + 1) as i32, START_X, &format);
+                mvwaddstr(self.win, (_idx + 1) as i32, 1, &i.to_string());
+                mvwaddstr(self.win, (_idx + 1) as i32, START_X, &format);
             }
         } else {
             for (idx, _i) in (self.start..self.end + self.start).enumerate() {
-                mvwprintw(self.win, (idx + 1) as i32, 1, "~");
+                mvwaddstr(self.win, (idx + 1) as i32, 1, "~");
             }
 
             for (_idx, i) in (self.start..self.end + self.start).enumerate() {
-                if i > (self.archivo.buffer.len() - 1) as i32 {
+                if i > (self.archivo.buffer.len_lines() - 1) as i32 {
                     break;
                 }
-                let v: String = self.archivo.buffer[i as usize].iter().collect();
+                let v = printable(self.archivo.buffer.line_string(i as usize));
                 let sp: Vec<_> = v.split(" ").collect();
                 let mut counter = 0;
-                for (_ia, &v) in sp.iter().enumerate() {
+                for &v in sp.iter() {
                     if self.reserved.contains(&v.to_string()) {
                         wattron(self.win, COLOR_PAIR(5) | A_BOLD());
-                        mvwprintw(self.win, (_idx + 1) as i32, counter + START_X, v);
+                        mvwaddstr(self.win, (_idx + 1) as i32, counter + START_X, v);
                         wattroff(self.win, COLOR_PAIR(5) | A_BOLD());
                     } else {
-                        mvwprintw(self.win, (_idx + 1) as i32, counter + START_X, v);
+                        mvwaddstr(self.win, (_idx + 1) as i32, counter + START_X, v);
                     }
                     counter += v.len() as i32 + 1;
                     let f = format!("{}", i);
-                    mvwprintw(self.win, (_idx + 1) as i32, 1, &f);
+                    mvwaddstr(self.win, (_idx + 1) as i32, 1, &f);
                 }
             }
         }
 
+        self.display_matches();
+        if let Some(visual) = self.visual {
+            let (first, last) = visual.lines();
+            let top = self.start.max(0) as usize;
+            for y in first.max(top)..=last.min(top + self.end.max(0) as usize) {
+                if let Some((col, len)) = visual.span(&self.archivo.buffer, y) {
+                    self.display_span(y, col, len, 1);
+                }
+            }
+        }
+        if let Some((y, col, len)) = self.current_match {
+            self.display_span(y, col, len.max(1), 7);
+        }
         self.display_bar();
+        if let Some(prompt) = &self.prompt {
+            let text = match self.mode {
+                Mode::Search if prompt.forward => "/",
+                Mode::Search => "?",
+                _ => ":",
+            };
+            self.display_cmdline(text, &prompt.line);
+        }
+        wrefresh(self.win);
+    }
+
+    /// Draws the matches of the search pattern over the visible lines.
+    fn display_matches(&self) {
+        let Some(re) = &self.highlight else {
+            return;
+        };
+        let rows = self.start..(self.start + self.end).min(self.archivo.buffer.len_lines() as i32);
+        for (row, y) in rows.enumerate() {
+            let line = self.archivo.buffer.line_string(y as usize);
+            let shown: Vec<char> = printable(line.clone()).chars().collect();
+            wattron(self.win, COLOR_PAIR(6));
+            for (col, len) in search::matches(re, &line) {
+                let x = START_X + col as i32;
+                if len == 0 || x >= self.w {
+                    continue;
+                }
+                let end = (col + len).min(col + (self.w - x) as usize);
+                let text: String = shown[col..end].iter().collect();
+                mvwaddstr(self.win, row as i32 + START_Y, x, &text);
+            }
+            wattroff(self.win, COLOR_PAIR(6));
+        }
+    }
+
+    /// Redraws `len` characters of line `y` from `col` with a color pair, if
+    /// they are on screen.
+    fn display_span(&self, y: usize, col: usize, len: usize, pair: i16) {
+        let row = y as i32 - self.start;
+        let x = START_X + col as i32;
+        if row < 0 || row >= self.end || x >= self.w {
+            return;
+        }
+        let line: Vec<char> = printable(self.archivo.buffer.line_string(y))
+            .chars()
+            .chain(std::iter::repeat(' '))
+            .take(col + len)
+            .collect();
+        let end = (col + len).min(col + (self.w - x) as usize);
+        let text: String = line[col..end].iter().collect();
+        wattron(self.win, COLOR_PAIR(pair));
+        mvwaddstr(self.win, row + START_Y, x, &text);
+        wattroff(self.win, COLOR_PAIR(pair));
+    }
+
+    fn display_msg(&self) {
+        wmove(self.win, self.h - 2, 0);
+        wclrtoeol(self.win);
+        self.msg.display(1, self.h - 2);
+    }
+
+    /// Shows a list over the bottom of the editor until a key is pressed.
+    /// Lists taller than the window are shown a page at a time.
+    fn display_list(&mut self, lines: &[String]) {
+        let rows = (self.h - 3).max(1) as usize;
+        for (n, page) in lines.chunks(rows).enumerate() {
+            let top = self.h - 2 - page.len() as i32;
+            if n > 0 {
+                wclear(self.win);
+            }
+            for (i, line) in page.iter().enumerate() {
+                let y = top + i as i32;
+                wmove(self.win, y, 0);
+                wclrtoeol(self.win);
+                mvwaddstr(self.win, y, 1, &printable(line.clone()));
+            }
+            let last = (n + 1) * rows >= lines.len();
+            wmove(self.win, self.h - 2, 0);
+            wclrtoeol(self.win);
+            mvwaddstr(
+                self.win,
+                self.h - 2,
+                1,
+                if last {
+                    "Press any key to continue"
+                } else {
+                    "-- More -- (q to stop)"
+                },
+            );
+            wrefresh(self.win);
+            let ch = self.getch();
+            if last || ch == 113 || ch == 27 {
+                break;
+            }
+        }
+        wclear(self.win);
+    }
+
+    /// Shows `question` on the message line and returns the key pressed.
+    fn ask(&mut self, question: &str) -> i32 {
+        self.msg.update(question, MsgType::Error);
+        self.display_msg();
         wrefresh(self.win);
+        let ch = self.getch();
+        self.msg.update("", MsgType::Info);
+        ch
     }
 
     pub fn display_bar(&self) {
-        let metadata = self.archivo.file.metadata().unwrap();
-        let per = format_permissions(metadata.permissions(), false);
+        let (per, len) = match self.archivo.metadata() {
+            Some(metadata) => (
+                format_permissions(metadata.permissions(), false),
+                metadata.len(),
+            ),
+            None => ("[New]".to_string(), 0),
+        };
 
-        let file = self.archivo.path.split('/').last().unwrap();
-        let lenguaje = file.split('.').last().unwrap();
+        let file = self.archivo.path.split('/').next_back().unwrap();
+        let lenguaje = file.split('.').next_back().unwrap();
         let lang = obtener_nombre_lenguaje(lenguaje).unwrap();
 
-        let fmt_right = format!("{} {}KB", per, metadata.len());
+        let recording = match &self.macro_keys {
+            Some((name, _)) => format!("  recording @{}", name),
+            None => String::new(),
+        };
+        let fmt_right = format!("{} {}KB{}", per, len, recording);
         let fmt_left = format!(
-            "UNIX  UTF-8  {:?}  {}:{}",
+            "{}{}  {}{}  {:?}  {}:{}",
+            self.archivo.line_ending.name().to_uppercase(),
+            if self.archivo.eol { "" } else { " [noeol]" },
+            self.archivo.encoding.name(),
+            if self.archivo.bom { " BOM" } else { "" },
             lang,
             self.idx_y,
-            self.archivo.buffer.len()
+            self.archivo.buffer.len_lines()
         );
 
         let x = getmaxx(self.win);
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
         mvwhline(self.win, self.h - 3, 1, 32, x - 2);
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
-        if !self.mode {
-            wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-            mvwprintw(self.win, self.h - 3, 2, "NORMAL");
-            wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
-        } else {
-            wattron(self.win, COLOR_PAIR(1) | A_BOLD());
-            mvwprintw(self.win, self.h - 3, 2, "INSERT");
-            wattroff(self.win, COLOR_PAIR(1) | A_BOLD());
-        }
+        self.display_mode();
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-        mvwprintw(self.win, self.h - 3, 10, &fmt_right);
-        mvwprintw(
+        mvwaddstr(self.win, self.h - 3, 13, &fmt_right);
+        mvwaddstr(
             self.win,
             self.h - 3,
             self.w - fmt_left.len() as i32 - 10,
             &fmt_left,
         );
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
+        self.display_msg();
         wmove(self.win, self.y, self.x);
         wrefresh(self.win);
     }
 
+    /// The label of the mode, at the left of the status bar.
+    fn display_mode(&self) {
+        let pair = if self.mode == Mode::Normal { 2 } else { 1 };
+        wattron(self.win, COLOR_PAIR(pair) | A_BOLD());
+        mvwaddstr(self.win, self.h - 3, 2, self.mode.label());
+        wattroff(self.win, COLOR_PAIR(pair) | A_BOLD());
+    }
+
     pub fn display_bar_debug(&self) {
-        let metadata = self.archivo.file.metadata().unwrap();
-        let per = format_permissions(metadata.permissions(), false);
+        let (per, len) = match self.archivo.metadata() {
+            Some(metadata) => (
+                format_permissions(metadata.permissions(), false),
+                metadata.len(),
+            ),
+            None => ("[New]".to_string(), 0),
+        };
 
-        let file = self.archivo.path.split('/').last().unwrap();
-        let lenguaje = file.split('.').last().unwrap();
+        let file = self.archivo.path.split('/').next_back().unwrap();
+        let lenguaje = file.split('.').next_back().unwrap();
         let lang = obtener_nombre_lenguaje(lenguaje).unwrap();
 
         let format = format!(
             "{:?} {}  {}KB  {}:{}  x:{} y:{} realx:{}   realy:{}",
             lang,
             per,
-            metadata.len(),
+            len,
             self.idx_y,
-            self.archivo.buffer.len(),
+            self.archivo.buffer.len_lines(),
             self.x,
             self.y,
             self.idx_x,
@@ -236,17 +774,9 @@ impl State {
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
         mvwhline(self.win, self.h - 3, 1, 32, x - 2);
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
-        if !self.mode {
-            wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-            mvwprintw(self.win, self.h - 3, 2, "NORMAL");
-            wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
-        } else {
-            wattron(self.win, COLOR_PAIR(1) | A_BOLD());
-            mvwprintw(self.win, self.h - 3, 2, "INSERT");
-            wattroff(self.win, COLOR_PAIR(1) | A_BOLD());
-        }
+        self.display_mode();
         wattron(self.win, COLOR_PAIR(2) | A_BOLD());
-        mvwprintw(self.win, self.h - 3, 10, &format);
+        mvwaddstr(self.win, self.h - 3, 13, &format);
         wattroff(self.win, COLOR_PAIR(2) | A_BOLD());
         wmove(self.win, self.y, self.x);
         wrefresh(self.win);
@@ -255,448 +785,2498 @@ impl State {
     pub fn update(&mut self) {
         keypad(self.win, true);
 
-        let mut ch = wgetch(self.win);
-        while ch != 113 {
-            if self.mode {
-                self.handle_insert(&mut ch);
-            }
-            match ch {
-                100 => {
-                    self.handle_delete(&mut ch);
-                }
-                106 => {
-                    self.handle_movment_down();
-                }
-                107 => {
-                    self.handle_movment_up();
-                }
-                104 => {
-                    self.handle_movment_left();
-                }
-                108 => {
-                    self.handle_movment_right();
-                }
-                98 => {
-                    self.handle_start_line();
-                }
-                32 => {
-                    self.explorer.display();
-                    let a = self.explorer.update();
-                    wclear(self.win);
-                    match a {
-                        Some(str) => {
-                            delwin(self.win);
-                            *self = State::new(&str);
-                            endwin();
-                            self.display();
-                            self.update();
-                            break;
-                        }
-                        None => self.x += 0,
-                    };
-                }
-                9 | 11 => {
-                    self.x += 0;
-                }
-                118 => {
-                    self.handle_v();
-                }
-                KEY_LEFT => {
-                    self.handle_movment_left();
-                }
-                KEY_RIGHT => {
-                    self.handle_movment_right();
-                }
-                KEY_UP => {
-                    self.handle_movment_up();
-                }
-                KEY_DOWN => {
-                    self.handle_movment_up();
-                }
-                KEY_ENTER | 10 => {
-                    self.handle_enter();
-                }
-                111 => {
-                    self.handle_new_line();
-                }
-                103 => {
-                    self.handle_save();
-                }
-                58 => {
-                    self.handle_command(&mut ch);
-                }
-                105 => {
-                    self.handle_insert(&mut ch);
-                }
-                _ => {
-                    self.x += 0;
-                }
+        let mut ch = self.read_key();
+        while !self.quit {
+            if self.mode == Mode::Normal && self.check_disk() {
+                self.display();
+                ch = self.read_key();
+                continue;
+            }
+            self.handle_key(ch);
+            if self.quit {
+                break;
             }
             self.display();
-            ch = wgetch(self.win);
+            ch = self.read_key();
         }
 
+        self.close_swap();
         endwin();
     }
 
-    fn handle_v(&mut self) {
-        self.idx_x = 0;
-        self.x = START_X;
-        self.idx_y = 0;
-        self.y = START_Y;
-        self.start = 0;
-        wclear(self.win);
-    }
-
-    //H
-    fn handle_movment_left(&mut self) {
-        if self.x > START_X {
-            self.x -= 1;
-            self.idx_x -= 1;
+    /// Hands a key to the current mode. The keys from NORMAL mode until
+    /// it is back are kept for `.` when they changed the buffer.
+    fn handle_key(&mut self, ch: i32) {
+        if self.mode == Mode::Normal {
+            self.recording = Some((self.archivo.tick, Vec::new()));
         }
-    }
-    //L
-    fn handle_movment_right(&mut self) {
-        if self.x > self.x - 2 && self.x - START_X < self.archivo.buffer[self.idx_y].len() as i32 {
-            self.x += 1;
-            self.idx_x += 1;
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(ch);
         }
-    }
-    //K
-    fn handle_movment_up(&mut self) {
-        if self.y > START_Y {
-            self.y -= 1;
-            self.idx_y -= 1;
-            self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-            self.idx_x = self.archivo.buffer[self.idx_y].len();
-        } else if self.start > 0 {
-            self.start -= 1;
-            self.idx_y -= 1;
-            self.idx_x = self.archivo.buffer[self.idx_y].len();
-            self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-            wclear(self.win);
+        match self.mode {
+            Mode::Normal => self.normal_key(ch),
+            Mode::Insert | Mode::Replace => self.insert_key(ch),
+            Mode::Visual(_) => self.visual_key(ch),
+            Mode::OperatorPending => self.operator_key(ch),
+            Mode::CommandLine | Mode::Search => self.prompt_key(ch),
         }
-    }
-    //J
-    fn handle_movment_down(&mut self) {
-        if self.y <= self.h - 6 && self.idx_y < self.archivo.buffer.len() - 1 {
-            self.y += 1;
-            self.idx_y += 1;
-            self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-            self.idx_x = self.archivo.buffer[self.idx_y].len();
-        } else if self.idx_y < self.archivo.buffer.len() - 1 {
-            self.start += 1;
-            self.idx_y += 1;
-            self.idx_x = self.archivo.buffer[self.idx_y].len();
-            self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-            wclear(self.win);
+        if self.mode != Mode::Normal {
+            return;
         }
-    }
-
-    fn handle_save(&mut self) {
-        let str: String;
-        let a = self.archivo.save();
-        let metadata = self.archivo.file.metadata().unwrap();
-        match a {
-            Ok(_) => {
-                str = format!(
-                    "{} {}L {}B written",
-                    self.archivo.path,
-                    self.archivo.buffer.len(),
-                    metadata.len()
-                )
+        // Commands run by this one, like `.` and `:normal`, have taken
+        // the recording already.
+        if let Some((tick, keys)) = self.recording.take() {
+            if tick != self.archivo.tick {
+                if let Some(change) = Repeat::from_keys(&keys) {
+                    self.last_change = Some(change);
+                }
             }
-            Err(err) => str = format!("Nothing updated due to error {}", err,),
         }
-
-        mvwprintw(self.win, self.h - 2, 2, &str);
-        wrefresh(self.win);
-    }
-    //B
-    fn handle_start_line(&mut self) {
-        self.idx_x = 0;
-        self.x = START_X;
     }
 
-    fn handle_enter(&mut self) {
-        self.archivo
-            .buffer
-            .insert(self.idx_y + 1, Vec::<char>::new());
-        self.idx_y += 1;
-        self.idx_x = 0;
-        self.x = START_X;
-
-        if self.idx_y < self.archivo.buffer.len() && self.archivo.buffer.len() as i32 > self.h {
-            self.start += 1;
+    /// Runs the NORMAL mode command that starts with `ch`.
+    /// Commands are `[count] operator [count] motion`, `[count] motion` or
+    /// one of the other commands.
+    fn normal_key(&mut self, mut ch: i32) {
+        let mut count = self.read_count(&mut ch);
+        if !self.read_register(&mut ch, &mut count) {
+            return;
+        }
+        // `g` starts commands of two keys.
+        let g = ch == 103;
+        let key = if g { self.getch() } else { ch };
+        let operator = if g {
+            Operator::from_g_key(key)
         } else {
-            self.y += 1;
+            Operator::from_key(key)
+        };
+        if let Some(op) = operator {
+            self.pending = Some((op, count));
+            self.mode = Mode::OperatorPending;
+            return;
         }
-        wclear(self.win);
-    }
-
-    fn handle_new_line(&mut self) {
-        let mut v: Vec<char> = Vec::new();
-        for i in self.archivo.buffer[self.idx_y].iter() {
-            if *i == ' ' {
-                v.push(*i);
-            } else {
-                break;
-            }
+        if let Some(motion) = self.read_motion(g, key, count) {
+            self.handle_motion(motion, count);
+            return;
         }
-
-        match self.archivo.buffer[self.idx_y].last().unwrap() {
-            '{' | '(' | ':' => {
-                for _ in 0..4 {
-                    v.push(' ');
+        if g {
+            self.handle_g(key);
+            return;
+        }
+        match ch {
+            // q
+            113 => {
+                self.handle_record();
+            }
+            // @
+            64 => {
+                self.handle_macro(count);
+            }
+            // ZZ and ZQ
+            90 => match self.getch() {
+                90 => self.run_command("x"),
+                81 => self.run_command("q!"),
+                _ => (),
+            },
+            // p and P
+            112 | 80 => {
+                self.handle_put(ch == 80, count);
+            }
+            // Ctrl-D, Ctrl-U, Ctrl-F and Ctrl-B
+            4 | 21 | 6 | 2 => {
+                self.handle_scroll(ch, count);
+            }
+            32 => {
+                self.explorer.display();
+                let a = self.explorer.update();
+                wclear(self.win);
+                match a {
+                    Some(str) if self.handle_leave() => self.edit_file(&str, None),
+                    _ => self.x += 0,
+                };
+            }
+            9 | 11 => {
+                self.x += 0;
+            }
+            // v, V and Ctrl-V
+            118 | 86 | 22 => {
+                let kind = VisualKind::from_key(ch).unwrap();
+                self.start_visual(Visual::new(kind, (self.idx_y, self.idx_x)));
+            }
+            KEY_ENTER | 10 => {
+                self.handle_enter();
+            }
+            111 => {
+                self.handle_new_line();
+            }
+            58 => {
+                self.open_prompt(Mode::CommandLine, true);
+            }
+            117 => {
+                self.handle_undo();
+            }
+            // Ctrl-R
+            18 => {
+                self.handle_redo();
+            }
+            105 => {
+                self.start_insert();
+            }
+            // R
+            82 => {
+                self.start_insert();
+                self.mode = Mode::Replace;
+                self.replaced.clear();
+            }
+            // r
+            114 => {
+                self.handle_replace_char(count);
+            }
+            // x
+            120 => {
+                self.handle_operator(Operator::Delete, count, 108);
+            }
+            // .
+            46 => {
+                if let Some(change) = &self.last_change {
+                    self.run_keys(change.keys(count));
                 }
             }
-            _ => (),
+            // /
+            47 => {
+                self.open_prompt(Mode::Search, true);
+            }
+            // ?
+            63 => {
+                self.open_prompt(Mode::Search, false);
+            }
+            // n and N
+            110 => {
+                self.handle_search_next(false);
+            }
+            78 => {
+                self.handle_search_next(true);
+            }
+            // * and #
+            42 => {
+                self.handle_search_word(true);
+            }
+            35 => {
+                self.handle_search_word(false);
+            }
+            109 => {
+                self.handle_mark();
+            }
+            // ' and `
+            39 | 96 => {
+                self.handle_jump_mark(ch);
+            }
+            _ => {
+                self.x += 0;
+            }
         }
+    }
 
-        self.archivo
-            .buffer
-            .insert(self.idx_y + 1, Vec::<char>::from(v.clone()));
-        self.idx_y += 1;
-        let l = v.len();
-        self.idx_x = l;
-        self.x = START_X + l as i32;
+    /// `"x` picks the register for the command that follows, which may
+    /// have a count of its own. Returns false when the name isn't one.
+    fn read_register(&mut self, ch: &mut i32, count: &mut Option<usize>) -> bool {
+        self.register = None;
+        if *ch != 34 {
+            return true;
+        }
+        let name = self.getch();
+        match char::from_u32(name as u32).filter(|c| Registers::valid(*c)) {
+            Some(name) => self.register = Some(name),
+            None => return false,
+        }
+        *ch = self.getch();
+        if let Some(more) = self.read_count(ch) {
+            *count = Some(count.unwrap_or(1).saturating_mul(more));
+        }
+        true
+    }
 
-        if self.idx_y < self.archivo.buffer.len() - 1 && self.archivo.buffer.len() as i32 > self.h {
-            self.start += 1;
-        } else {
-            self.y += 1;
+    /// Starts VISUAL mode with `visual` selected.
+    fn start_visual(&mut self, visual: Visual) {
+        self.goto(visual.cursor.0, visual.cursor.1);
+        if visual.to_end {
+            self.curswant = usize::MAX;
         }
-        self.mode = true;
-        wclear(self.win);
+        self.mode = Mode::Visual(visual.kind);
+        self.visual = Some(visual);
+        self.last_visual = Some(visual);
+    }
+
+    /// Ends VISUAL mode, leaving the `'<` and `'>` marks on the selection.
+    fn stop_visual(&mut self) {
+        if let Some(visual) = self.visual.take() {
+            let (start, end) = visual.marks();
+            self.archivo.marks.insert('<', start);
+            self.archivo.marks.insert('>', end);
+        }
+        self.mode = Mode::Normal;
     }
 
-    fn handle_delete(&mut self, ch: &mut i32) {
-        *ch = wgetch(self.win);
-        if *ch == 100 {
-            if self.archivo.buffer.len() > 1 {
-                if self.idx_y < 1 {
-                    if !self.archivo.buffer[self.idx_y].is_empty() {
-                        self.archivo.buffer[self.idx_y].clear();
+    /// VISUAL mode. Motions and text objects move the end of the selection
+    /// at the cursor, and an operator works on the selection and ends it.
+    fn visual_key(&mut self, mut ch: i32) {
+        let Some(mut visual) = self.visual else {
+            self.stop_visual();
+            return;
+        };
+        let mut count = self.read_count(&mut ch);
+        if !self.read_register(&mut ch, &mut count) {
+            return;
+        }
+        let g = ch == 103;
+        let key = if g { self.getch() } else { ch };
+        let operator = match key {
+            _ if g => Operator::from_g_key(key),
+            // x s ~ u U
+            120 => Some(Operator::Delete),
+            115 => Some(Operator::Change),
+            126 => Some(Operator::ToggleCase),
+            117 => Some(Operator::Lowercase),
+            85 => Some(Operator::Uppercase),
+            _ => Operator::from_key(key),
+        };
+        if let Some(op) = operator {
+            self.stop_visual();
+            self.apply_visual(op, &visual);
+            return;
+        }
+        if let Some(motion) = self.read_motion(g, key, count) {
+            self.handle_motion(motion, count);
+        } else if !g {
+            match key {
+                27 => {
+                    self.stop_visual();
+                    return;
+                }
+                // v, V and Ctrl-V change the kind of selection, or end it.
+                118 | 86 | 22 => {
+                    let kind = VisualKind::from_key(key).unwrap();
+                    if kind == visual.kind {
+                        self.stop_visual();
+                        return;
+                    }
+                    visual.kind = kind;
+                }
+                // o goes to the other end; O to the other side of a block.
+                111 | 79 => {
+                    let (y, x) = visual.anchor;
+                    if key == 79 && visual.kind == VisualKind::Block {
+                        visual.anchor.1 = visual.cursor.1;
+                        self.goto(self.idx_y, x);
                     } else {
-                        self.archivo.buffer.remove(self.idx_y + 1);
+                        visual.anchor = visual.cursor;
+                        self.goto(y, x);
                     }
-                    self.x = START_X;
-                    self.idx_x = 0;
-                } else {
-                    self.archivo.buffer.remove(self.idx_y);
-                    self.idx_y -= 1;
-                    self.y -= 1;
-                    self.x = START_X;
-                    self.idx_x = 0;
                 }
+                // i and a
+                105 | 97 => {
+                    let object = self.getch();
+                    let html = self.lang == Lenguaje::Html;
+                    let buffer = &self.archivo.buffer;
+                    let region = TextObject::from_key(object, html).and_then(|object| {
+                        object.select(buffer, visual.cursor, count.unwrap_or(1), key == 97)
+                    });
+                    if let Some(region) = region {
+                        let end = if region.linewise {
+                            (region.end.0, 0)
+                        } else if region.end == region.start {
+                            region.end
+                        } else {
+                            motion::prev(buffer, region.end).unwrap_or(region.start)
+                        };
+                        if region.linewise && visual.kind == VisualKind::Char {
+                            visual.kind = VisualKind::Line;
+                        }
+                        visual.anchor = region.start;
+                        self.goto(end.0, end.1);
+                    }
+                }
+                // J
+                74 => {
+                    self.stop_visual();
+                    let (first, last) = visual.lines();
+                    let last = last.max(first + 1).min(self.archivo.buffer.len_lines() - 1);
+                    self.handle_join_lines((first, last), false);
+                    return;
+                }
+                // I and A
+                73 | 65 => {
+                    self.stop_visual();
+                    self.visual_insert(&visual, key == 65);
+                    return;
+                }
+                // : works on the selected lines.
+                58 => {
+                    self.stop_visual();
+                    self.open_prompt(Mode::CommandLine, true);
+                    if let Some(prompt) = &mut self.prompt {
+                        prompt.line.set_text("'<,'>");
+                    }
+                    return;
+                }
+                _ => (),
             }
-            wclear(self.win);
         }
-        // a -> ALL
-        if *ch == 97 {
-            self.archivo.buffer.clear();
-            self.archivo.buffer.push(Vec::<char>::new());
-            self.idx_y = 0;
-            self.y = START_Y;
-            self.x = START_X;
-            self.idx_x = 0;
-            wclear(self.win);
+        visual.cursor = (self.idx_y, self.idx_x);
+        if visual.kind == VisualKind::Block {
+            visual.to_end = self.curswant == usize::MAX;
         }
+        self.mode = Mode::Visual(visual.kind);
+        self.visual = Some(visual);
+        self.last_visual = Some(visual);
     }
 
-    fn handle_command(&mut self, ch: &mut i32) {
-        mvwprintw(self.win, self.h - 2, 1, "Enter a command: ");
-        wrefresh(self.win);
-        *ch = wgetch(self.win);
-        let mut ty: char;
-        if *ch == 27 {
-            wclear(self.win);
-            self.display();
+    /// Applies an operator to the selection. On a block each line is
+    /// changed on its own.
+    fn apply_visual(&mut self, op: Operator, visual: &Visual) {
+        if visual.kind != VisualKind::Block {
+            let region = visual.region(&self.archivo.buffer);
+            self.goto(region.start.0, region.start.1);
+            self.apply_operator(op, region);
+            return;
+        }
+        let (first, last) = visual.lines();
+        let (left, _) = visual.columns();
+        let regions = visual.block_regions(&self.archivo.buffer);
+        match op {
+            Operator::Yank => {
+                let text = regions
+                    .iter()
+                    .map(|region| region.text(&self.archivo.buffer).concat())
+                    .collect();
+                self.store_register(
+                    self.register,
+                    Register {
+                        text,
+                        linewise: false,
+                        blockwise: true,
+                    },
+                    false,
+                );
+                self.goto(first, left);
+            }
+            Operator::Delete | Operator::Change => {
+                self.archivo.hold_changes(self.idx_y, self.idx_x);
+                let text = regions
+                    .iter()
+                    .map(|region| self.archivo.remove_region(region).concat())
+                    .collect();
+                self.store_register(
+                    self.register,
+                    Register {
+                        text,
+                        linewise: false,
+                        blockwise: true,
+                    },
+                    true,
+                );
+                if op == Operator::Change {
+                    self.block_insert((first, last), left, false);
+                } else {
+                    self.goto(first, left);
+                }
+                self.archivo.release_changes();
+            }
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                self.archivo.hold_changes(self.idx_y, self.idx_x);
+                for region in &regions {
+                    self.change_case(region, op);
+                }
+                self.archivo.release_changes();
+                self.goto(first, left);
+            }
+            Operator::ShiftRight | Operator::ShiftLeft | Operator::Indent => {
+                let region = Region {
+                    start: (first, 0),
+                    end: (last, self.archivo.buffer.line_len(last)),
+                    linewise: true,
+                };
+                self.apply_operator(op, region);
+            }
+        }
+    }
+
+    /// `I` and `A` insert before or after the selection. On a block the
+    /// text is typed on its first line and then put on every other line.
+    fn visual_insert(&mut self, visual: &Visual, append: bool) {
+        let (first, last) = visual.lines();
+        let buffer = &self.archivo.buffer;
+        let (y, x) = match visual.kind {
+            VisualKind::Block => {
+                let (left, right) = visual.columns();
+                if !append {
+                    self.block_insert((first, last), left, false);
+                } else if visual.to_end {
+                    self.block_insert((first, last), usize::MAX, true);
+                } else {
+                    self.block_insert((first, last), right + 1, true);
+                }
+                return;
+            }
+            VisualKind::Line if append => (last, buffer.line_len(last)),
+            VisualKind::Line => (first, self.first_non_blank(first)),
+            VisualKind::Char => {
+                let region = visual.region(buffer);
+                if append {
+                    region.end
+                } else {
+                    region.start
+                }
+            }
+        };
+        self.goto(y, x);
+        self.start_insert();
+    }
+
+    /// Insert mode at column `col` of the first line of a block, then what
+    /// was typed goes to the same column of the other lines. Lines too
+    /// short for it are skipped, or padded with spaces when `pad`. A `col`
+    /// past the end is the end of each line.
+    fn block_insert(&mut self, (first, last): (usize, usize), col: usize, pad: bool) {
+        self.archivo.hold_changes(self.idx_y, self.idx_x);
+        let len = self.archivo.buffer.line_len(first);
+        let at = if col == usize::MAX { len } else { col };
+        if pad && len < at {
+            self.archivo.insert_text(first, len, &vec![' '; at - len]);
+        }
+        self.block = Some(BlockInsert {
+            first,
+            last,
+            col,
+            pad,
+            at,
+            before: (
+                self.archivo.buffer.line_len(first),
+                self.archivo.buffer.len_lines(),
+            ),
+        });
+        self.goto(first, at);
+        self.start_insert();
+    }
+
+    /// Puts what was typed in a block insert on the other lines. Text
+    /// typed over more than one line is not repeated.
+    fn finish_block_insert(&mut self, block: BlockInsert) {
+        let (before, lines) = block.before;
+        let (first, at) = (block.first, block.at);
+        let after = self.archivo.buffer.line_len(first);
+        if self.archivo.buffer.len_lines() == lines && after > before && at <= before {
+            let text = self.archivo.buffer.line(first)[at..at + after - before].to_vec();
+            for y in first + 1..=block.last {
+                let len = self.archivo.buffer.line_len(y);
+                let at = if block.col == usize::MAX {
+                    len
+                } else {
+                    block.col
+                };
+                if len < at && !block.pad {
+                    continue;
+                }
+                if len < at {
+                    self.archivo.insert_text(y, len, &vec![' '; at - len]);
+                }
+                self.archivo.insert_text(y, at, &text);
+            }
+        }
+        self.archivo.release_changes();
+        self.goto(first, at);
+    }
+
+    /// Moves the cursor to a buffer position, scrolling the viewport if needed.
+    fn goto(&mut self, idx_y: usize, idx_x: usize) {
+        let rows = self.h - 5;
+        self.idx_y = idx_y.min(self.archivo.buffer.len_lines() - 1);
+        self.idx_x = idx_x.min(self.archivo.buffer.line_len(self.idx_y));
+        if (self.idx_y as i32) < self.start {
+            self.start = self.idx_y as i32;
+        } else if self.idx_y as i32 >= self.start + rows {
+            self.start = self.idx_y as i32 - rows + 1;
+        }
+        self.y = self.idx_y as i32 - self.start + START_Y;
+        self.x = self.idx_x as i32 + START_X;
+        self.curswant = self.idx_x;
+        wclear(self.win);
+    }
+
+    /// Reads the count typed before a command. `0` only counts after
+    /// another digit, on its own it is a motion. Like vim, counts stop
+    /// growing at `MAX_COUNT`.
+    fn read_count(&mut self, ch: &mut i32) -> Option<usize> {
+        let mut count: Option<usize> = None;
+        while (49..=57).contains(ch) || (count.is_some() && *ch == 48) {
+            let digit = (*ch - 48) as usize;
+            count = Some((count.unwrap_or(0) * 10 + digit).min(MAX_COUNT));
+            *ch = self.getch();
+        }
+        count
+    }
+
+    fn motion_context(&self, count: Option<usize>, operator: bool) -> Context {
+        Context {
+            cursor: (self.idx_y, self.idx_x),
+            want: self.curswant,
+            count,
+            operator,
+            top: self.start.max(0) as usize,
+            rows: (self.h - 5).max(1) as usize,
+        }
+    }
+
+    /// The motion typed as `key`, after a `g` if `g` is set. `f` and `t`
+    /// read the character to find and are remembered for `;` and `,`.
+    fn read_motion(&mut self, g: bool, key: i32, count: Option<usize>) -> Option<Motion> {
+        if g {
+            return Motion::from_g_key(key);
+        }
+        match key {
+            // f F t T
+            102 | 70 | 116 | 84 => {
+                let ch = typed_char(self.getch())?;
+                let motion = Motion::Find {
+                    ch,
+                    forward: key == 102 || key == 116,
+                    till: key == 116 || key == 84,
+                    repeat: false,
+                };
+                self.last_find = Some(motion);
+                Some(motion)
+            }
+            // ; and ,
+            59 | 44 => self.last_find.map(|find| find.repeat(key == 44)),
+            37 if count.is_some() => Some(Motion::Percent),
+            _ => Motion::from_key(key),
+        }
+    }
+
+    /// Ctrl-D and Ctrl-U scroll half a screen, or `count` lines; Ctrl-F
+    /// and Ctrl-B `count` screens, keeping two lines of the last one. The
+    /// cursor moves along and stays on screen.
+    fn handle_scroll(&mut self, key: i32, count: Option<usize>) {
+        let rows = (self.h - 5).max(1) as usize;
+        let last = self.archivo.buffer.len_lines() - 1;
+        let top = self.start.max(0) as usize;
+        let (top, y) = match key {
+            // Ctrl-D
+            4 => {
+                if self.idx_y == last {
+                    return;
+                }
+                let n = count.unwrap_or(rows / 2).max(1);
+                let top = (top + n).min(last.saturating_sub(rows - 1)).max(top);
+                (top, (self.idx_y + n).min(last))
+            }
+            // Ctrl-U
+            21 => {
+                if self.idx_y == 0 {
+                    return;
+                }
+                let n = count.unwrap_or(rows / 2).max(1);
+                (top.saturating_sub(n), self.idx_y.saturating_sub(n))
+            }
+            // Ctrl-F
+            6 => {
+                let n = count.unwrap_or(1) * rows.saturating_sub(2).max(1);
+                let top = (top + n).min(last);
+                (top, self.idx_y.max(top))
+            }
+            // Ctrl-B
+            _ => {
+                let n = count.unwrap_or(1) * rows.saturating_sub(2).max(1);
+                let top = top.saturating_sub(n);
+                (top, self.idx_y.min(top + rows - 1))
+            }
+        };
+        self.start = top as i32;
+        self.goto(y, self.first_non_blank(y));
+    }
+
+    fn handle_motion(&mut self, motion: Motion, count: Option<usize>) {
+        let ctx = self.motion_context(count, false);
+        if let Some((y, x)) = motion.apply(&self.archivo.buffer, ctx) {
+            self.goto(y, x);
+            match motion {
+                Motion::Up | Motion::Down => self.curswant = ctx.want,
+                Motion::LineEnd => self.curswant = usize::MAX,
+                _ => (),
+            }
+        }
+    }
+
+    /// OPERATOR-PENDING mode, after an operator: `ch` starts its motion.
+    fn operator_key(&mut self, ch: i32) {
+        self.mode = Mode::Normal;
+        if let Some((op, count)) = self.pending.take() {
+            self.handle_operator(op, count, ch);
+        }
+    }
+
+    /// Reads what an operator works on, starting with `ch`, and applies it.
+    /// The operator typed again (`dd`, `gUU`) works on `count` lines. Esc
+    /// or a key that isn't a motion cancels it.
+    fn handle_operator(&mut self, op: Operator, count: Option<usize>, mut ch: i32) {
+        let count = match (count, self.read_count(&mut ch)) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+        };
+        let g = ch == 103;
+        let key = if g { self.getch() } else { ch };
+        let cursor = (self.idx_y, self.idx_x);
+        let buffer = &self.archivo.buffer;
+        let region = if key == op.line_key() && (!g || op.g_prefixed()) {
+            let last = self
+                .idx_y
+                .saturating_add(count.unwrap_or(1) - 1)
+                .min(buffer.len_lines() - 1);
+            Some(Region {
+                start: (self.idx_y, 0),
+                end: (last, buffer.line_len(last)),
+                linewise: true,
+            })
+        } else if !g && (key == 105 || key == 97) {
+            // i and a
+            let object = self.getch();
+            TextObject::from_key(object, self.lang == Lenguaje::Html).and_then(|object| {
+                object.select(&self.archivo.buffer, cursor, count.unwrap_or(1), key == 97)
+            })
         } else {
-            loop {
-                ty = *ch as u8 as char;
-                match *ch {
-                    119 => {
-                        *ch = wgetch(self.win);
-                        match *ch {
-                            KEY_ENTER => {
-                                self.archivo.save().unwrap();
-                                mvwprintw(self.win, self.h - 2, 1, "ARCHIVO GURADADO");
-                                wrefresh(self.win);
-                                self.display();
-                            }
-                            _ => (),
-                        }
-                    }
-                    _ => (),
+            self.read_motion(g, key, count)
+                .and_then(|motion| self.operator_region(op, motion, count))
+        };
+        if let Some(region) = region {
+            self.apply_operator(op, region);
+        }
+    }
+
+    fn operator_region(
+        &self,
+        op: Operator,
+        motion: Motion,
+        count: Option<usize>,
+    ) -> Option<Region> {
+        let buffer = &self.archivo.buffer;
+        let cursor = (self.idx_y, self.idx_x);
+        if let (Operator::Change, Motion::WordForward { big }) = (op, motion) {
+            // Like vim, `cw` on a word leaves the white space after it, and
+            // on white space it changes a single character.
+            if motion::class(buffer.char_at(cursor.0, cursor.1), big) == 0 {
+                if count.unwrap_or(1) == 1 && cursor.1 < buffer.line_len(cursor.0) {
+                    return Some(Region {
+                        start: cursor,
+                        end: (cursor.0, cursor.1 + 1),
+                        linewise: false,
+                    });
                 }
-                if *ch != 27 {
-                    mvwprintw(self.win, self.h - 2, 18, &ty.to_string());
-                    wrefresh(self.win);
-                    *ch = wgetch(self.win);
+            } else {
+                let end = motion::change_word_end(buffer, cursor, count.unwrap_or(1), big);
+                return Some(Region::between(buffer, cursor, end, true, false));
+            }
+        }
+        let ctx = self.motion_context(count, true);
+        let to = motion.apply(buffer, ctx)?;
+        Some(Region::between(
+            buffer,
+            cursor,
+            to,
+            motion.inclusive(),
+            motion.linewise(),
+        ))
+    }
+
+    fn apply_operator(&mut self, op: Operator, region: Region) {
+        let (first, last) = region.lines();
+        let lines = last - first + 1;
+        // Linewise operators keep the column when they start on its line.
+        let start = if region.linewise {
+            (first, if first == self.idx_y { self.idx_x } else { 0 })
+        } else {
+            region.start
+        };
+        match op {
+            Operator::Delete => {
+                self.archivo.begin_change(self.idx_y, self.idx_x);
+                let text = self.archivo.remove_region(&region);
+                self.archivo.end_change();
+                self.store_register(
+                    self.register,
+                    Register {
+                        text,
+                        linewise: region.linewise,
+                        blockwise: false,
+                    },
+                    true,
+                );
+                if region.linewise {
+                    let y = first.min(self.archivo.buffer.len_lines() - 1);
+                    self.goto(y, self.first_non_blank(y));
+                    self.report(lines, "fewer lines");
                 } else {
-                    wclear(self.win);
-                    self.display();
-                    break;
+                    self.goto(region.start.0, region.start.1);
+                }
+            }
+            Operator::Yank => {
+                self.store_register(
+                    self.register,
+                    Register {
+                        text: region.text(&self.archivo.buffer),
+                        linewise: region.linewise,
+                        blockwise: false,
+                    },
+                    false,
+                );
+                self.goto(start.0, start.1);
+                if region.linewise {
+                    self.report(lines, "lines yanked");
                 }
             }
+            Operator::Change => {
+                // The change and the insert that follows undo together.
+                self.archivo.begin_change(self.idx_y, self.idx_x);
+                let text = if region.linewise {
+                    let indent: Vec<char> =
+                        self.archivo.buffer.line(first)[..self.first_non_blank(first)].to_vec();
+                    let text = self.archivo.remove_region(&region);
+                    let width = indent.len();
+                    self.archivo.insert_line(first, indent);
+                    self.goto(first, width);
+                    text
+                } else {
+                    let text = self.archivo.remove_region(&region);
+                    self.goto(region.start.0, region.start.1);
+                    text
+                };
+                self.store_register(
+                    self.register,
+                    Register {
+                        text,
+                        linewise: region.linewise,
+                        blockwise: false,
+                    },
+                    true,
+                );
+                self.start_insert();
+            }
+            Operator::ShiftRight => self.handle_shift_lines((first, last), ">"),
+            Operator::ShiftLeft => self.handle_shift_lines((first, last), "<"),
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                self.change_case(&region, op);
+                self.goto(start.0, start.1);
+                self.report(lines, "lines changed");
+            }
+            Operator::Indent => self.handle_indent_lines((first, last)),
         }
     }
 
-    //I
-    fn handle_insert(&mut self, ch: &mut i32) {
-        self.mode = true;
-        self.display_bar();
-        *ch = wgetch(self.win);
-        let mut ty: char;
-        if *ch == 27 {
-            self.mode = false;
-            self.display_bar();
+    /// `gu`, `gU` and `g~`
+    fn change_case(&mut self, region: &Region, op: Operator) {
+        let (first, last) = region.lines();
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        for y in first..=last {
+            let line = self.archivo.buffer.line(y);
+            let (from, to) = region.columns(y, line.len());
+            let old = &line[from..to];
+            let new: Vec<char> = old
+                .iter()
+                .flat_map(|c| match op {
+                    Operator::Uppercase => c.to_uppercase().collect::<Vec<_>>(),
+                    Operator::Lowercase => c.to_lowercase().collect(),
+                    _ if c.is_lowercase() => c.to_uppercase().collect(),
+                    _ => c.to_lowercase().collect(),
+                })
+                .collect();
+            if new != old {
+                self.archivo.remove_text(y, from, to - from);
+                self.archivo.insert_text(y, from, &new);
+            }
+        }
+        self.archivo.end_change();
+    }
+
+    /// `=` indents each line like the line above it, one 'shiftwidth' more
+    /// after a line that opens a block and one less for a line that closes
+    /// it. Blank lines lose their white space.
+    fn handle_indent_lines(&mut self, (first, last): (usize, usize)) {
+        let width = self.options.shiftwidth;
+        let indent_of = |line: &[char]| -> usize {
+            line.iter()
+                .take_while(|c| c.is_whitespace())
+                .map(|c| if *c == '\t' { width } else { 1 })
+                .sum()
+        };
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        for y in first..=last {
+            let line = self.archivo.buffer.line(y);
+            let blanks = self.first_non_blank(y);
+            let body = &line[blanks..];
+            let above = (0..y)
+                .rev()
+                .map(|p| self.archivo.buffer.line(p))
+                .find(|l| l.iter().any(|c| !c.is_whitespace()));
+            let mut indent = match &above {
+                Some(above) => {
+                    let opens = above
+                        .iter()
+                        .rev()
+                        .find(|c| !c.is_whitespace())
+                        .is_some_and(|c| matches!(c, '{' | '(' | '[' | ':'));
+                    indent_of(above) + if opens { width } else { 0 }
+                }
+                None => 0,
+            };
+            if body.first().is_some_and(|c| matches!(c, '}' | ')' | ']')) {
+                indent = indent.saturating_sub(width);
+            }
+            if body.is_empty() {
+                indent = 0;
+            }
+            if line[..blanks].iter().any(|c| *c != ' ') || blanks != indent {
+                self.archivo.remove_text(y, 0, blanks);
+                self.archivo.insert_text(y, 0, &vec![' '; indent]);
+            }
+        }
+        self.archivo.end_change();
+        self.goto(first, self.first_non_blank(first));
+        self.report(last - first + 1, "lines indented");
+    }
+
+    fn handle_undo(&mut self) {
+        match self.archivo.undo() {
+            Some((y, x)) => self.goto(y, x),
+            None => self.msg.update("Already at oldest change", MsgType::Error),
+        }
+    }
+
+    fn handle_redo(&mut self) {
+        match self.archivo.redo() {
+            Some((y, x)) => self.goto(y, x),
+            None => self.msg.update("Already at newest change", MsgType::Error),
+        }
+    }
+
+    /// `g` commands that aren't motions or operators.
+    fn handle_g(&mut self, ch: i32) {
+        let cur = self.archivo.history.cur;
+        match ch {
+            // gv selects what was last selected again.
+            118 => match self.last_visual {
+                Some(mut visual) => {
+                    let buffer = &self.archivo.buffer;
+                    let y = visual.anchor.0.min(buffer.len_lines() - 1);
+                    visual.anchor = (y, visual.anchor.1.min(buffer.line_len(y)));
+                    self.start_visual(visual);
+                }
+                None => self.msg.update("No previous visual area", MsgType::Error),
+            },
+            // g- / g+ walk the undo tree in the order the states were created
+            45 => {
+                if cur == 0 {
+                    self.msg.update("Already at oldest change", MsgType::Error);
+                } else if let Some((y, x)) = self.archivo.undo_to(cur - 1) {
+                    self.goto(y, x);
+                }
+            }
+            43 => {
+                if cur + 1 >= self.archivo.history.nodes.len() {
+                    self.msg.update("Already at newest change", MsgType::Error);
+                } else if let Some((y, x)) = self.archivo.undo_to(cur + 1) {
+                    self.goto(y, x);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_undolist(&mut self) {
+        let history = &self.archivo.history;
+        let leaves = history.leaves();
+        if leaves.is_empty() {
+            self.msg.update("Nothing to undo", MsgType::Info);
+            return;
+        }
+        let mut lines = vec!["number changes  when".to_string()];
+        for n in leaves {
+            let when = history::ago(history.nodes[n].time);
+            lines.push(format!("{:>6} {:>7}  {}", n, history.depth(n), when));
+        }
+        self.display_list(&lines);
+    }
+
+    /// Notices, between keys, that another program changed the file. With
+    /// 'autoread' an unmodified buffer is reloaded, otherwise the user picks.
+    /// Each new version of the file is only reported once.
+    fn check_disk(&mut self) -> bool {
+        if !self.archivo.changed_on_disk() {
+            return false;
+        }
+        let now = DiskState::read(&self.archivo.path);
+        if now == self.disk_warned {
+            return false;
+        }
+        self.disk_warned = now;
+        if now.is_none() {
+            self.msg.update(
+                format!("\"{}\" no longer available", self.archivo.path),
+                MsgType::Error,
+            );
+        } else if self.options.autoread && !self.archivo.modified() {
+            self.handle_reload_disk();
         } else {
-            loop {
-                ty = *ch as u8 as char;
-                match *ch {
-                    KEY_BACKSPACE => {
-                        if self.x > self.archivo.buffer[self.idx_y].len() as i32 {
-                            self.archivo.buffer[self.idx_y].pop();
-                            self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-                            if self.idx_x > 0 {
-                                if self.archivo.buffer[self.idx_y].iter().all(|&f| f == ' ') {
-                                    self.archivo.buffer[self.idx_y].clear();
-                                    self.idx_x = 0;
-                                    self.x = START_X
-                                } else {
-                                    self.idx_x -= 1;
-                                }
-                            } else {
-                                if self.idx_y > 0 {
-                                    self.idx_y -= 1;
-                                    self.y -= 1;
-                                    self.x = self.archivo.buffer[self.idx_y].len() as i32 + START_X;
-                                    self.idx_x = self.archivo.buffer[self.idx_y].len();
-                                }
-                            }
-                        } else {
-                            self.archivo.buffer[self.idx_y].remove(self.idx_x);
-                        }
-                        wclear(self.win);
-                        self.display();
-                    }
-                    KEY_ENTER | 10 => {
-                        let mut right: Vec<char> = Vec::new();
-                        for i in self.archivo.buffer[self.idx_y].iter() {
-                            if *i == ' ' {
-                                right.push(*i);
-                            } else {
-                                break;
-                            }
-                        }
-                        match self.archivo.buffer[self.idx_y].last().unwrap() {
-                            '{' | '(' | ':' => {
-                                for _ in 0..4 {
-                                    right.push(' ');
-                                }
-                            }
-                            _ => (),
-                        }
+            self.handle_disk_change(false);
+        }
+        true
+    }
 
-                        for i in self.idx_x..self.archivo.buffer[self.idx_y].len() {
-                            right.push(self.archivo.buffer[self.idx_y][i]);
-                        }
-                        if let Some(buffer_y) = self.archivo.buffer.get_mut(self.idx_y) {
-                            if self.idx_x < buffer_y.len() {
-                                buffer_y.truncate(self.idx_x); // Elimina todos los elementos a partir del índice self.idx_x
-                            }
-                        }
+    /// Asks what to do about a file that changed on disk. Returns whether a
+    /// pending save should go ahead and overwrite it.
+    fn handle_disk_change(&mut self, saving: bool) -> bool {
+        loop {
+            let question = if saving {
+                "File changed on disk since reading it! [r]eload, [k]eep and overwrite, [d]iff, [c]ancel"
+            } else {
+                "File changed on disk! [r]eload, [k]eep buffer, [d]iff"
+            };
+            match self.ask(question) {
+                // r
+                114 => {
+                    self.handle_reload_disk();
+                    return false;
+                }
+                // k
+                107 => return saving,
+                // d
+                100 => self.handle_diff(),
+                // c / Esc
+                99 | 27 if saving => {
+                    self.msg.update("Nothing written", MsgType::Info);
+                    return false;
+                }
+                // Esc keeps the buffer, and ends the question when keys run
+                // out in `:normal`.
+                27 => return false,
+                _ => (),
+            }
+        }
+    }
+
+    fn handle_reload_disk(&mut self) {
+        match self.archivo.reload() {
+            Ok(()) => {
+                self.disk_warned = self.archivo.disk;
+                self.goto(self.idx_y, self.idx_x);
+                self.msg
+                    .update(format!("\"{}\" reloaded", self.archivo.path), MsgType::Info);
+            }
+            Err(err) => self.msg.update(err.to_string(), MsgType::Error),
+        }
+    }
+
+    /// Shows the differences between the file on disk and the buffer.
+    fn handle_diff(&mut self) {
+        let disk = match self.archivo.disk_lines() {
+            Ok(lines) => lines,
+            Err(err) => {
+                self.msg.update(err.to_string(), MsgType::Error);
+                return;
+            }
+        };
+        let buffer: Vec<String> = (0..self.archivo.buffer.len_lines())
+            .map(|y| self.archivo.buffer.line_string(y))
+            .collect();
+        let mut lines = vec![
+            format!("--- {} (on disk)", self.archivo.path),
+            format!("+++ {} (buffer)", self.archivo.path),
+        ];
+        match diff::diff_lines(&disk, &buffer) {
+            Some(d) => lines.extend(diff::format_diff(&d, 3)),
+            None => lines.push("Too many differences to show".to_string()),
+        }
+        self.display_list(&lines);
+    }
+
+    /// Quits unless that would throw away changes; `force` is the `!` of `:q!`.
+    fn handle_quit(&mut self, force: bool) {
+        if self.archivo.modified() && !force {
+            self.msg.update(
+                "No write since last change (add ! to override)",
+                MsgType::Error,
+            );
+        } else {
+            self.quit = true;
+        }
+    }
+
+    /// Asks what to do with unsaved changes before the buffer is replaced
+    /// by another file. Returns whether it's fine to go on.
+    fn handle_leave(&mut self) -> bool {
+        if !self.archivo.modified() {
+            return true;
+        }
+        loop {
+            let question = format!(
+                "Save changes to \"{}\"? [y]es, [n]o, [c]ancel",
+                self.archivo.path
+            );
+            match self.ask(&question) {
+                // y
+                121 => return self.handle_save(false),
+                // n
+                110 => return true,
+                // c / Esc
+                99 | 27 => return false,
+                _ => (),
+            }
+        }
+    }
+
+    /// Writes the buffer, returning whether it was saved.
+    fn handle_save(&mut self, force: bool) -> bool {
+        if self.archivo.readonly && !force {
+            self.msg.update(
+                "'readonly' option is set (add ! to override)",
+                MsgType::Error,
+            );
+            return false;
+        }
+        if self.archivo.illegal && !force {
+            self.msg.update(ILLEGAL_BYTES, MsgType::Error);
+            return false;
+        }
+        if self.archivo.changed_on_disk()
+            && DiskState::read(&self.archivo.path).is_some()
+            && !self.handle_disk_change(true)
+        {
+            return false;
+        }
+        let a = self.archivo.save(&self.options);
+        let len = self.archivo.metadata().map(|m| m.len()).unwrap_or(0);
+        match a {
+            Ok(_) => {
+                self.msg.update(
+                    format!(
+                        "{} {}L {}B written",
+                        self.archivo.path,
+                        self.archivo.buffer.len_lines(),
+                        len
+                    ),
+                    MsgType::Info,
+                );
+                true
+            }
+            Err(err) => {
+                self.msg.update(
+                    format!("Nothing updated due to error {}", err),
+                    MsgType::Error,
+                );
+                false
+            }
+        }
+    }
+    //B
+    fn handle_enter(&mut self) {
+        self.archivo.insert_line(self.idx_y + 1, Vec::<char>::new());
+        self.idx_y += 1;
+        self.idx_x = 0;
+        self.x = START_X;
+
+        if self.idx_y < self.archivo.buffer.len_lines()
+            && self.archivo.buffer.len_lines() as i32 > self.h
+        {
+            self.start += 1;
+        } else {
+            self.y += 1;
+        }
+        wclear(self.win);
+    }
+
+    fn handle_new_line(&mut self) {
+        let mut v: Vec<char> = Vec::new();
+        for i in self.archivo.buffer.line(self.idx_y).iter() {
+            if *i == ' ' {
+                v.push(*i);
+            } else {
+                break;
+            }
+        }
+
+        if let Some('{' | '(' | ':') = self.archivo.buffer.line(self.idx_y).last() {
+            v.extend([' '; 4]);
+        }
+
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        self.archivo.insert_line(self.idx_y + 1, v.clone());
+        self.idx_y += 1;
+        let l = v.len();
+        self.idx_x = l;
+        self.x = START_X + l as i32;
+
+        if self.idx_y < self.archivo.buffer.len_lines() - 1
+            && self.archivo.buffer.len_lines() as i32 > self.h
+        {
+            self.start += 1;
+        } else {
+            self.y += 1;
+        }
+        self.mode = Mode::Insert;
+        wclear(self.win);
+    }
+
+    /// Starts typing a `:` command, or a search pattern for `/` (or `?`
+    /// when not `forward`).
+    fn open_prompt(&mut self, mode: Mode, forward: bool) {
+        self.prompt = Some(Prompt {
+            line: CmdLine::new(),
+            forward,
+            origin: (self.idx_y, self.idx_x, self.start),
+            highlight: self.highlight.clone(),
+        });
+        self.mode = mode;
+    }
+
+    /// COMMAND and SEARCH mode. The line ends with Enter, or is cancelled
+    /// with Esc or by backspacing over the prompt.
+    fn prompt_key(&mut self, ch: i32) {
+        let Some(mut prompt) = self.prompt.take() else {
+            self.mode = Mode::Normal;
+            return;
+        };
+        let search = self.mode == Mode::Search;
+        let event = if search {
+            prompt
+                .line
+                .handle_key(ch, &self.search_history, |_| (0, Vec::new()))
+        } else {
+            prompt
+                .line
+                .handle_key(ch, &self.cmd_history, command::complete)
+        };
+        let line = match event {
+            CmdLineEvent::Continue => {
+                if search {
+                    self.preview_search(&prompt);
+                }
+                self.prompt = Some(prompt);
+                return;
+            }
+            CmdLineEvent::Cancel => None,
+            CmdLineEvent::Done(text) => {
+                if search {
+                    self.search_history.add(&text);
+                } else {
+                    self.cmd_history.add(&text);
+                }
+                Some(text)
+            }
+        };
+        self.mode = Mode::Normal;
+        wclear(self.win);
+        if search {
+            self.start = prompt.origin.2;
+            self.goto(prompt.origin.0, prompt.origin.1);
+            self.highlight = prompt.highlight;
+            if let Some(line) = line {
+                self.handle_search(&line, prompt.forward);
+            }
+        } else if let Some(line) = line {
+            self.run_command(&line);
+        }
+    }
+
+    fn display_cmdline(&self, prompt: &str, line: &CmdLine) {
+        if let Some((_, candidates, shown)) = &line.completion {
+            wmove(self.win, self.h - 3, 0);
+            wclrtoeol(self.win);
+            wmove(self.win, self.h - 3, 1);
+            for (i, candidate) in candidates.iter().enumerate() {
+                if i == *shown {
+                    wattron(self.win, A_REVERSE());
+                }
+                waddstr(self.win, candidate);
+                wattroff(self.win, A_REVERSE());
+                waddstr(self.win, "  ");
+            }
+        }
+        // Long lines scroll so the cursor stays visible.
+        let width = (self.w - 2 - prompt.chars().count() as i32).max(1) as usize;
+        let skip = (line.cursor + 1).saturating_sub(width);
+        let shown: String = line.text.iter().skip(skip).take(width).collect();
+        wmove(self.win, self.h - 2, 0);
+        wclrtoeol(self.win);
+        mvwaddstr(self.win, self.h - 2, 1, &format!("{}{}", prompt, shown));
+        wmove(
+            self.win,
+            self.h - 2,
+            1 + prompt.chars().count() as i32 + (line.cursor - skip) as i32,
+        );
+        wrefresh(self.win);
+    }
+
+    /// Compiles a pattern with the case options. An empty pattern means the
+    /// last one searched for.
+    fn compile_search(&self, pattern: &str) -> Result<Regex, String> {
+        let pattern = match (pattern, &self.search) {
+            ("", Some(last)) => last.pattern.as_str(),
+            ("", None) => return Err("No previous regular expression".to_string()),
+            (pattern, _) => pattern,
+        };
+        search::compile(pattern, self.options.ignorecase, self.options.smartcase)
+    }
+
+    /// While the pattern of `/` or `?` is typed the cursor shows the first
+    /// match, and goes back if the search is cancelled.
+    fn preview_search(&mut self, prompt: &Prompt) {
+        if !self.options.incsearch {
+            return;
+        }
+        let (y, x, start) = prompt.origin;
+        self.start = start;
+        self.goto(y, x);
+        self.highlight = prompt.highlight.clone();
+        let text = prompt.line.as_string();
+        if text.is_empty() {
+            return;
+        }
+        if let Ok(re) = self.compile_search(&text) {
+            if let Some((pos, _)) = search::find(&self.archivo.buffer, &re, (y, x), prompt.forward)
+            {
+                self.goto(pos.0, pos.1);
+            }
+            self.highlight = Some(re);
+        }
+    }
+
+    /// Searches for `line`, typed after `/` or `?`.
+    fn handle_search(&mut self, line: &str, forward: bool) {
+        let pattern = match (line, &self.search) {
+            ("", Some(last)) => last.pattern.clone(),
+            ("", None) => {
+                self.msg
+                    .update("No previous regular expression", MsgType::Error);
+                return;
+            }
+            (pattern, _) => pattern.to_string(),
+        };
+        self.search = Some(LastSearch {
+            pattern: pattern.clone(),
+            forward,
+        });
+        self.search_next(false, &pattern, self.options.smartcase);
+    }
+
+    /// Jumps to the next match of `pattern`, in the direction of the last
+    /// search or against it with `reverse` (`N`).
+    fn search_next(&mut self, reverse: bool, pattern: &str, smartcase: bool) {
+        let Some(last) = &self.search else {
+            self.msg
+                .update("No previous regular expression", MsgType::Error);
+            return;
+        };
+        let forward = last.forward != reverse;
+        let re = match search::compile(pattern, self.options.ignorecase, smartcase) {
+            Ok(re) => re,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        if self.options.hlsearch {
+            self.highlight = Some(re.clone());
+        }
+        let prompt = if forward { "/" } else { "?" };
+        match search::find(&self.archivo.buffer, &re, (self.idx_y, self.idx_x), forward) {
+            Some((_, true)) if !self.options.wrapscan => {
+                let end = if forward { "BOTTOM" } else { "TOP" };
+                self.msg.update(
+                    format!("search hit {} without match for: {}", end, pattern),
+                    MsgType::Error,
+                );
+            }
+            Some(((y, x), wrapped)) => {
+                self.goto(y, x);
+                if wrapped && forward {
+                    self.msg
+                        .update("search hit BOTTOM, continuing at TOP", MsgType::Error);
+                } else if wrapped {
+                    self.msg
+                        .update("search hit TOP, continuing at BOTTOM", MsgType::Error);
+                } else {
+                    self.msg
+                        .update(format!("{}{}", prompt, pattern), MsgType::Info);
+                }
+            }
+            None => self
+                .msg
+                .update(format!("Pattern not found: {}", pattern), MsgType::Error),
+        }
+    }
+
+    /// `n` and `N`.
+    fn handle_search_next(&mut self, reverse: bool) {
+        match self.search.clone() {
+            Some(last) => self.search_next(reverse, &last.pattern, self.options.smartcase),
+            None => self
+                .msg
+                .update("No previous regular expression", MsgType::Error),
+        }
+    }
+
+    /// `*` and `#` search for the word under the cursor.
+    fn handle_search_word(&mut self, forward: bool) {
+        let line = self.archivo.buffer.line(self.idx_y);
+        let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+        // Like vim, the first word at or after the cursor.
+        let Some(start) = (self.idx_x..line.len()).find(|&i| is_word(&line[i])) else {
+            self.msg
+                .update("No identifier under cursor", MsgType::Error);
+            return;
+        };
+        let start = (0..=start)
+            .rev()
+            .take_while(|&i| is_word(&line[i]))
+            .last()
+            .unwrap_or(start);
+        let end = (start..line.len())
+            .find(|&i| !is_word(&line[i]))
+            .unwrap_or(line.len());
+        let word: String = line[start..end].iter().collect();
+        let pattern = search::word_pattern(&word);
+        self.search_history.add(&pattern);
+        self.search = Some(LastSearch {
+            pattern: pattern.clone(),
+            forward,
+        });
+        // Start from the beginning of the word so `#` skips it.
+        self.idx_x = start;
+        self.search_next(false, &pattern, false);
+    }
 
-                        self.archivo
-                            .buffer
-                            .insert(self.idx_y + 1, Vec::<char>::from(right.clone()));
-                        self.idx_y += 1;
-                        let l = right.len();
-                        self.idx_x = l;
-                        self.x = START_X + l as i32;
-                        self.y += 1;
-                        wclear(self.win);
-                        self.display();
+    /// Parses and runs one command line.
+    fn run_command(&mut self, line: &str) {
+        let cmd = match command::parse(line) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        // A range alone moves the cursor, `:12` or `:$`.
+        if cmd.name.is_empty() {
+            if let Some(last) = cmd.range.last() {
+                match command::resolve_addr(last, &self.archivo, self.idx_y, &|p| {
+                    self.compile_search(p)
+                }) {
+                    Ok(line) => self.goto(line.max(1) - 1, 0),
+                    Err(err) => self.msg.update(err, MsgType::Error),
+                }
+            }
+            return;
+        }
+        let Some(ex) = command::lookup(&cmd.name) else {
+            self.msg.update(
+                format!("Not an editor command: {}", line.trim()),
+                MsgType::Error,
+            );
+            return;
+        };
+        if !cmd.range.is_empty() && !ex.range {
+            self.msg.update("No range allowed", MsgType::Error);
+            return;
+        }
+        let range = match command::resolve_range(&cmd.range, &self.archivo, self.idx_y, &|p| {
+            self.compile_search(p)
+        }) {
+            Ok(range) => range,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        // Arguments keep the blanks they end with, which matter to `:s` and
+        // `:normal`. Commands that don't want them trim them.
+        match ex.name {
+            "delete" | "yank" | ">" | "<" => {
+                // `:d` and `:y` take a register before the count.
+                let args = cmd.args.trim_end();
+                let (register, args) = match args.chars().next() {
+                    Some(c) if ex.name != ">" && ex.name != "<" && !c.is_ascii_digit() => {
+                        (Some(c), args[c.len_utf8()..].trim_start())
                     }
-                    27 => {
-                        self.mode = false;
-                        //self.display_bar();
-                        //self.display();
+                    _ => (None, args),
+                };
+                match self.count_range(range, args) {
+                    Ok(_) if register.is_some_and(|c| !Registers::valid(c)) => {
+                        self.msg.update("Invalid register name", MsgType::Error)
                     }
-                    9 => {
-                        if self.x > self.w + START_X + 4 {
-                            // Insert a single space
-                            self.archivo.buffer[self.idx_y].insert(self.idx_x, ' ');
-                            self.x += 1;
-                            self.idx_x += 1;
-                        } else {
-                            // Insert four spaces
-                            for _ in 0..4 {
-                                self.archivo.buffer[self.idx_y].insert(self.idx_x, ' ');
-                                self.x += 1;
-                                self.idx_x += 1;
-                            }
+                    Ok(range) if ex.name == "delete" => self.handle_delete_lines(range, register),
+                    Ok(range) if ex.name == "yank" => self.handle_yank_lines(range, register),
+                    Ok(range) => self.handle_shift_lines(range, &cmd.name),
+                    Err(err) => self.msg.update(err, MsgType::Error),
+                }
+            }
+            "join" => {
+                // A single line is joined with the one below it.
+                let args = cmd.args.trim_end();
+                let range = if cmd.range.len() < 2 && args.is_empty() {
+                    (range.0, range.1 + 1)
+                } else {
+                    range
+                };
+                match self.count_range(range, args) {
+                    Ok(range) => self.handle_join_lines(range, cmd.bang),
+                    Err(err) => self.msg.update(err, MsgType::Error),
+                }
+            }
+            "move" | "copy" | "t" => {
+                let dest = command::parse_address(&cmd.args).and_then(|addr| {
+                    command::resolve_addr(&addr, &self.archivo, self.idx_y, &|p| {
+                        self.compile_search(p)
+                    })
+                });
+                match dest {
+                    Ok(dest) if ex.name == "move" => self.handle_move_lines(range, dest),
+                    Ok(dest) => self.handle_copy_lines(range, dest),
+                    Err(err) => self.msg.update(err, MsgType::Error),
+                }
+            }
+            "write" => {
+                self.handle_write(&cmd);
+            }
+            "quit" => self.handle_quit(cmd.bang),
+            "wq" if self.handle_write(&cmd) => self.handle_quit(cmd.bang),
+            "xit" | "exit" if !self.archivo.modified() || self.handle_write(&cmd) => {
+                self.handle_quit(cmd.bang)
+            }
+            "edit" => self.handle_edit(&cmd),
+            "set" => self.handle_set(cmd.args.trim_end()),
+            "undolist" => self.handle_undolist(),
+            "registers" | "display" => self.handle_registers(),
+            "nohlsearch" => self.highlight = None,
+            "substitute" => self.handle_substitute(range, &cmd.args),
+            "global" | "vglobal" => {
+                // Without a range it is the whole buffer.
+                let range = if cmd.range.is_empty() {
+                    (0, self.archivo.buffer.len_lines() - 1)
+                } else {
+                    range
+                };
+                self.handle_global(range, &cmd.args, cmd.bang || ex.name == "vglobal");
+            }
+            "normal" => {
+                let range = (!cmd.range.is_empty()).then_some(range);
+                self.handle_normal(range, &cmd.args);
+            }
+            _ => (),
+        }
+    }
+
+    /// `:[range]s/pattern/replacement/[gciI] [count]`. Without arguments it
+    /// repeats the last substitution, without its flags. All the changes are
+    /// undone together.
+    fn handle_substitute(&mut self, range: (usize, usize), args: &str) {
+        let sub = match (substitute::parse(args), &self.last_substitute) {
+            (Ok(sub), _) if !args.is_empty() => sub,
+            (Ok(_), Some(last)) => Substitute {
+                pattern: last.pattern.clone(),
+                replacement: last.replacement.clone(),
+                ..Substitute::default()
+            },
+            (Ok(_), None) => {
+                self.msg
+                    .update("No previous substitute regular expression", MsgType::Error);
+                return;
+            }
+            (Err(err), _) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        let (start, mut end) = match self.count_range(range, &sub.count) {
+            Ok(range) => range,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        let pattern = match (sub.pattern.as_str(), &self.search) {
+            ("", Some(last)) => last.pattern.clone(),
+            ("", None) => {
+                self.msg
+                    .update("No previous regular expression", MsgType::Error);
+                return;
+            }
+            (pattern, _) => pattern.to_string(),
+        };
+        let re = match sub.ignorecase {
+            Some(ignorecase) => search::compile(&pattern, ignorecase, false),
+            None => search::compile(&pattern, self.options.ignorecase, self.options.smartcase),
+        };
+        let re = match re {
+            Ok(re) => re,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        // Like vim, the pattern becomes the last search pattern.
+        self.search_history.add(&pattern);
+        self.search = Some(LastSearch {
+            pattern: pattern.clone(),
+            forward: self.search.as_ref().is_none_or(|last| last.forward),
+        });
+        self.last_substitute = Some(Substitute {
+            pattern: pattern.clone(),
+            ..sub.clone()
+        });
+
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        let mut count = 0;
+        let mut lines = 0;
+        let mut last_line = None;
+        let mut ask = sub.confirm;
+        let mut quit = false;
+        let mut y = start;
+        while y <= end && !quit {
+            let mut changed = false;
+            // Byte offset in the line where the next match may start.
+            let mut pos = 0;
+            // Like vim, no empty match right where the last one ended.
+            let mut after_match = false;
+            loop {
+                let line = self.archivo.buffer.line_string(y);
+                let Some(caps) = re.captures_at(&line, pos) else {
+                    break;
+                };
+                let found = caps.get(0).unwrap();
+                if after_match && found.is_empty() && found.start() == pos {
+                    after_match = false;
+                    match line[pos..].chars().next() {
+                        Some(c) => pos += c.len_utf8(),
+                        None => break,
+                    }
+                    continue;
+                }
+                after_match = !found.is_empty();
+                let col = line[..found.start()].chars().count();
+                let len = found.as_str().chars().count();
+                let text = substitute::expand(&sub.replacement, &caps);
+                let mut replace = true;
+                if ask {
+                    match self.confirm_substitute(y, col, len, &text) {
+                        'y' => (),
+                        'l' => quit = true,
+                        'a' => ask = false,
+                        'n' => replace = false,
+                        _ => {
+                            quit = true;
+                            break;
                         }
-                        wclear(self.win);
-                        self.display();
                     }
-                    KEY_LEFT => {
-                        self.handle_movment_left();
+                }
+                if replace {
+                    self.archivo.remove_text(y, col, len);
+                    let mut parts = text.split('\n');
+                    let first: Vec<char> = parts.next().unwrap_or("").chars().collect();
+                    self.archivo.insert_text(y, col, &first);
+                    pos = found.start() + first.iter().collect::<String>().len();
+                    // `\r` in the replacement breaks the line.
+                    let mut at = col + first.len();
+                    for part in parts {
+                        let len = self.archivo.buffer.line_len(y);
+                        let rest = self.archivo.remove_text(y, at, len);
+                        let mut next: Vec<char> = part.chars().collect();
+                        at = next.len();
+                        pos = part.len();
+                        next.extend(rest);
+                        self.archivo.insert_line(y + 1, next);
+                        y += 1;
+                        end += 1;
                     }
-                    KEY_RIGHT => {
-                        self.handle_movment_right();
+                    count += 1;
+                    changed = true;
+                } else {
+                    pos = found.end();
+                }
+                // An empty match would be found again at the same place.
+                if found.is_empty() {
+                    let line = self.archivo.buffer.line_string(y);
+                    match line[pos..].chars().next() {
+                        Some(c) => pos += c.len_utf8(),
+                        None => break,
                     }
-                    KEY_UP => {
-                        self.handle_movment_up();
+                }
+                if !sub.global || quit {
+                    break;
+                }
+            }
+            if changed {
+                lines += 1;
+                last_line = Some(y);
+            }
+            y += 1;
+        }
+        self.archivo.end_change();
+        self.current_match = None;
+        if self.options.hlsearch {
+            self.highlight = Some(re);
+        }
+
+        if self.global_busy {
+            // `:g` reports the total; `q` stops it too.
+            self.substitutions.0 += count;
+            self.substitutions.1 += lines;
+            if quit {
+                self.archivo.marked.clear();
+            }
+            if let Some(y) = last_line {
+                self.goto(y, self.first_non_blank(y));
+            }
+            return;
+        }
+        match last_line {
+            Some(y) => {
+                self.goto(y, self.first_non_blank(y));
+                let plural = |n: usize| if n == 1 { "" } else { "s" };
+                self.msg.update(
+                    format!(
+                        "{} substitution{} on {} line{}",
+                        count,
+                        plural(count),
+                        lines,
+                        plural(lines)
+                    ),
+                    MsgType::Info,
+                );
+            }
+            None if !quit => self
+                .msg
+                .update(format!("Pattern not found: {}", pattern), MsgType::Error),
+            None => (),
+        }
+    }
+
+    /// `:[range]g/pattern/command` runs an ex command on every line that
+    /// matches, `:v` and `:g!` on every line that doesn't. The lines are
+    /// marked first, so the command can add or remove lines; it runs with
+    /// the cursor on each one in turn. Without a command the lines are
+    /// listed. It all undoes in one step.
+    fn handle_global(&mut self, (start, end): (usize, usize), args: &str, invert: bool) {
+        if self.global_busy {
+            self.msg
+                .update("Cannot do :global recursive", MsgType::Error);
+            return;
+        }
+        let (pattern, command) = match command::split_pattern(args) {
+            Ok(split) => split,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        let re = match self.compile_search(&pattern) {
+            Ok(re) => re,
+            Err(err) => {
+                self.msg.update(err, MsgType::Error);
+                return;
+            }
+        };
+        if !pattern.is_empty() {
+            self.search_history.add(&pattern);
+            self.search = Some(LastSearch {
+                pattern: pattern.clone(),
+                forward: self.search.as_ref().is_none_or(|last| last.forward),
+            });
+        }
+        if self.options.hlsearch {
+            self.highlight = Some(re.clone());
+        }
+
+        let marked = search::matching_lines(&self.archivo.buffer, &re, (start, end), invert);
+        if marked.is_empty() {
+            let what = if invert {
+                "Pattern found in every line"
+            } else {
+                "Pattern not found"
+            };
+            self.msg
+                .update(format!("{}: {}", what, pattern), MsgType::Error);
+            return;
+        }
+        if command.trim().is_empty() {
+            let lines: Vec<String> = marked
+                .iter()
+                .map(|&y| self.archivo.buffer.line_string(y))
+                .collect();
+            self.display_list(&lines);
+            self.goto(*marked.back().unwrap(), 0);
+            return;
+        }
+
+        let before = self.archivo.buffer.len_lines();
+        self.archivo.marked = marked;
+        self.archivo.hold_changes(self.idx_y, self.idx_x);
+        self.global_busy = true;
+        self.substitutions = (0, 0);
+        self.msg.update("", MsgType::Info);
+        while let Some(y) = self.archivo.marked.pop_front() {
+            if self.quit {
+                break;
+            }
+            self.goto(y, 0);
+            self.run_command(command);
+        }
+        self.global_busy = false;
+        self.archivo.marked.clear();
+        self.archivo.release_changes();
+
+        let after = self.archivo.buffer.len_lines();
+        let (count, lines) = self.substitutions;
+        if count > 0 {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            self.msg.update(
+                format!(
+                    "{} substitution{} on {} line{}",
+                    count,
+                    plural(count),
+                    lines,
+                    plural(lines)
+                ),
+                MsgType::Info,
+            );
+        } else if before > after {
+            self.report(before - after, "fewer lines");
+        } else if after > before {
+            self.report(after - before, "more lines");
+        }
+    }
+
+    /// `q{register}` starts recording the keys typed, and `q` stops and
+    /// keeps them in the register. An uppercase name appends to it.
+    fn handle_record(&mut self) {
+        if let Some((name, mut keys)) = self.macro_keys.take() {
+            // The q that stopped it
+            keys.pop();
+            self.registers.record(name, Register::from_keys(&keys));
+            return;
+        }
+        let name = self.getch();
+        match char::from_u32(name as u32) {
+            Some(name) if name.is_ascii_alphanumeric() || name == '"' => {
+                self.macro_keys = Some((name, Vec::new()));
+            }
+            _ => (),
+        }
+    }
+
+    /// `@{register}` runs the register's text as keys `count` times, and
+    /// `@@` the register run last.
+    fn handle_macro(&mut self, count: Option<usize>) {
+        let name = match char::from_u32(self.getch() as u32) {
+            Some('@') => match self.last_macro {
+                Some(name) => name,
+                None => {
+                    self.msg
+                        .update("No previously used register", MsgType::Error);
+                    return;
+                }
+            },
+            Some(name) if Registers::valid(name) => name,
+            _ => return,
+        };
+        // A macro that runs itself would never stop.
+        if self.replaying >= 100 {
+            self.msg.update("Command too recursive", MsgType::Error);
+            return;
+        }
+        self.last_macro = Some(name);
+        let Some(register) = self.registers.get(Some(name)) else {
+            return;
+        };
+        let keys = register.keys().repeat(count.unwrap_or(1));
+        self.run_keys(keys);
+    }
+
+    /// `:[range]norm[al] {keys}` runs the keys as if typed in NORMAL mode,
+    /// once on each line of the range or just at the cursor.
+    fn handle_normal(&mut self, range: Option<(usize, usize)>, keys: &str) {
+        if keys.is_empty() {
+            self.msg.update("Argument required", MsgType::Error);
+            return;
+        }
+        let lines: Vec<Option<usize>> = match range {
+            Some((start, end)) => (start..=end).map(Some).collect(),
+            None => vec![None],
+        };
+        // Lines are visited as numbered when the command starts, like vim.
+        for y in lines {
+            if let Some(y) = y {
+                if y >= self.archivo.buffer.len_lines() {
+                    break;
+                }
+                self.goto(y, 0);
+            }
+            self.run_keys(keys.chars().map(|c| c as i32).collect());
+            if self.quit {
+                break;
+            }
+        }
+    }
+
+    /// Runs `keys` as if typed in NORMAL mode. Whatever they leave
+    /// unfinished is ended as with Esc.
+    fn run_keys(&mut self, keys: Vec<i32>) {
+        let queued = std::mem::replace(&mut self.keys, keys.into());
+        self.replaying += 1;
+        while let Some(ch) = self.keys.pop_front() {
+            self.handle_key(ch);
+            if self.quit {
+                break;
+            }
+        }
+        while self.mode != Mode::Normal && !self.quit {
+            self.handle_key(27);
+        }
+        self.replaying -= 1;
+        self.keys = queued;
+    }
+
+    /// Shows a match of `:s///c` and asks what to do with it. Returns `y`,
+    /// `n`, `a` (all the rest), `l` (this one and stop) or `q`.
+    fn confirm_substitute(&mut self, y: usize, col: usize, len: usize, text: &str) -> char {
+        self.goto(y, col);
+        self.current_match = Some((y, col, len));
+        let question = format!("replace with {} (y/n/a/q/l)?", printable(text.to_string()));
+        loop {
+            self.display();
+            match self.ask(&question) {
+                121 => return 'y',
+                110 => return 'n',
+                97 => return 'a',
+                108 => return 'l',
+                // q, Esc and Ctrl-C
+                113 | 27 | 3 => return 'q',
+                _ => (),
+            }
+        }
+    }
+
+    /// `m{a-z}` sets a mark at the cursor.
+    fn handle_mark(&mut self) {
+        let ch = self.getch();
+        if (97..=122).contains(&ch) {
+            self.archivo
+                .marks
+                .insert(ch as u8 as char, (self.idx_y, self.idx_x));
+        }
+    }
+
+    /// `'{a-z}` jumps to the line of a mark, `` `{a-z} `` to its column too.
+    fn handle_jump_mark(&mut self, key: i32) {
+        let ch = self.getch();
+        if !(97..=122).contains(&ch) {
+            return;
+        }
+        match self.archivo.marks.get(&(ch as u8 as char)).copied() {
+            Some((y, x)) if key == 96 => self.goto(y, x),
+            Some((y, _)) => self.goto(y, self.first_non_blank(y)),
+            None => self.msg.update("Mark not set", MsgType::Error),
+        }
+    }
+
+    /// Applies the count of commands like `:d 3`, which counts lines from
+    /// the end of the range.
+    fn count_range(&self, range: (usize, usize), arg: &str) -> Result<(usize, usize), String> {
+        let last = self.archivo.buffer.len_lines() - 1;
+        if arg.is_empty() {
+            return Ok((range.0, range.1.min(last)));
+        }
+        match arg.parse::<usize>() {
+            Ok(count) if count > 0 => Ok((range.1, range.1.saturating_add(count - 1).min(last))),
+            _ => Err(format!("Trailing characters: {}", arg)),
+        }
+    }
+
+    /// Like vim's 'report', only changes to more than two lines are told.
+    fn report(&mut self, count: usize, what: &str) {
+        if count > 2 {
+            self.msg
+                .update(format!("{} {}", count, what), MsgType::Info);
+        }
+    }
+
+    fn first_non_blank(&self, y: usize) -> usize {
+        self.archivo
+            .buffer
+            .line(y)
+            .iter()
+            .take_while(|c| c.is_whitespace())
+            .count()
+    }
+
+    /// `:[range]d [x]`
+    fn handle_delete_lines(&mut self, (start, end): (usize, usize), register: Option<char>) {
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        let lines = self.archivo.remove_lines(start, end);
+        self.archivo.end_change();
+        self.store_register(
+            register,
+            Register {
+                text: lines.iter().map(|l| l.iter().collect()).collect(),
+                linewise: true,
+                blockwise: false,
+            },
+            true,
+        );
+        let y = start.min(self.archivo.buffer.len_lines() - 1);
+        self.goto(y, self.first_non_blank(y));
+        self.report(lines.len(), "fewer lines");
+    }
+
+    /// `:[range]y [x]`
+    fn handle_yank_lines(&mut self, (start, end): (usize, usize), register: Option<char>) {
+        self.store_register(
+            register,
+            Register {
+                text: (start..=end)
+                    .map(|y| self.archivo.buffer.line_string(y))
+                    .collect(),
+                linewise: true,
+                blockwise: false,
+            },
+            false,
+        );
+        self.report(end - start + 1, "lines yanked");
+    }
+
+    /// Keeps yanked or deleted text in register `name`. Text for `"+` and
+    /// `"*` is sent to the system clipboard too; when that fails it is
+    /// still in the register.
+    fn store_register(&mut self, name: Option<char>, register: Register, delete: bool) {
+        if let Some(name @ ('+' | '*')) = name {
+            let mut text = register.text.join("\n");
+            if register.linewise {
+                text.push('\n');
+            }
+            if let Err(err) = clipboard::copy(self.options.clipboard, &text, name == '*') {
+                self.msg.update(
+                    format!("{}, text kept in register {}", err, name),
+                    MsgType::Error,
+                );
+            }
+        }
+        self.registers.store(name, register, delete);
+    }
+
+    /// `p` and `P` put `count` copies of the register after or before the
+    /// cursor. Lines go below or above the cursor line; the cursor ends on
+    /// the last character put, or at the start of the text when it spans
+    /// lines.
+    fn handle_put(&mut self, before: bool, count: Option<usize>) {
+        if let Some(name @ ('+' | '*')) = self.register {
+            // Text ending in a line break was copied as whole lines.
+            if let Some(text) = clipboard::paste(self.options.clipboard, name == '*') {
+                let register = Register {
+                    text: text.lines().map(String::from).collect(),
+                    linewise: text.ends_with('\n'),
+                    blockwise: false,
+                };
+                self.registers.set_clipboard(name == '*', register);
+            }
+        }
+        let Some(register) = self.registers.get(self.register).cloned() else {
+            let name = self.register.unwrap_or('"');
+            self.msg
+                .update(format!("Nothing in register {}", name), MsgType::Error);
+            return;
+        };
+        let count = count.unwrap_or(1);
+        let y = self.idx_y;
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        if register.linewise {
+            let at = if before { y } else { y + 1 };
+            let lines: Vec<Vec<char>> = (0..count)
+                .flat_map(|_| register.text.iter().map(|line| line.chars().collect()))
+                .collect();
+            let added = lines.len();
+            self.archivo.insert_lines(at, lines);
+            self.archivo.end_change();
+            self.goto(at, self.first_non_blank(at));
+            self.report(added, "more lines");
+            return;
+        }
+        if register.blockwise {
+            self.put_block(&register.text, before, count);
+            self.archivo.end_change();
+            return;
+        }
+        let len = self.archivo.buffer.line_len(y);
+        let x = if before || len == 0 {
+            self.idx_x.min(len)
+        } else {
+            (self.idx_x + 1).min(len)
+        };
+        let text = vec![register.text.join("\n"); count].concat();
+        let mut pieces: Vec<Vec<char>> = text.split('\n').map(|p| p.chars().collect()).collect();
+        let rest = self.archivo.remove_text(y, x, len - x);
+        let last = pieces.len() - 1;
+        let cursor = if last == 0 {
+            (y, (x + pieces[0].len()).saturating_sub(1))
+        } else {
+            (y, x)
+        };
+        pieces[last].extend(rest);
+        self.archivo.insert_text(y, x, &pieces[0]);
+        self.archivo
+            .insert_lines(y + 1, pieces.into_iter().skip(1).collect());
+        self.archivo.end_change();
+        self.goto(cursor.0, cursor.1);
+    }
+
+    /// Puts a block at the cursor column, each piece of it on a line of its
+    /// own going down, adding lines at the end of the buffer as needed.
+    /// Short lines are filled with spaces to reach the column, and pieces
+    /// are filled to the width of the block when text follows them.
+    fn put_block(&mut self, text: &[String], before: bool, count: usize) {
+        let y = self.idx_y;
+        let len = self.archivo.buffer.line_len(y);
+        let x = if before || len == 0 {
+            self.idx_x.min(len)
+        } else {
+            (self.idx_x + 1).min(len)
+        };
+        let width = text.iter().map(|piece| piece.chars().count()).max();
+        let width = width.unwrap_or(0) * count;
+        let missing = (y + text.len()).saturating_sub(self.archivo.buffer.len_lines());
+        let end = self.archivo.buffer.len_lines();
+        self.archivo.insert_lines(end, vec![Vec::new(); missing]);
+        for (i, piece) in text.iter().enumerate() {
+            let mut piece: Vec<char> = piece.repeat(count).chars().collect();
+            let len = self.archivo.buffer.line_len(y + i);
+            if len < x {
+                self.archivo.insert_text(y + i, len, &vec![' '; x - len]);
+            } else if len > x {
+                piece.resize(width.max(piece.len()), ' ');
+            }
+            self.archivo.insert_text(y + i, x, &piece);
+        }
+        self.goto(y, x);
+    }
+
+    /// `:registers`
+    fn handle_registers(&mut self) {
+        let mut lines = vec!["Type Name Content".to_string()];
+        for (name, register) in self.registers.list() {
+            let kind = if register.linewise {
+                "l"
+            } else if register.blockwise {
+                "b"
+            } else {
+                "c"
+            };
+            let mut content = register.text.join("^J");
+            if register.linewise {
+                content.push_str("^J");
+            }
+            let width = (self.w - 12).max(0) as usize;
+            let content: String = content.chars().take(width).collect();
+            lines.push(format!("  {}  \"{}   {}", kind, name, content));
+        }
+        self.display_list(&lines);
+    }
+
+    /// `:[range]m {address}` puts the lines below `dest`, a 1-based line
+    /// number where 0 means the top of the buffer.
+    fn handle_move_lines(&mut self, (start, end): (usize, usize), dest: usize) {
+        if dest > start && dest <= end {
+            self.msg
+                .update("Cannot move a range of lines into itself", MsgType::Error);
+            return;
+        }
+        let count = end - start + 1;
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        let lines = self.archivo.remove_lines(start, end);
+        let at = if dest > end { dest - count } else { dest };
+        self.archivo.insert_lines(at, lines);
+        self.archivo.end_change();
+        let y = at + count - 1;
+        self.goto(y, self.first_non_blank(y));
+        self.report(count, "lines moved");
+    }
+
+    /// `:[range]t {address}` copies the lines below `dest`.
+    fn handle_copy_lines(&mut self, (start, end): (usize, usize), dest: usize) {
+        let lines: Vec<Vec<char>> = (start..=end).map(|y| self.archivo.buffer.line(y)).collect();
+        let count = lines.len();
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        self.archivo.insert_lines(dest, lines);
+        self.archivo.end_change();
+        let y = dest + count - 1;
+        self.goto(y, self.first_non_blank(y));
+        self.report(count, "more lines");
+    }
+
+    /// `:[range]>` and `:[range]<`, once per `>` or `<` typed. Empty lines
+    /// are left alone.
+    fn handle_shift_lines(&mut self, (start, end): (usize, usize), name: &str) {
+        let width = self.options.shiftwidth * name.len();
+        let right = name.starts_with('>');
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        for y in start..=end {
+            if self.archivo.buffer.line_len(y) == 0 {
+                continue;
+            }
+            if right {
+                self.archivo.insert_text(y, 0, &vec![' '; width]);
+            } else {
+                // A tab counts as a whole shiftwidth.
+                let line = self.archivo.buffer.line(y);
+                let mut col = 0;
+                let mut len = 0;
+                for c in line.iter() {
+                    if col >= width || !(*c == ' ' || *c == '\t') {
+                        break;
                     }
-                    KEY_DOWN => {
-                        self.handle_movment_up();
+                    col += if *c == '\t' {
+                        self.options.shiftwidth
+                    } else {
+                        1
+                    };
+                    len += 1;
+                }
+                self.archivo.remove_text(y, 0, len);
+            }
+        }
+        self.archivo.end_change();
+        self.goto(end, self.first_non_blank(end));
+        let times = if name.len() == 1 { "time" } else { "times" };
+        self.report(
+            end - start + 1,
+            &format!("lines {}ed {} {}", &name[..1], name.len(), times),
+        );
+    }
+
+    /// `:[range]j[!]` joins the lines into the first one. Without `!` the
+    /// leading white space of each joined line becomes a single space.
+    fn handle_join_lines(&mut self, (start, end): (usize, usize), keep_spaces: bool) {
+        if start == end {
+            return;
+        }
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        let mut col = 0;
+        for _ in start..end {
+            let next = self.archivo.remove_line(start + 1);
+            let len = self.archivo.buffer.line_len(start);
+            col = len;
+            let mut text: Vec<char> = if keep_spaces {
+                next
+            } else {
+                next.into_iter().skip_while(|c| c.is_whitespace()).collect()
+            };
+            let ends_blank = self
+                .archivo
+                .buffer
+                .char_at(start, len.saturating_sub(1))
+                .is_none_or(|c| c.is_whitespace());
+            if !keep_spaces && !text.is_empty() && !ends_blank && text[0] != ')' {
+                text.insert(0, ' ');
+            }
+            self.archivo.insert_text(start, len, &text);
+        }
+        self.archivo.end_change();
+        self.goto(start, col);
+        self.report(end - start + 1, "lines joined");
+    }
+
+    /// `:w [file]`. Writing to another file leaves the buffer as it is.
+    fn handle_write(&mut self, cmd: &command::Command) -> bool {
+        let args = cmd.args.trim_end();
+        if args.is_empty() {
+            return self.handle_save(cmd.bang);
+        }
+        let path = command::expand_home(args);
+        if std::fs::canonicalize(&path).ok() == std::fs::canonicalize(&self.archivo.path).ok()
+            && std::path::Path::new(&path).exists()
+        {
+            return self.handle_save(cmd.bang);
+        }
+        if std::path::Path::new(&path).exists() && !cmd.bang {
+            self.msg
+                .update("File exists (add ! to override)", MsgType::Error);
+            return false;
+        }
+        if self.archivo.illegal && !cmd.bang {
+            self.msg.update(ILLEGAL_BYTES, MsgType::Error);
+            return false;
+        }
+        match self.archivo.write_to(&path, &self.options) {
+            Ok(len) => {
+                self.msg.update(
+                    format!(
+                        "\"{}\" {}L {}B written",
+                        path,
+                        self.archivo.buffer.len_lines(),
+                        len
+                    ),
+                    MsgType::Info,
+                );
+                true
+            }
+            Err(err) => {
+                self.msg.update(
+                    format!("Nothing updated due to error {}", err),
+                    MsgType::Error,
+                );
+                false
+            }
+        }
+    }
+
+    /// `:e[!] [++enc=name] [file]`. Without a file the current one is read
+    /// again: as an undoable change, or from scratch with `++enc`.
+    fn handle_edit(&mut self, cmd: &command::Command) {
+        if self.archivo.modified() && !cmd.bang {
+            self.msg.update(
+                "No write since last change (add ! to override)",
+                MsgType::Error,
+            );
+            return;
+        }
+        let args = cmd.args.trim();
+        let (encoding, file) = match args.strip_prefix("++enc=") {
+            Some(rest) => {
+                let (name, file) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                match Encoding::from_name(name) {
+                    Some(encoding) => (Some(encoding), file.trim_start()),
+                    None => {
+                        self.msg
+                            .update(format!("Invalid encoding: {}", name), MsgType::Error);
+                        return;
                     }
-                    _ => {
-                        if self.x < self.w - START_X {
-                            if self.idx_x > self.archivo.buffer[self.idx_y].len() {
-                                self.archivo.buffer[self.idx_y].push(ty);
-                                // self.archivo.buffer[self.idx_y].insert(self.idx_x, ty);
-                            } else {
-                                self.archivo.buffer[self.idx_y].insert(self.idx_x, ty);
-                            }
-                            self.x += 1;
-                            self.idx_x += 1;
-                        } else {
-                            if self.idx_y == self.archivo.buffer.len() - 1 {
-                                if let Some(buffer_y) = self.archivo.buffer.get_mut(self.idx_y) {
-                                    if self.idx_x < buffer_y.len() {
-                                        buffer_y.truncate(self.idx_x); // Elimina todos los elementos a partir del índice self.idx_x
-                                    }
-                                }
-
-                                self.archivo
-                                    .buffer
-                                    .insert(self.idx_y + 1, Vec::<char>::new());
-
-                                self.idx_x = 0;
-                                self.x = START_X;
-                                self.idx_y += 1;
-                                self.y += 1;
-                            } else {
-                                self.idx_x = 0;
-                                self.x = START_X;
-                                self.idx_y += 1;
-                                self.y += 1;
-                            }
-                        }
+                }
+            }
+            None => (None, args),
+        };
+        if !file.is_empty() {
+            self.edit_file(&command::expand_home(file), encoding);
+        } else if encoding.is_some() {
+            let (y, x) = (self.idx_y, self.idx_x);
+            let path = self.archivo.path.clone();
+            self.edit_file(&path, encoding);
+            self.goto(y, x);
+        } else if self.archivo.disk.is_some() {
+            self.handle_reload_disk();
+        }
+    }
+
+    fn handle_set(&mut self, arg: &str) {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.trim_end_matches('?'), None),
+        };
+        match (name, value) {
+            ("fileformat" | "ff", None) => self.msg.update(
+                format!("fileformat={}", self.archivo.line_ending.name()),
+                MsgType::Info,
+            ),
+            ("fileformat" | "ff", Some(value)) => match LineEnding::from_name(value) {
+                Some(ending) => self.archivo.set_line_ending(ending),
+                None => self
+                    .msg
+                    .update(format!("Invalid argument: {}", arg), MsgType::Error),
+            },
+            ("fileencoding" | "fenc", None) => self.msg.update(
+                format!("fileencoding={}", self.archivo.encoding.name()),
+                MsgType::Info,
+            ),
+            ("fileencoding" | "fenc", Some(value)) => match Encoding::from_name(value) {
+                Some(encoding) => self.archivo.set_encoding(encoding),
+                None => self
+                    .msg
+                    .update(format!("Invalid argument: {}", arg), MsgType::Error),
+            },
+            _ => match self.options.set(arg) {
+                Ok(Some(info)) => self.msg.update(info, MsgType::Info),
+                Ok(None) => (),
+                Err(err) => self.msg.update(err, MsgType::Error),
+            },
+        }
+    }
+
+    /// Starts INSERT mode at the cursor. What is typed until Esc undoes
+    /// as one change.
+    fn start_insert(&mut self) {
+        self.archivo.begin_change(self.idx_y, self.idx_x);
+        self.mode = Mode::Insert;
+    }
+
+    fn stop_insert(&mut self) {
+        self.mode = Mode::Normal;
+        self.archivo.end_change();
+        if let Some(block) = self.block.take() {
+            self.finish_block_insert(block);
+        }
+    }
+
+    /// `r` puts the character typed next in place of `count` characters.
+    /// Enter replaces them all with one line break. Esc and keys that
+    /// don't type a character cancel it.
+    fn handle_replace_char(&mut self, count: Option<usize>) {
+        let ch = self.getch();
+        let enter = ch == KEY_ENTER || ch == 10 || ch == 13;
+        let Some(c) = typed_char(ch).or(enter.then_some('\n')) else {
+            return;
+        };
+        let n = count.unwrap_or(1);
+        let (y, x) = (self.idx_y, self.idx_x);
+        if x + n > self.archivo.buffer.line_len(y) {
+            return;
+        }
+        self.archivo.begin_change(y, x);
+        self.archivo.remove_text(y, x, n);
+        if c == '\n' {
+            let len = self.archivo.buffer.line_len(y);
+            let tail = self.archivo.remove_text(y, x, len);
+            self.archivo.insert_line(y + 1, tail);
+            self.goto(y + 1, 0);
+        } else {
+            self.archivo.insert_text(y, x, &vec![c; n]);
+            self.goto(y, x + n - 1);
+        }
+        self.archivo.end_change();
+    }
+
+    /// Backspace in REPLACE mode puts back what the last character typed
+    /// replaced. Before the first one typed it only moves left.
+    fn replace_backspace(&mut self) {
+        if self.idx_x == 0 {
+            return;
+        }
+        let (y, x) = (self.idx_y, self.idx_x - 1);
+        match self.replaced.pop() {
+            Some(Some(c)) => {
+                self.archivo.remove_text(y, x, 1);
+                self.archivo.insert_char(y, x, c);
+            }
+            Some(None) => {
+                self.archivo.remove_text(y, x, 1);
+            }
+            None => (),
+        }
+        self.goto(y, x);
+    }
 
-                        self.display();
+    //I
+    fn insert_key(&mut self, ch: i32) {
+        match ch {
+            KEY_BACKSPACE if self.mode == Mode::Replace => {
+                self.replace_backspace();
+            }
+            27 => {
+                self.stop_insert();
+            }
+            // Tab inserts spaces as in INSERT mode.
+            _ if self.mode == Mode::Replace && (32..127).contains(&ch) => {
+                let (y, x) = (self.idx_y, self.idx_x);
+                if x < self.archivo.buffer.line_len(y) {
+                    let old = self.archivo.remove_text(y, x, 1);
+                    self.replaced.push(old.first().copied());
+                } else {
+                    self.replaced.push(None);
+                }
+                self.archivo.insert_char(y, x, ch as u8 as char);
+                self.goto(y, x + 1);
+            }
+            // Backspace at the start of a line joins it to the one above.
+            KEY_BACKSPACE => {
+                let (y, x) = (self.idx_y, self.idx_x);
+                if x > 0 {
+                    self.archivo.remove_text(y, x - 1, 1);
+                    self.goto(y, x - 1);
+                } else if y > 0 {
+                    let len = self.archivo.buffer.line_len(y - 1);
+                    let line = self.archivo.remove_line(y);
+                    self.archivo.insert_text(y - 1, len, &line);
+                    self.goto(y - 1, len);
+                }
+            }
+            KEY_ENTER | 10 => {
+                // In REPLACE mode a line break is inserted, and can't be
+                // backspaced over.
+                self.replaced.clear();
+                let mut right: Vec<char> = Vec::new();
+                for i in self.archivo.buffer.line(self.idx_y).iter() {
+                    if *i == ' ' {
+                        right.push(*i);
+                    } else {
+                        break;
                     }
                 }
-                if *ch != 27 {
-                    wrefresh(self.win);
-                    *ch = wgetch(self.win);
+                if let Some('{' | '(' | ':') = self.archivo.buffer.line(self.idx_y).last() {
+                    right.extend([' '; 4]);
+                }
+
+                // Elimina todos los elementos a partir del índice self.idx_x
+                let len = self.archivo.buffer.line_len(self.idx_y);
+                let tail = self.archivo.remove_text(self.idx_y, self.idx_x, len);
+                right.extend(tail);
+
+                self.archivo.insert_line(self.idx_y + 1, right.clone());
+                self.idx_y += 1;
+                let l = right.len();
+                self.idx_x = l;
+                self.x = START_X + l as i32;
+                self.y += 1;
+                wclear(self.win);
+            }
+            9 => {
+                if self.x > self.w + START_X + 4 {
+                    // Insert a single space
+                    self.archivo.insert_char(self.idx_y, self.idx_x, ' ');
+                    self.x += 1;
+                    self.idx_x += 1;
+                    self.replaced.push(None);
                 } else {
-                    self.display_bar();
-                    break;
+                    // Insert four spaces
+                    self.archivo.insert_text(self.idx_y, self.idx_x, &[' '; 4]);
+                    self.x += 4;
+                    self.idx_x += 4;
+                    self.replaced.extend([None; 4]);
+                }
+                wclear(self.win);
+            }
+            KEY_LEFT | KEY_RIGHT | KEY_UP | KEY_DOWN => {
+                if let Some(motion) = Motion::from_key(ch) {
+                    self.handle_motion(motion, None);
+                }
+            }
+            // Other keys don't type over anything, and function keys don't
+            // type at all.
+            _ if self.mode == Mode::Replace => (),
+            _ => {
+                let Some(c) = typed_char(ch) else {
+                    return;
+                };
+                if self.x < self.w - START_X {
+                    self.archivo.insert_char(self.idx_y, self.idx_x, c);
+                    self.x += 1;
+                    self.idx_x += 1;
+                } else if self.idx_y == self.archivo.buffer.len_lines() - 1 {
+                    // Elimina todos los elementos a partir del índice self.idx_x
+                    let len = self.archivo.buffer.line_len(self.idx_y);
+                    self.archivo.remove_text(self.idx_y, self.idx_x, len);
+                    self.archivo.insert_line(self.idx_y + 1, Vec::<char>::new());
+
+                    self.idx_x = 0;
+                    self.x = START_X;
+                    self.idx_y += 1;
+                    self.y += 1;
+                } else {
+                    self.idx_x = 0;
+                    self.x = START_X;
+                    self.idx_y += 1;
+                    self.y += 1;
                 }
             }
         }
@@ -0,0 +1,252 @@
+use crate::operator::Region;
+use crate::texto::Texto;
+
+/// The three kinds of selection, started with `v`, `V` and Ctrl-V.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VisualKind {
+    Char,
+    Line,
+    Block,
+}
+
+impl VisualKind {
+    pub fn from_key(ch: i32) -> Option<VisualKind> {
+        Some(match ch {
+            118 => VisualKind::Char,
+            86 => VisualKind::Line,
+            22 => VisualKind::Block,
+            _ => return None,
+        })
+    }
+}
+
+/// A selection from `anchor`, where it was started, to `cursor`. Both ends
+/// are in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Visual {
+    pub kind: VisualKind,
+    pub anchor: (usize, usize),
+    pub cursor: (usize, usize),
+    /// After `$` a block goes to the end of every line.
+    pub to_end: bool,
+}
+
+impl Visual {
+    pub fn new(kind: VisualKind, cursor: (usize, usize)) -> Visual {
+        Visual {
+            kind,
+            anchor: cursor,
+            cursor,
+            to_end: false,
+        }
+    }
+
+    /// The first and last line of the selection.
+    pub fn lines(&self) -> (usize, usize) {
+        let (a, b) = (self.anchor.0, self.cursor.0);
+        (a.min(b), a.max(b))
+    }
+
+    /// The first and last column of a block.
+    pub fn columns(&self) -> (usize, usize) {
+        let (a, b) = (self.anchor.1, self.cursor.1);
+        (a.min(b), a.max(b))
+    }
+
+    /// Where the `'<` and `'>` marks go: the start and the end of the
+    /// selection, or the top left and bottom right corners of a block.
+    pub fn marks(&self) -> ((usize, usize), (usize, usize)) {
+        match self.kind {
+            VisualKind::Char => (self.anchor.min(self.cursor), self.anchor.max(self.cursor)),
+            VisualKind::Line | VisualKind::Block => {
+                let (first, last) = self.lines();
+                let (left, right) = self.columns();
+                ((first, left), (last, right))
+            }
+        }
+    }
+
+    /// What an operator works on, for `v` and `V`. Past the last character
+    /// of a line the selection takes in the line break.
+    pub fn region(&self, buffer: &Texto) -> Region {
+        let (start, end) = if self.cursor < self.anchor {
+            (self.cursor, self.anchor)
+        } else {
+            (self.anchor, self.cursor)
+        };
+        if self.kind == VisualKind::Line {
+            return Region {
+                start: (start.0, 0),
+                end: (end.0, buffer.line_len(end.0)),
+                linewise: true,
+            };
+        }
+        let end = if end.1 < buffer.line_len(end.0) {
+            (end.0, end.1 + 1)
+        } else if end.0 + 1 < buffer.len_lines() {
+            (end.0 + 1, 0)
+        } else {
+            (end.0, buffer.line_len(end.0))
+        };
+        Region {
+            start,
+            end,
+            linewise: false,
+        }
+    }
+
+    /// The part of each line inside a block. Lines too short to reach it
+    /// get an empty region at their end.
+    pub fn block_regions(&self, buffer: &Texto) -> Vec<Region> {
+        let (first, last) = self.lines();
+        let (left, right) = self.columns();
+        (first..=last)
+            .map(|y| {
+                let len = buffer.line_len(y);
+                let from = left.min(len);
+                let to = if self.to_end {
+                    len
+                } else {
+                    (right + 1).min(len)
+                };
+                Region {
+                    start: (y, from),
+                    end: (y, to.max(from)),
+                    linewise: false,
+                }
+            })
+            .collect()
+    }
+
+    /// The column and width to highlight on line `y`. The line break of a
+    /// selected line shows as one more column.
+    pub fn span(&self, buffer: &Texto, y: usize) -> Option<(usize, usize)> {
+        let (first, last) = self.lines();
+        if y < first || y > last {
+            return None;
+        }
+        let len = buffer.line_len(y);
+        match self.kind {
+            VisualKind::Line => Some((0, len + 1)),
+            VisualKind::Char => {
+                let (start, end) = if self.cursor < self.anchor {
+                    (self.cursor, self.anchor)
+                } else {
+                    (self.anchor, self.cursor)
+                };
+                let from = if y == start.0 { start.1 } else { 0 };
+                let to = if y == end.0 { end.1 + 1 } else { len + 1 };
+                Some((from, to.saturating_sub(from).max(1)))
+            }
+            VisualKind::Block => {
+                let (left, right) = self.columns();
+                let to = if self.to_end {
+                    len
+                } else {
+                    (right + 1).min(len)
+                };
+                (left < to).then(|| (left, to - left))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visual(kind: VisualKind, anchor: (usize, usize), cursor: (usize, usize)) -> Visual {
+        Visual {
+            kind,
+            anchor,
+            cursor,
+            to_end: false,
+        }
+    }
+
+    fn ends(region: &Region) -> ((usize, usize), (usize, usize), bool) {
+        (region.start, region.end, region.linewise)
+    }
+
+    #[test]
+    fn regions_go_from_the_first_end() {
+        let buffer = Texto::from_text("abcd\nef\nghij");
+        let forward = visual(VisualKind::Char, (0, 1), (2, 2));
+        let backward = visual(VisualKind::Char, (2, 2), (0, 1));
+        assert_eq!(ends(&forward.region(&buffer)), ((0, 1), (2, 3), false));
+        assert_eq!(ends(&backward.region(&buffer)), ((0, 1), (2, 3), false));
+        // Past the end of a line the line break is selected.
+        let past = visual(VisualKind::Char, (0, 3), (1, 2));
+        assert_eq!(ends(&past.region(&buffer)), ((0, 3), (2, 0), false));
+        let lines = visual(VisualKind::Line, (2, 1), (1, 1));
+        assert_eq!(ends(&lines.region(&buffer)), ((1, 0), (2, 4), true));
+    }
+
+    #[test]
+    fn blocks_are_cut_to_each_line() {
+        let buffer = Texto::from_text("abcdef\nab\nabcdefgh");
+        let block = visual(VisualKind::Block, (2, 4), (0, 1));
+        let regions: Vec<_> = block.block_regions(&buffer).iter().map(ends).collect();
+        assert_eq!(
+            regions,
+            [
+                ((0, 1), (0, 5), false),
+                ((1, 1), (1, 2), false),
+                ((2, 1), (2, 5), false),
+            ]
+        );
+        // A line that ends before the block gets an empty region.
+        let block = visual(VisualKind::Block, (0, 3), (2, 4));
+        assert_eq!(
+            ends(&block.block_regions(&buffer)[1]),
+            ((1, 2), (1, 2), false)
+        );
+        // After $ the block takes every line to its end.
+        let block = Visual {
+            to_end: true,
+            ..visual(VisualKind::Block, (0, 2), (2, 2))
+        };
+        let regions: Vec<_> = block.block_regions(&buffer).iter().map(ends).collect();
+        assert_eq!(
+            regions,
+            [
+                ((0, 2), (0, 6), false),
+                ((1, 2), (1, 2), false),
+                ((2, 2), (2, 8), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn marks_are_the_ends() {
+        let chars = visual(VisualKind::Char, (2, 1), (0, 4));
+        assert_eq!(chars.marks(), ((0, 4), (2, 1)));
+        let block = visual(VisualKind::Block, (2, 1), (0, 4));
+        assert_eq!(block.marks(), ((0, 1), (2, 4)));
+        let lines = visual(VisualKind::Line, (0, 3), (1, 0));
+        assert_eq!(lines.marks(), ((0, 0), (1, 3)));
+    }
+
+    #[test]
+    fn spans_to_highlight() {
+        let buffer = Texto::from_text("abcdef\nab\nabcdefgh");
+        let chars = visual(VisualKind::Char, (2, 2), (0, 4));
+        assert_eq!(chars.span(&buffer, 0), Some((4, 3)));
+        assert_eq!(chars.span(&buffer, 1), Some((0, 3)));
+        assert_eq!(chars.span(&buffer, 2), Some((0, 3)));
+        let lines = visual(VisualKind::Line, (1, 0), (2, 0));
+        assert_eq!(lines.span(&buffer, 0), None);
+        assert_eq!(lines.span(&buffer, 1), Some((0, 3)));
+        let block = visual(VisualKind::Block, (2, 4), (0, 1));
+        assert_eq!(block.span(&buffer, 0), Some((1, 4)));
+        assert_eq!(block.span(&buffer, 1), Some((1, 1)));
+        // Short lines show nothing of the block.
+        let block = visual(VisualKind::Block, (0, 3), (2, 4));
+        assert_eq!(block.span(&buffer, 1), None);
+        let block = Visual {
+            to_end: true,
+            ..block
+        };
+        assert_eq!(block.span(&buffer, 2), Some((3, 5)));
+    }
+}